@@ -0,0 +1,52 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while editing.
+pub enum Error {
+    // Reading or writing a file failed; the session carries on
+    Io(io::Error),
+    // Talking to the terminal failed, there is no way to keep editing
+    Terminal(io::Error),
+    // We were asked to stop (SIGTERM, SIGHUP)
+    Signal(&'static str),
+    // A fatal error ended the session, after unsaved buffers were written to `saved`
+    Aborted { cause: Box<Error>, saved: Vec<PathBuf> },
+}
+
+impl Error {
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Self::Io(_))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{}", error),
+            Self::Terminal(error) => write!(f, "terminal error: {}", error),
+            Self::Signal(name) => write!(f, "received {}", name),
+            Self::Aborted { cause, saved } => {
+                write!(f, "{}", cause)?;
+                for path in saved {
+                    write!(f, "\nunsaved changes were written to {}", path.display())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
@@ -14,9 +14,13 @@
     clippy::wildcard_enum_match_arm,
     clippy::else_if_without_else
 )]
+mod error;
 mod state;
 mod terminal;
 
+use std::io::{self, Write};
+use std::process;
+
 use state::Editor;
 
 pub use state::line::Line;
@@ -24,5 +28,11 @@ pub use state::document::Document;
 pub use state::SearchDirection;
 
 fn main() {
-    Editor::default().run();
+    // The editor is dropped before we print, so the error lands on the normal screen
+    let result = Editor::new().and_then(|mut editor| editor.run());
+    let _ = io::stdout().flush();
+    if let Err(error) = result {
+        eprintln!("sodium: {}", error);
+        process::exit(1);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,168 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::state::filetype::{self, Filetype};
+use crate::state::window::Window;
+use crate::state::{Editor, Position, State};
+use crate::{Document, Line};
+
+const PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}')];
+// How many lines away the match of the bracket at the cursor is looked for while drawing
+const HIGHLIGHT_LINES: usize = 1000;
+
+// Brackets on line `y` and where they are, leaving out those in strings and comments when the
+// filetype tells us where those are
+fn brackets(document: &Document, filetype: Option<&Filetype>, y: usize) -> Vec<(usize, char)> {
+    let text = document.line(y).map_or("", Line::as_str);
+    let code = filetype.map(|filetype| filetype.code(text));
+    text.graphemes(true)
+        .enumerate()
+        .filter_map(|(x, grapheme)| {
+            let mut chars = grapheme.chars();
+            let c = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return None,
+            };
+            let in_code = code.as_ref().map_or(true, |code| code.get(x).copied().unwrap_or(true));
+            if in_code && PAIRS.iter().any(|(open, close)| *open == c || *close == c) {
+                Some((x, c))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// The bracket pairing with the one at `at`, no more than `limit` lines away
+pub fn find_match(document: &Document, filetype: Option<&Filetype>, at: &Position, limit: usize) -> Option<Position> {
+    let (_, c) = brackets(document, filetype, at.y).into_iter().find(|(x, _)| *x == at.x)?;
+    let (open, close) = PAIRS.iter().find(|(open, close)| *open == c || *close == c)?;
+    let forward = c == *open;
+    let (from, to) = if forward { (open, close) } else { (close, open) };
+    let lines: Vec<usize> = if forward {
+        (at.y..document.len()).take(limit.saturating_add(1)).collect()
+    } else {
+        (0..=at.y).rev().take(limit.saturating_add(1)).collect()
+    };
+
+    let mut depth: usize = 0;
+    for y in lines {
+        let mut found = brackets(document, filetype, y);
+        if !forward {
+            found.reverse();
+        }
+        for (x, c) in found {
+            let past = if forward { x > at.x } else { x < at.x };
+            if y == at.y && !past {
+                continue;
+            }
+            if c == *from {
+                depth = depth.saturating_add(1);
+            } else if c == *to && depth == 0 {
+                return Some(Position { x, y });
+            } else if c == *to {
+                depth = depth.saturating_sub(1);
+            }
+        }
+    }
+    None
+}
+
+impl Editor {
+    // `%`: to the bracket matching the one under the cursor, or the first one after it on the line
+    pub(super) fn jump_to_match(&mut self) {
+        let filetype = self.filetype();
+        let Position { x, y } = self.window().cur_pos;
+        // Past the end of the line is on its last character
+        let x = x.min(self.document().line(y).map_or(0, Line::len).saturating_sub(1));
+        let bracket = brackets(self.document(), filetype, y).into_iter().find(|(bracket, _)| *bracket >= x);
+        let found = bracket.and_then(|(x, _)| find_match(self.document(), filetype, &Position { x, y }, usize::MAX));
+        if let Some(position) = found {
+            self.window_mut().cur_pos = position;
+        }
+    }
+
+    // The bracket at the cursor of `window` and the one matching it, to be highlighted; while
+    // typing, the bracket just before the cursor counts too
+    pub(super) fn matching_brackets(&self, window: &Window, document: &Document, focused: bool) -> Vec<Position> {
+        if !self.options.match_brackets {
+            return Vec::new();
+        }
+        let filetype = filetype::detect(document.filename.as_deref());
+        let cursor = &window.cur_pos;
+        let mut candidates = vec![cursor.clone()];
+        if focused && matches!(self.mode, State::Insert | State::Replace) && cursor.x > 0 {
+            candidates.push(Position {
+                x: cursor.x.saturating_sub(1),
+                y: cursor.y,
+            });
+        }
+        candidates
+            .into_iter()
+            .find_map(|at| {
+                let found = find_match(document, filetype, &at, HIGHLIGHT_LINES)?;
+                Some(vec![at, found])
+            })
+            .unwrap_or_default()
+    }
+
+    // With `autopairs`, an opening bracket or quote typed in insert mode comes with its closing
+    // one, and typing that closing one then steps over it. Whether `c` was taken care of.
+    pub(super) fn auto_pair(&mut self, c: char) -> bool {
+        if !self.options.autopairs || self.mode != State::Insert {
+            return false;
+        }
+        let Position { x, y } = self.window().cur_pos;
+        let (previous, next) = match self.document().line(y) {
+            Some(line) => (
+                line.slice(x.saturating_sub(1), x).chars().next().filter(|_| x > 0),
+                line.slice(x, x.saturating_add(1)).chars().next(),
+            ),
+            None => (None, None),
+        };
+        let quotes = self.filetype().map_or(&['"'][..], |filetype| filetype.quotes);
+
+        if next == Some(c) && (quotes.contains(&c) || PAIRS.iter().any(|(_, close)| *close == c)) {
+            self.window_mut().cur_pos.x = x.saturating_add(1);
+            return true;
+        }
+        // Only where nothing but white space or a closing bracket follows, and quotes not right
+        // after a word, where they are more likely apostrophes
+        let open_space = next.map_or(true, |next| next.is_whitespace() || PAIRS.iter().any(|(_, close)| *close == next));
+        let close = match PAIRS.iter().find(|(open, _)| *open == c) {
+            Some((_, close)) => *close,
+            None if quotes.contains(&c) && !previous.map_or(false, char::is_alphanumeric) => c,
+            None => return false,
+        };
+        if !open_space {
+            return false;
+        }
+        let at = Position { x, y };
+        self.document_mut().insert_text(&at, &format!("{}{}", c, close));
+        self.window_mut().cur_pos.x = x.saturating_add(1);
+        true
+    }
+
+    // Backspace between an empty pair that `autopairs` would have typed deletes both halves
+    pub(super) fn delete_pair(&mut self) -> bool {
+        if !self.options.autopairs || self.mode != State::Insert {
+            return false;
+        }
+        let Position { x, y } = self.window().cur_pos;
+        let pair = self.document().line(y).map(|line| line.slice(x.saturating_sub(1), x.saturating_add(1)).to_string());
+        let quotes = self.filetype().map_or(&['"'][..], |filetype| filetype.quotes);
+        let mut chars = pair.as_deref().unwrap_or("").chars();
+        let paired = match (chars.next(), chars.next()) {
+            (Some(open), Some(close)) => {
+                (open == close && quotes.contains(&open)) || PAIRS.contains(&(open, close))
+            }
+            _ => false,
+        };
+        if x == 0 || !paired {
+            return false;
+        }
+        let start = Position { x: x.saturating_sub(1), y };
+        self.document_mut().delete_text(&start, &Position { x: x.saturating_add(1), y });
+        self.window_mut().cur_pos = start;
+        true
+    }
+}
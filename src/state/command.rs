@@ -0,0 +1,637 @@
+use std::mem;
+
+use termion::event::Key;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::error::Error;
+use crate::state::indent::leading_whitespace;
+use crate::state::keymap::{mapping_command, Action};
+use crate::state::mark::is_jump;
+use crate::state::register::Register;
+use crate::state::session::{HistoryKind, LAST_CURSOR};
+use crate::state::visual::{Selection, SelectionKind};
+use crate::state::wildmenu::Completer;
+use crate::state::window::{SplitDirection, Window};
+use crate::state::{Editor, Position, State, StatusMessage};
+use crate::{Document, Line};
+
+const HELP: &str = include_str!("../../help.txt");
+// What Tab completes in the command prompt, besides the mapping commands; the long names of
+// what `run_command` takes
+pub const COMMANDS: &[&str] = &[
+    "b", "bd", "cc", "cfile", "close", "cnext", "copen", "cprevious", "definition", "delmarks", "e", "get", "grep",
+    "help", "highlight", "hover", "jumps", "ls", "lsp", "make", "marks", "mksession", "o", "only", "q",
+    "references", "reflow", "registers", "reindent", "rename", "resize", "session", "set", "source", "split",
+    "tabclose", "tabmove", "tabname", "tabnew", "tabnext", "tabonly", "tabprevious", "theme", "toggle", "unset",
+    "vresize", "vsplit", "w",
+];
+
+impl Editor {
+    // Read a command in the message bar and run it
+    pub(super) fn command_prompt(&mut self) -> Result<(), Error> {
+        if let Some(command) = self.prompt(":", |_, _, _| {}, false, Some(HistoryKind::Command), Some(Completer::Command))? {
+            self.run_command(&command)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn run_command(&mut self, command: &str) -> Result<(), Error> {
+        let current = self.window().cur_pos.y;
+        let last = self.document().len().saturating_sub(1);
+        let (range, command) = match parse_range(command.trim(), current, last) {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                self.error(&error);
+                return Ok(());
+            }
+        };
+        let command = command.trim();
+        let (name, argument) = match command.find(' ') {
+            Some(index) => (&command[..index], Some(command[index..].trim())),
+            None => (command, None),
+        };
+        let (name, force) = match name.strip_suffix('!') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+
+        let shift = !name.is_empty() && (name.chars().all(|c| c == '>') || name.chars().all(|c| c == '<'));
+        if range.is_some() && !shift && !matches!(name, "" | "reindent" | "reflow") {
+            self.error(&format!("{} does not take a range", name));
+            return Ok(());
+        }
+        let (top, bottom) = range.unwrap_or((current, current));
+
+        match name {
+            // `:<line>` jumps there
+            "" => {
+                if range.is_some() {
+                    let here = self.window().cur_pos.clone();
+                    self.push_jump(here);
+                    self.window_mut().cur_pos = Position {
+                        x: self.first_non_blank(bottom),
+                        y: bottom,
+                    };
+                }
+            }
+            _ if shift => {
+                for _ in name.chars() {
+                    self.shift_lines(top, bottom, name.starts_with('>'));
+                }
+                self.window_mut().cur_pos = Position {
+                    x: self.first_non_blank(top),
+                    y: top,
+                };
+            }
+            "reindent" => self.reindent(top, bottom),
+            "reflow" => {
+                let (top, bottom) = range.unwrap_or_else(|| self.paragraph_bounds(current));
+                self.reflow(top, bottom);
+            }
+            "o" | "e" => match argument {
+                Some(filename) => self.open(filename),
+                None => self.error("Usage: o <filename>"),
+            },
+            "w" => self.write(argument.map(String::from))?,
+            "q" => self.quit_window(force),
+            "sp" | "split" => self.split(SplitDirection::Horizontal, argument),
+            "vs" | "vsplit" => self.split(SplitDirection::Vertical, argument),
+            "close" => self.close_window(),
+            "only" => self.layout_mut().only(),
+            "resize" => self.resize_command(SplitDirection::Horizontal, argument),
+            "vresize" => self.resize_command(SplitDirection::Vertical, argument),
+            "ls" => self.list_buffers(),
+            "bd" => self.delete_buffer(force),
+            "b" => self.switch_buffer(argument.unwrap_or("")),
+            "help" => self.show_help(),
+            "reg" | "registers" => {
+                let list = self.registers.list();
+                self.show_scratch(&list);
+            }
+            "marks" => self.list_marks(),
+            "jumps" | "ju" => self.list_jumps(),
+            "delmarks" | "delm" => self.delete_marks(argument, force),
+            "mksession" | "mks" => self.make_session(argument, force),
+            "session" => self.load_session(argument),
+            "tabnew" | "tabe" => self.tab_new(argument),
+            "tabclose" | "tabc" => self.tab_close(),
+            "tabonly" | "tabo" => self.tab_only(),
+            "tabnext" | "tabn" => self.tab_switch(argument, true),
+            "tabprevious" | "tabp" => self.tab_switch(argument, false),
+            "tabmove" | "tabm" => self.tab_move(argument),
+            "tabname" => self.tab_name(argument),
+            "hover" => self.hover(),
+            "definition" => self.definition(),
+            "references" => self.references(),
+            "rename" => self.rename(argument),
+            "make" => self.make(argument, force)?,
+            "cfile" | "cf" => self.quickfix_file(argument, force),
+            "copen" | "cope" => self.quickfix_open(),
+            "cnext" | "cn" => self.quickfix_step(true),
+            "cprevious" | "cprev" | "cp" | "cN" => self.quickfix_step(false),
+            "cc" => self.quickfix_current(argument),
+            "grep" => self.grep(argument),
+            "set" | "unset" | "toggle" | "get" | "theme" | "hi" | "highlight" | "source" | "lsp" => {
+                if let Err(error) = self.configure(command) {
+                    self.error(&error);
+                }
+            }
+            _ => {
+                // `b<numeral>` without a space
+                if let Some(number) = name.strip_prefix('b').filter(|number| number.parse::<usize>().is_ok()) {
+                    self.switch_buffer(number);
+                } else if mapping_command(name).is_some() {
+                    if let Err(error) = self.configure(command) {
+                        self.error(&error);
+                    }
+                } else {
+                    self.error(&format!("Unknown command: {}", name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Carry out an action bound to a key
+    pub(super) fn perform(&mut self, action: Action) -> Result<(), Error> {
+        if is_jump(action) {
+            let here = self.window().cur_pos.clone();
+            self.push_jump(here);
+        }
+        match action {
+            Action::Search => self.search()?,
+            Action::CommandPrompt => self.command_prompt()?,
+            Action::Save => self.save()?,
+            Action::Up => self.move_cursor(Key::Up),
+            Action::Down => self.move_cursor(Key::Down),
+            Action::Left => self.move_cursor(Key::Left),
+            Action::Right => self.move_cursor(Key::Right),
+            Action::PageUp => self.move_cursor(Key::PageUp),
+            Action::PageDown => self.move_cursor(Key::PageDown),
+            Action::Home => self.move_cursor(Key::Home),
+            Action::End => self.move_cursor(Key::End),
+            Action::Top => self.window_mut().cur_pos = Position::default(),
+            Action::WordForward => self.word_forward(false),
+            Action::WordBackward => self.word_backward(false),
+            Action::WordEnd => self.word_end(false),
+            Action::BigWordForward => self.word_forward(true),
+            Action::BigWordBackward => self.word_backward(true),
+            Action::BigWordEnd => self.word_end(true),
+            Action::SentenceForward => self.sentence_motion(true),
+            Action::SentenceBackward => self.sentence_motion(false),
+            Action::ParagraphForward => self.paragraph_motion(true),
+            Action::ParagraphBackward => self.paragraph_motion(false),
+            Action::MatchBracket => self.jump_to_match(),
+            Action::SetMark => self.set_mark()?,
+            Action::JumpToMark => self.jump_to_mark(true)?,
+            Action::JumpToMarkLine => self.jump_to_mark(false)?,
+            Action::JumpBack => self.jump_back(),
+            Action::JumpForward => self.jump_forward(),
+            Action::Bottom => {
+                let y = self.document().len().saturating_sub(1);
+                self.window_mut().cur_pos = Position {
+                    x: self.first_non_blank(y),
+                    y,
+                };
+            }
+            Action::Delete if self.mode == State::Visual => self.operate(action)?,
+            Action::Delete if self.mode == State::Normal => self.pending_operator(action)?,
+            Action::Delete | Action::DeleteChar => {
+                let at = self.window().cur_pos.clone();
+                if self.mode == State::Normal {
+                    let deleted = self
+                        .document()
+                        .line(at.y)
+                        .map(|line| line.slice(at.x, at.x.saturating_add(1)).to_string());
+                    if let Some(text) = deleted.filter(|text| !text.is_empty()) {
+                        self.store_register(Register { kind: SelectionKind::Char, text }, true)?;
+                    }
+                }
+                self.document_mut().delete(&at);
+            }
+            Action::DeleteLine => {
+                let anchor = self.window().cur_pos.clone();
+                self.window_mut().selection = Some(Selection { kind: SelectionKind::Line, anchor });
+                self.operate(Action::Delete)?;
+            }
+            Action::Put => self.put(false)?,
+            Action::PutBefore => self.put(true)?,
+            Action::Register => self.select_register()?,
+            Action::Undo | Action::Redo => {
+                let changed = if action == Action::Undo {
+                    self.document_mut().undo()
+                } else {
+                    self.document_mut().redo()
+                };
+                match changed {
+                    Some(y) => {
+                        let y = y.min(self.document().len().saturating_sub(1));
+                        self.window_mut().cur_pos = Position {
+                            x: self.first_non_blank(y),
+                            y,
+                        };
+                    }
+                    None if action == Action::Undo => self.error("Already at oldest change"),
+                    None => self.error("Already at newest change"),
+                }
+            }
+            Action::Backspace => {
+                if !self.delete_pair() && (self.window().cur_pos.x > 0 || self.window().cur_pos.y > 0) {
+                    self.move_cursor(Key::Left);
+                    let at = self.window().cur_pos.clone();
+                    self.document_mut().delete(&at);
+                }
+            }
+            Action::Split => self.split(SplitDirection::Horizontal, None),
+            Action::VerticalSplit => self.split(SplitDirection::Vertical, None),
+            Action::NextWindow => self.layout_mut().cycle(true),
+            Action::PreviousWindow => self.layout_mut().cycle(false),
+            Action::WindowLeft => self.layout_mut().focus_towards(SplitDirection::Vertical, false),
+            Action::WindowRight => self.layout_mut().focus_towards(SplitDirection::Vertical, true),
+            Action::WindowUp => self.layout_mut().focus_towards(SplitDirection::Horizontal, false),
+            Action::WindowDown => self.layout_mut().focus_towards(SplitDirection::Horizontal, true),
+            Action::CloseWindow => self.close_window(),
+            Action::OnlyWindow => self.layout_mut().only(),
+            Action::QuitWindow => self.quit_window(false),
+            Action::Taller => self.resize(SplitDirection::Horizontal, 1),
+            Action::Shorter => self.resize(SplitDirection::Horizontal, -1),
+            Action::Wider => self.resize(SplitDirection::Vertical, 1),
+            Action::Narrower => self.resize(SplitDirection::Vertical, -1),
+            Action::Equalize => self.layout_mut().equalize(),
+            Action::WindowToTab => self.window_to_tab(),
+            Action::NormalMode => {
+                if self.mode == State::Insert || self.mode == State::Replace {
+                    self.finish_block_insert();
+                    self.drop_empty_indent();
+                    let x = self.window().cur_pos.x;
+                    self.window_mut().cur_pos.x = x.saturating_sub(1);
+                }
+                self.window_mut().selection = None;
+                self.mode = State::Normal;
+            }
+            Action::Insert => self.mode = State::Insert,
+            Action::Append => {
+                let Position { x, y } = self.window().cur_pos;
+                if x < self.document().line(y).map_or(0, Line::len) {
+                    self.window_mut().cur_pos.x = x.saturating_add(1);
+                }
+                self.mode = State::Insert;
+            }
+            Action::AppendEnd => {
+                let y = self.window().cur_pos.y;
+                self.window_mut().cur_pos.x = self.document().line(y).map_or(0, Line::len);
+                self.mode = State::Insert;
+            }
+            Action::InsertStart => {
+                let y = self.window().cur_pos.y;
+                self.window_mut().cur_pos.x = self.first_non_blank(y);
+                self.mode = State::Insert;
+            }
+            Action::OpenBelow | Action::OpenAbove => {
+                let current = self.window().cur_pos.y;
+                let text = self.document().line(current).map_or("", Line::as_str).to_string();
+                let (y, indent) = if action == Action::OpenBelow {
+                    (current.saturating_add(1).min(self.document().len()), self.new_line_indent(current, &text))
+                } else if self.options.autoindent {
+                    (current, leading_whitespace(&text).to_string())
+                } else {
+                    (current, String::new())
+                };
+                self.document_mut().insert_line(y, &indent);
+                self.window_mut().cur_pos = Position {
+                    x: indent.graphemes(true).count(),
+                    y,
+                };
+                self.mode = State::Insert;
+            }
+            Action::ReplaceMode => self.mode = State::Replace,
+            Action::Visual => self.visual(SelectionKind::Char),
+            Action::VisualLine => self.visual(SelectionKind::Line),
+            Action::VisualBlock => self.visual(SelectionKind::Block),
+            Action::SwapAnchor => self.swap_anchor(),
+            Action::Yank
+            | Action::Change
+            | Action::Indent
+            | Action::Outdent
+            | Action::Reindent
+            | Action::Reflow
+            | Action::Lowercase
+            | Action::Uppercase
+                if self.mode == State::Normal =>
+            {
+                self.pending_operator(action)?
+            }
+            Action::Yank
+            | Action::Change
+            | Action::Indent
+            | Action::Outdent
+            | Action::Reindent
+            | Action::Reflow
+            | Action::ToggleCase
+            | Action::Lowercase
+            | Action::Uppercase => self.operate(action)?,
+            Action::YankLine => self.operate(Action::Yank)?,
+            Action::CompleteNext => self.complete(true),
+            Action::CompletePrevious => self.complete(false),
+            Action::CompleteLsp => self.complete_lsp(),
+            Action::Hover => self.hover(),
+            Action::Definition => self.definition(),
+            Action::References => self.references(),
+            Action::QuickfixNext => self.quickfix_step(true),
+            Action::QuickfixPrevious => self.quickfix_step(false),
+            Action::InnerObject | Action::AroundObject => self.select_object(action == Action::AroundObject)?,
+            Action::Nop
+            | Action::Quit
+            | Action::Accept
+            | Action::Cancel
+            | Action::HistoryBack
+            | Action::HistoryForward => (),
+        }
+        Ok(())
+    }
+
+    pub(super) fn error(&mut self, message: &str) {
+        self.status_message = StatusMessage::error(message.to_string());
+    }
+
+    // Show `filename` in the focused window, reusing its buffer if it is already open
+    pub(super) fn open(&mut self, filename: &str) {
+        let index = self.buffer_for(filename);
+        self.show_document(index);
+    }
+
+    // Index of the buffer holding `filename`, opening it first if needed
+    pub(super) fn buffer_for(&mut self, filename: &str) -> usize {
+        if let Some(index) = self
+            .documents
+            .iter()
+            .position(|document| document.filename.as_deref() == Some(filename))
+        {
+            return index;
+        }
+        let document = match Document::open(filename) {
+            Ok(document) => document,
+            Err(_) => {
+                self.status_message = StatusMessage::from(format!("New file: {}", filename));
+                Document::new(filename)
+            }
+        };
+        self.documents.push(document);
+        let index = self.documents.len().saturating_sub(1);
+        self.restore_file(index);
+        index
+    }
+
+    pub(super) fn show_document(&mut self, index: usize) {
+        if self.window().document != index {
+            let here = self.window().cur_pos.clone();
+            self.push_jump(here);
+            self.set_document(index);
+        }
+    }
+
+    // Switch the focused window to another buffer, keeping its jump list; the cursor goes back
+    // to where it was when that buffer was left
+    pub(super) fn set_document(&mut self, index: usize) {
+        let here = self.window().cur_pos.clone();
+        self.document_mut().marks.set(LAST_CURSOR, here);
+        let window = self.window_mut();
+        *window = Window {
+            rect: window.rect,
+            gutter: window.gutter,
+            jumps: mem::take(&mut window.jumps),
+            ..Window::new(index)
+        };
+        self.restore_cursor();
+    }
+
+    fn split(&mut self, direction: SplitDirection, filename: Option<&str>) {
+        self.layout_mut().split(direction);
+        if let Some(filename) = filename {
+            self.open(filename);
+        }
+    }
+
+    fn close_window(&mut self) {
+        if !self.layout_mut().close() {
+            self.error("Cannot close the last window");
+        }
+    }
+
+    // Close the focused window, or leave the editor if it is the last one
+    fn quit_window(&mut self, force: bool) {
+        if self.layout().windows.len() > 1 {
+            self.layout_mut().close();
+        } else if self.tabs.len() > 1 {
+            self.tab_close();
+        } else if force || !self.documents.iter().any(Document::is_changed) {
+            self.quit = true;
+        } else {
+            self.error("No write since last change (add ! to override)");
+        }
+    }
+
+    fn resize(&mut self, direction: SplitDirection, delta: isize) {
+        if !self.layout_mut().resize(direction, delta) {
+            self.error("No split to resize in that direction");
+        }
+    }
+
+    // `resize +N`, `resize -N` or `resize N` (absolute rows, or columns for `vresize`)
+    fn resize_command(&mut self, direction: SplitDirection, argument: Option<&str>) {
+        let argument = argument.unwrap_or("");
+        let current = match direction {
+            SplitDirection::Horizontal => self.window().text_height(),
+            SplitDirection::Vertical => self.window().text_width(),
+        } as isize;
+
+        let delta = if let Some(delta) = argument.strip_prefix('+') {
+            delta.parse::<isize>().ok()
+        } else if argument.starts_with('-') {
+            argument.parse::<isize>().ok()
+        } else {
+            argument.parse::<isize>().ok().map(|size| size.saturating_sub(current))
+        };
+        match delta {
+            Some(delta) => self.resize(direction, delta),
+            None => self.error("Usage: resize [+-]<numeral>"),
+        }
+    }
+
+    fn list_buffers(&mut self) {
+        let current = self.window().document;
+        let list: Vec<String> = self
+            .documents
+            .iter()
+            .enumerate()
+            .map(|(index, document)| {
+                format!(
+                    "{}{}:{}{}",
+                    if index == current { "%" } else { "" },
+                    index.saturating_add(1),
+                    document.display_name(),
+                    if document.is_changed() { "+" } else { "" }
+                )
+            })
+            .collect();
+        self.status_message = StatusMessage::from(list.join("  "));
+    }
+
+    // `b<numeral>`, counting from 1 like `ls` does, or `b <name>` with the name or a part of it
+    // that only one buffer has
+    fn switch_buffer(&mut self, buffer: &str) {
+        let buffer = buffer.trim();
+        if buffer.is_empty() {
+            self.error("Usage: b <numeral> or b <name>");
+            return;
+        }
+        if let Ok(number) = buffer.parse::<usize>() {
+            if number >= 1 && number <= self.documents.len() {
+                self.show_document(number.saturating_sub(1));
+            } else {
+                self.error(&format!("No such buffer: {}", number));
+            }
+            return;
+        }
+        let named = |exact: bool| -> Vec<usize> {
+            let names = self.documents.iter().map(Document::display_name).enumerate();
+            names
+                .filter(|(_, name)| if exact { name == buffer } else { name.contains(buffer) })
+                .map(|(index, _)| index)
+                .collect()
+        };
+        let mut found = named(true);
+        if found.is_empty() {
+            found = named(false);
+        }
+        match found.as_slice() {
+            [index] => self.show_document(*index),
+            [] => self.error(&format!("No such buffer: {}", buffer)),
+            _ => self.error(&format!("More than one buffer matches {}", buffer)),
+        }
+    }
+
+    fn delete_buffer(&mut self, force: bool) {
+        let index = self.window().document;
+        if !force && self.document().is_changed() {
+            self.error("No write since last change (add ! to override)");
+            return;
+        }
+
+        self.document_mut().remove_swap();
+        self.remember_buffer(index);
+        self.keep_file_marks(index);
+        if self.documents.len() == 1 {
+            self.documents.push(Document::default());
+        }
+        // Windows showing the buffer move on to its neighbour, numbered as before the removal
+        let replacement = if index.saturating_add(1) < self.documents.len() {
+            index.saturating_add(1)
+        } else {
+            index.saturating_sub(1)
+        };
+        self.documents.remove(index);
+        for tab in &mut self.tabs {
+            tab.layout.remove_document(index, replacement);
+        }
+    }
+
+    // The help buffer, made the first time it is asked for
+    fn show_help(&mut self) {
+        let index = match self.documents.iter().position(|document| document.help) {
+            Some(index) => index,
+            None => {
+                let lines: Vec<String> = HELP.lines().map(String::from).collect();
+                let mut document = Document::scratch(&lines);
+                document.help = true;
+                self.documents.push(document);
+                self.documents.len().saturating_sub(1)
+            }
+        };
+        self.show_document(index);
+    }
+
+    // Show `lines` in the unnamed buffer kept for listings, made the first time one is shown
+    pub(super) fn show_scratch(&mut self, lines: &[String]) {
+        let mut document = Document::scratch(lines);
+        document.scratch = true;
+        let index = match self.documents.iter().position(|document| document.scratch) {
+            Some(index) => index,
+            None => {
+                self.documents.push(Document::default());
+                self.documents.len().saturating_sub(1)
+            }
+        };
+        if let Some(slot) = self.documents.get_mut(index) {
+            *slot = document;
+        }
+        self.show_document(index);
+        self.window_mut().cur_pos = Position::default();
+    }
+}
+
+// Split a leading line range off `command`: `%` for every line, or one or two addresses
+// separated by a comma, each a line number, `.` or `$` with optional `+<n>` and `-<n>` offsets.
+// Lines are returned counting from 0.
+fn parse_range(command: &str, current: usize, last: usize) -> Result<(Option<(usize, usize)>, &str), String> {
+    if let Some(rest) = command.strip_prefix('%') {
+        return Ok((Some((0, last)), rest));
+    }
+    let (first, rest) = parse_address(command, current, last)?;
+    let first = match first {
+        Some(first) => first,
+        None => return Ok((None, command)),
+    };
+    let (second, rest) = match rest.strip_prefix(',') {
+        Some(rest) => match parse_address(rest, current, last)? {
+            (Some(second), rest) => (second, rest),
+            (None, _) => return Err("Missing line after ,".to_string()),
+        },
+        None => (first, rest),
+    };
+    if second < first {
+        return Err("Backwards range".to_string());
+    }
+    Ok((Some((first, second)), rest))
+}
+
+fn parse_address(text: &str, current: usize, last: usize) -> Result<(Option<usize>, &str), String> {
+    let (mut line, mut rest) = if let Some(rest) = text.strip_prefix('.') {
+        (Some(current), rest)
+    } else if let Some(rest) = text.strip_prefix('$') {
+        (Some(last), rest)
+    } else {
+        match split_number(text)? {
+            (Some(number), rest) => (Some(number.saturating_sub(1)), rest),
+            (None, rest) => (None, rest),
+        }
+    };
+    loop {
+        let (forward, after) = match (rest.strip_prefix('+'), rest.strip_prefix('-')) {
+            (Some(after), _) => (true, after),
+            (_, Some(after)) => (false, after),
+            _ => break,
+        };
+        let (count, after) = split_number(after)?;
+        let count = count.unwrap_or(1);
+        let from = line.unwrap_or(current);
+        line = Some(if forward {
+            from.saturating_add(count)
+        } else {
+            from.saturating_sub(count)
+        });
+        rest = after;
+    }
+    Ok((line.map(|line| line.min(last)), rest))
+}
+
+// A number at the start of `text`, and what follows it
+fn split_number(text: &str) -> Result<(Option<usize>, &str), String> {
+    let digits = text.len().saturating_sub(text.trim_start_matches(|c: char| c.is_ascii_digit()).len());
+    if digits == 0 {
+        return Ok((None, text));
+    }
+    let (number, rest) = text.split_at(digits);
+    let number = number.parse().map_err(|_| format!("Invalid line number: {}", number))?;
+    Ok((Some(number), rest))
+}
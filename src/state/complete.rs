@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+
+use termion::event::Key;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::state::keymap::{Action, Input};
+use crate::state::{Editor, Position, State};
+use crate::terminal::Screen;
+use crate::Line;
+
+// Candidates shown at once; the menu scrolls to keep the selected one in view
+const MENU_HEIGHT: usize = 10;
+// More than this and the rest are left out
+const CANDIDATE_LIMIT: usize = 1000;
+
+/// Completion of the word before the cursor in insert mode, shown in a popup menu.
+pub struct Completion {
+    start: Position, // Where the word being completed starts
+    prefix: String, // What was typed of it
+    candidates: Vec<String>,
+    selected: Option<usize>, // None while the word is back to just what was typed
+}
+
+fn is_keyword(grapheme: &str) -> bool {
+    grapheme.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// Runs of word characters in `text`, and the grapheme each one starts at
+fn keywords(text: &str) -> Vec<(usize, String)> {
+    let mut found: Vec<(usize, String)> = Vec::new();
+    let mut previous = false;
+    for (x, grapheme) in text.graphemes(true).enumerate() {
+        let keyword = is_keyword(grapheme);
+        match found.last_mut() {
+            Some((_, word)) if keyword && previous => word.push_str(grapheme),
+            _ if keyword => found.push((x, grapheme.to_string())),
+            _ => (),
+        }
+        previous = keyword;
+    }
+    found
+}
+
+impl Editor {
+    // Words of the open buffers starting with `prefix`, nearest first: from the word at `start`
+    // on through this buffer and round from its top, then the other buffers. The word at
+    // `start` itself is left out.
+    fn candidates(&self, prefix: &str, start: &Position) -> Vec<String> {
+        let current = self.window().document;
+        let len = self.document().len();
+        let mut lines: Vec<(usize, usize)> = (start.y..len).chain(0..=start.y.min(len)).map(|y| (current, y)).collect();
+        for (index, other) in self.documents.iter().enumerate().filter(|(index, _)| *index != current) {
+            lines.extend((0..other.len()).map(|y| (index, y)));
+        }
+
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut candidates = Vec::new();
+        for (pass, (index, y)) in lines.into_iter().enumerate() {
+            let text = self.documents.get(index).and_then(|document| document.line(y)).map_or("", Line::as_str);
+            for (x, word) in keywords(text) {
+                // The cursor line is looked at twice: after the word first, before it at the end
+                let here = index == current && y == start.y;
+                let wanted = !here || if pass == 0 { x > start.x } else { x < start.x };
+                if wanted && word.starts_with(prefix) && word != prefix && seen.insert(word.clone()) {
+                    candidates.push(word);
+                    if candidates.len() >= CANDIDATE_LIMIT {
+                        return candidates;
+                    }
+                }
+            }
+        }
+        candidates
+    }
+
+    // Where the word before the cursor starts, and what of it is there
+    pub(super) fn word_before_cursor(&self) -> (Position, String) {
+        let Position { x, y } = self.window().cur_pos;
+        let line = self.document().line(y).map_or("", Line::as_str);
+        let graphemes: Vec<&str> = line.graphemes(true).take(x).collect();
+        let typed = graphemes.iter().rev().take_while(|grapheme| is_keyword(grapheme)).count();
+        let start = Position {
+            x: x.saturating_sub(typed),
+            y,
+        };
+        let prefix = graphemes.get(start.x..).map(<[&str]>::concat).unwrap_or_default();
+        (start, prefix)
+    }
+
+    // `Ctrl-N` (and `complete_previous`): complete the word before the cursor, or go to the
+    // next (or previous) candidate when already completing
+    pub(super) fn complete(&mut self, forward: bool) {
+        if self.mode != State::Insert {
+            return;
+        }
+        if self.completion.is_none() {
+            let (start, prefix) = self.word_before_cursor();
+            let candidates = self.candidates(&prefix, &start);
+            if !self.show_completions(start, prefix, candidates) {
+                return;
+            }
+        }
+        self.select_candidate(forward);
+    }
+
+    // Open the menu on `candidates` for the word at `start`; false when there are none
+    pub(super) fn show_completions(&mut self, start: Position, prefix: String, candidates: Vec<String>) -> bool {
+        if candidates.is_empty() {
+            self.error("No completions");
+            return false;
+        }
+        self.completion = Some(Completion {
+            start,
+            prefix,
+            candidates,
+            selected: None,
+        });
+        true
+    }
+
+    // Move through the candidates, going past either end back to what was typed, and put the
+    // one selected in place of the word
+    pub(super) fn select_candidate(&mut self, forward: bool) {
+        let (start, word) = match self.completion.as_mut() {
+            Some(completion) => {
+                let last = completion.candidates.len().saturating_sub(1);
+                completion.selected = match (completion.selected, forward) {
+                    (None, true) => Some(0),
+                    (None, false) => Some(last),
+                    (Some(index), true) if index >= last => None,
+                    (Some(index), true) => Some(index.saturating_add(1)),
+                    (Some(0), false) => None,
+                    (Some(index), false) => Some(index.saturating_sub(1)),
+                };
+                let word = match completion.selected {
+                    Some(index) => completion.candidates.get(index).cloned().unwrap_or_default(),
+                    None => completion.prefix.clone(),
+                };
+                (completion.start.clone(), word)
+            }
+            None => return,
+        };
+        let x = self.window().cur_pos.x;
+        self.document_mut().edit_line(start.y, start.x, x, |_| word.clone());
+        self.window_mut().cur_pos = Position {
+            x: start.x.saturating_add(word.graphemes(true).count()),
+            y: start.y,
+        };
+    }
+
+    // Keys that work the menu while it is open: <Down> and <Up> move through it, <CR> takes the
+    // selected word, and <C-e> goes back to what was typed. Whether `input` was used up.
+    pub(super) fn completion_key(&mut self, input: &Input) -> bool {
+        let selected = match &self.completion {
+            Some(completion) => completion.selected.is_some(),
+            None => return false,
+        };
+        match input {
+            Input::Action(Action::Down) => self.select_candidate(true),
+            Input::Action(Action::Up) => self.select_candidate(false),
+            Input::Key(Key::Char('\n')) if selected => self.completion = None,
+            Input::Key(Key::Ctrl('e')) => {
+                if let Some(completion) = self.completion.as_mut() {
+                    completion.selected = completion.candidates.len().checked_sub(1);
+                }
+                self.select_candidate(true);
+                self.completion = None;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // After other input: typing more of the word narrows the candidates down, deleting some of
+    // it looks for them again, and anything else closes the menu
+    pub(super) fn update_completion(&mut self, typing: bool) {
+        let completion = match self.completion.take() {
+            Some(completion) => completion,
+            None => return,
+        };
+        let Position { x, y } = self.window().cur_pos;
+        if self.mode != State::Insert || !typing || y != completion.start.y || x <= completion.start.x {
+            return;
+        }
+        let typed = self
+            .document()
+            .line(y)
+            .map_or("", |line| line.slice(completion.start.x, x))
+            .to_string();
+        if !typed.graphemes(true).all(is_keyword) {
+            return;
+        }
+        let candidates = if typed.starts_with(&completion.prefix) {
+            completion
+                .candidates
+                .into_iter()
+                .filter(|candidate| candidate.starts_with(&typed) && *candidate != typed)
+                .collect()
+        } else {
+            self.candidates(&typed, &completion.start)
+        };
+        if !candidates.is_empty() {
+            self.completion = Some(Completion {
+                start: completion.start,
+                prefix: typed,
+                candidates,
+                selected: None,
+            });
+        }
+    }
+
+    // The menu, under the word being completed or over it when there is more room there
+    pub(super) fn draw_completion(&self, screen: &mut Screen) {
+        let completion = match &self.completion {
+            Some(completion) => completion,
+            None => return,
+        };
+        let window = self.window();
+        let row = window.rect.y.saturating_add(completion.start.y.saturating_sub(window.offset.y));
+        let column = self.screen_column(window, &completion.start);
+
+        let shown = completion.candidates.len().min(MENU_HEIGHT);
+        let text_bottom = screen.height().saturating_sub(1); // Above the message bar
+        let below = text_bottom.saturating_sub(row.saturating_add(1));
+        let top = if below >= shown || below >= row {
+            row.saturating_add(1)
+        } else {
+            row.saturating_sub(shown)
+        };
+        let rows = shown.min(text_bottom.saturating_sub(top));
+        let first = match completion.selected {
+            Some(selected) if selected >= rows => selected.saturating_add(1).saturating_sub(rows),
+            _ => 0,
+        };
+
+        let visible = completion.candidates.iter().enumerate().skip(first).take(rows);
+        let width = completion
+            .candidates
+            .iter()
+            .map(|candidate| candidate.width())
+            .max()
+            .unwrap_or(0)
+            .saturating_add(2)
+            .min(screen.width());
+        // One column of padding before the words, so that they line up with the one typed
+        let left = column.saturating_sub(1).min(screen.width().saturating_sub(width));
+        for (offset, (index, candidate)) in visible.enumerate() {
+            let style = if completion.selected == Some(index) {
+                self.theme.menu_selected
+            } else {
+                self.theme.menu
+            };
+            let y = top.saturating_add(offset);
+            screen.fill(left, y, width, style);
+            screen.put_str(left.saturating_add(1), y, candidate, style);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::state::keymap::mapping_command;
+use crate::state::theme::Theme;
+use crate::state::{Editor, StatusMessage};
+
+// `$XDG_CONFIG_HOME/sodium/config`, falling back to `~/.config/sodium/config`
+pub fn path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("sodium").join("config"))
+}
+
+impl Editor {
+    // Read the user's config file, if there is one; mistakes are reported in the message bar
+    pub(super) fn load_config(&mut self) {
+        if let Some(path) = path() {
+            match fs::read_to_string(&path) {
+                Ok(contents) => {
+                    if let Err(error) = self.apply_config(&path, &contents) {
+                        self.status_message = StatusMessage::error(error);
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+                Err(error) => self.report(&format!("Could not read {}", path.display()), &error),
+            }
+        }
+    }
+
+    fn source(&mut self, filename: &str) -> Result<(), String> {
+        let path = fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+        if self.sourcing.contains(&path) {
+            return Err(format!("{} is already being sourced", filename));
+        }
+        match fs::read_to_string(filename) {
+            Ok(contents) => self.apply_config(Path::new(filename), &contents),
+            Err(error) => Err(format!("Could not read {}: {}", filename, error)),
+        }
+    }
+
+    // One setting per line; blank lines and lines starting with `#` or `"` are skipped. The
+    // first mistake is returned, with how many more there are.
+    fn apply_config(&mut self, path: &Path, contents: &str) -> Result<(), String> {
+        // A file sourcing itself, or one that leads back to it, would never finish
+        self.sourcing.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+        let mut errors = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('"') {
+                continue;
+            }
+            if let Err(error) = self.configure(line) {
+                errors.push(format!("{}:{}: {}", path.display(), number.saturating_add(1), error));
+            }
+        }
+
+        self.sourcing.pop();
+
+        match errors.first() {
+            Some(first) => {
+                let more = match errors.len() {
+                    1 => String::new(),
+                    count => format!(" (and {} more errors)", count.saturating_sub(1)),
+                };
+                Err(format!("{}{}", first, more))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Settings shared by the config file and the command prompt
+    pub(super) fn configure(&mut self, line: &str) -> Result<(), String> {
+        let (name, argument) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+        let required = |usage: &str| {
+            if argument.is_empty() {
+                Err(format!("Usage: {} {}", name, usage))
+            } else {
+                Ok(argument)
+            }
+        };
+
+        if let Some((states, command)) = mapping_command(name) {
+            return self.configure_mapping(&states, command, argument);
+        }
+        match name {
+            "set" => self.options.set(required("<option>[=<value>]")?),
+            "unset" => self.options.unset(required("<option>")?),
+            "toggle" => self.options.toggle(required("<option>")?),
+            "get" => {
+                let value = self.options.get(required("<option>")?)?;
+                self.status_message = StatusMessage::from(value);
+                Ok(())
+            }
+            "theme" => {
+                if argument.is_empty() {
+                    // The current theme is marked with `*`
+                    let list: Vec<String> = Theme::list()
+                        .into_iter()
+                        .map(|name| if name == self.theme.name { format!("*{}", name) } else { name })
+                        .collect();
+                    self.status_message = StatusMessage::from(list.join("  "));
+                    return Ok(());
+                }
+                self.theme = Theme::named(argument)?;
+                Ok(())
+            }
+            "hi" | "highlight" if argument.is_empty() => {
+                let faces = self.theme.faces();
+                self.show_scratch(&faces);
+                Ok(())
+            }
+            "hi" | "highlight" => {
+                let (face, attributes) = argument.split_at(argument.find(' ').unwrap_or(argument.len()));
+                self.theme.highlight(face, attributes)
+            }
+            "source" => self.source(required("<filename>")?),
+            "lsp" => self.lsp_command(argument),
+            _ => Err(format!("Unknown setting: {}", name)),
+        }
+    }
+}
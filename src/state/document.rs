@@ -1,7 +1,49 @@
 use crate::Line;
+use std::collections::hash_map::DefaultHasher;
 use std::fs;
+use std::hash::{Hash, Hasher};
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::SystemTime;
+use crate::state::mark::Marks;
 use crate::state::{Position, SearchDirection};
-use std::io::{Error, Write};
+use crate::state::swap::{self, Recovery};
+use std::io::{Error, ErrorKind, Write};
+use unicode_segmentation::UnicodeSegmentation;
+
+const UNDO_LEVELS: usize = 1000;
+
+// What the file looked like on disk when we last read or wrote it
+#[derive(PartialEq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl Stamp {
+    fn of(filename: &str, data: &[u8]) -> Self {
+        let modified = fs::metadata(filename).and_then(|meta| meta.modified()).ok();
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+
+        Self {
+            modified,
+            len: data.len() as u64,
+            hash: hasher.finish(),
+        }
+    }
+}
+
+// One undo step: lines `start..len - suffix` of the text took the place of `lines`
+struct Step {
+    id: u64,
+    start: usize,
+    suffix: usize, // Lines after the changed ones, which the step left alone
+    lines: Vec<Line>,
+    marks: Marks, // As they were before the step, put back for the lines it brings back
+}
 
 #[derive(Default)]
 pub struct Document {
@@ -9,6 +51,23 @@ pub struct Document {
     pub filename: Option<String>,
     pub name: bool, // In case our file does not exist, we will still set name = true since we may want to name the file with the entered filename
     pub changed: bool, // Has been changed since we opened it?
+    pub recovery: Option<Recovery>, // Stale swap file found when the document was opened
+    swap: Option<PathBuf>, // Swap file we last journaled to
+    revision: u64, // Bumped on every edit
+    journaled: u64, // Revision last written to the swap file
+    disk: Option<Stamp>, // State of the file on disk at the last load or save
+    pub read_only: bool, // Another session is editing the file, writing it needs a confirmation
+    pub disk_warned: bool, // User already chose to keep their changes over the ones on disk
+    history: Vec<Step>, // Undo steps, oldest first
+    future: Vec<Step>, // Undone steps, for redo
+    recording: bool, // Edits still belong to the step on top of `history`
+    steps: u64, // Undo steps made so far, numbering the next one
+    base: u64, // Step the oldest one in `history` undoes to, 0 for the text as opened
+    saved: u64, // Step the file on disk matches
+    pub marks: Marks,
+    pub quickfix: bool, // Lists the quickfix entries, <CR> goes to the one under the cursor
+    pub help: bool, // Shows the help text
+    pub scratch: bool, // Shows a listing, replaced by the next one
 }
 
 impl Document {
@@ -27,9 +86,63 @@ impl Document {
             filename: Some(filename.to_string()),
             name: true,
             changed: false,
+            recovery: swap::find(filename),
+            disk: Some(Stamp::of(filename, data.as_bytes())),
+            ..Self::default()
         })
     }
 
+    // Throw away the buffer and read the file from disk again
+    pub fn reload(&mut self) -> Result<(), Error> {
+        if let Some(filename) = &self.filename {
+            let data = fs::read_to_string(filename)?;
+            self.lines = data.lines().map(Line::from).collect();
+            self.history.clear();
+            self.future.clear();
+            self.recording = false;
+            self.saved = self.state();
+            self.disk = Some(Stamp::of(filename, data.as_bytes()));
+            self.changed = false;
+            self.revision = self.revision.wrapping_add(1);
+            self.remove_swap();
+        }
+        Ok(())
+    }
+
+    // Has the file on disk been changed by someone else since we last read or wrote it?
+    pub fn changed_on_disk(&mut self) -> bool {
+        let filename = if let Some(filename) = &self.filename {
+            filename
+        } else {
+            return false;
+        };
+        let meta = if let Ok(meta) = fs::metadata(filename) {
+            meta
+        } else {
+            // Deleted (or never created): saving will simply write it again
+            return false;
+        };
+
+        let stamp = if let Some(stamp) = &mut self.disk {
+            stamp
+        } else {
+            return true;
+        };
+        if stamp.modified == meta.modified().ok() && stamp.len == meta.len() {
+            return false;
+        }
+
+        // Only the metadata moved (e.g. `touch`), so compare the contents before complaining
+        if let Ok(data) = fs::read(filename) {
+            let current = Stamp::of(filename, &data);
+            if current.hash == stamp.hash && current.len == stamp.len {
+                stamp.modified = current.modified;
+                return false;
+            }
+        }
+        true
+    }
+
     // Create new file with supplied filename
     pub fn new(filename: &str) -> Self {
         let lines = Vec::new();
@@ -39,6 +152,16 @@ impl Document {
             filename: Some(filename.to_string()),
             name: true,
             changed: false,
+            recovery: swap::find(filename),
+            ..Self::default()
+        }
+    }
+
+    // Unnamed, read-only style document holding the given lines
+    pub fn scratch(lines: &[String]) -> Self {
+        Self {
+            lines: lines.iter().map(|line| Line::from(line.as_str())).collect(),
+            ..Self::default()
         }
     }
 
@@ -46,26 +169,205 @@ impl Document {
     pub fn save(&mut self) -> Result<(), Error> {
 
         if let Some(filename) = &self.filename {
-            let mut file = fs::File::create(filename)?;
+            let mut data = Vec::new();
             for line in &self.lines {
-                file.write_all(line.as_bytes())?;
-                file.write_all(b"\n")?;
+                data.extend_from_slice(line.as_bytes());
+                data.push(b'\n');
             }
+            fs::File::create(filename)?.write_all(&data)?;
+            self.disk = Some(Stamp::of(filename, &data));
             self.changed = false;
+            // Edits after this are a step of their own, so undoing them comes back here
+            self.recording = false;
+            self.saved = self.state();
+            self.remove_swap();
         }
         Ok(())
     }
 
+    // Write unsaved changes to the swap file, if there are any we have not journaled yet
+    pub fn journal(&mut self) -> Result<(), std::io::Error> {
+        if !self.changed || self.revision == self.journaled {
+            return Ok(());
+        }
+
+        let paths = swap::paths_for(self.filename.as_deref());
+        if self.swap.as_ref().map_or(false, |swap| !paths.contains(swap)) {
+            self.remove_swap();
+        }
+        if self.swap.is_none() {
+            // Never write over a swap file we did not create: another session may own it, or it
+            // is a stale one the user chose to keep
+            self.swap = paths.into_iter().find(|path| !path.exists());
+            if self.swap.is_none() {
+                return Err(Error::new(ErrorKind::AlreadyExists, "all swap file names are taken"));
+            }
+        }
+        if let Some(path) = &self.swap {
+            swap::write(path, self.filename.as_deref(), &self.lines)?;
+        }
+        self.journaled = self.revision;
+        Ok(())
+    }
+
+    // Last resort before a fatal exit: get unsaved changes onto disk somewhere, preferably the swap file
+    pub fn emergency_save(&mut self) -> Option<PathBuf> {
+        if !self.changed {
+            return None;
+        }
+        if self.journal().is_ok() {
+            if let Some(path) = &self.swap {
+                return Some(path.clone());
+            }
+        }
+
+        let name = self
+            .filename
+            .as_deref()
+            .and_then(|filename| std::path::Path::new(filename).file_name())
+            .map_or_else(|| "untitled".to_string(), |name| name.to_string_lossy().into_owned());
+        let path = env::temp_dir().join(format!("sodium-{}-{}", process::id(), name));
+
+        let mut data = Vec::new();
+        for line in &self.lines {
+            data.extend_from_slice(line.as_bytes());
+            data.push(b'\n');
+        }
+        fs::write(&path, data).ok().map(|()| path)
+    }
+
+    // Number of edits made since the last journal
+    pub fn unjournaled(&self) -> u64 {
+        self.revision.wrapping_sub(self.journaled)
+    }
+
+    pub fn remove_swap(&mut self) {
+        if let Some(path) = self.swap.take() {
+            swap::remove(&path);
+        }
+        self.journaled = self.revision;
+    }
+
+    // Replace the contents with the ones recovered from a swap file
+    pub fn restore(&mut self, recovery: &Recovery) {
+        self.touch(0, self.lines.len());
+        self.lines = recovery.lines.iter().map(|line| Line::from(line.as_str())).collect();
+        self.swap = Some(recovery.path.clone());
+    }
+
+    // Called before every edit, with the lines `start..end` it is going to change
+    #[allow(clippy::indexing_slicing)]
+    fn touch(&mut self, start: usize, end: usize) {
+        let len = self.lines.len();
+        let end = end.min(len);
+        let start = start.min(end);
+        let growing = if self.recording { self.history.last_mut() } else { None };
+        if let Some(step) = growing {
+            // Take in the lines of this edit that the step has not changed yet
+            if start < step.start {
+                step.lines.splice(0..0, self.lines[start..step.start].iter().cloned());
+                step.start = start;
+            }
+            let top = len.saturating_sub(step.suffix);
+            if end > top {
+                step.lines.extend(self.lines[top..end].iter().cloned());
+                step.suffix = len.saturating_sub(end);
+            }
+        } else {
+            self.steps = self.steps.wrapping_add(1);
+            self.history.push(Step {
+                id: self.steps,
+                start,
+                suffix: len.saturating_sub(end),
+                lines: self.lines[start..end].to_vec(),
+                marks: self.marks.clone(),
+            });
+            if self.history.len() > UNDO_LEVELS {
+                self.base = self.history.remove(0).id;
+            }
+            self.future.clear();
+            self.recording = true;
+        }
+        self.changed = true;
+        self.revision = self.revision.wrapping_add(1);
+    }
+
+    // End the current undo step; the next edit starts a new one
+    pub fn seal(&mut self) {
+        self.recording = false;
+    }
+
+    // The undo step the text is at
+    fn state(&self) -> u64 {
+        self.history.last().map_or(self.base, |step| step.id)
+    }
+
+    // Go back to before the last undo step, returning the first line that changed
+    pub fn undo(&mut self) -> Option<usize> {
+        let step = self.history.pop()?;
+        let (redo, line) = self.apply(step);
+        self.future.push(redo);
+        self.undone();
+        Some(line)
+    }
+
+    pub fn redo(&mut self) -> Option<usize> {
+        let step = self.future.pop()?;
+        let (undo, line) = self.apply(step);
+        self.history.push(undo);
+        self.undone();
+        Some(line)
+    }
+
+    // Put back the lines of `step`, returning the step that takes them out again and the first
+    // line that changed
+    #[allow(clippy::indexing_slicing)]
+    fn apply(&mut self, step: Step) -> (Step, usize) {
+        let Step { id, start, suffix, lines, marks } = step;
+        let end = self.lines.len().saturating_sub(suffix).max(start);
+        let added = lines.len();
+        let removed: Vec<Line> = self.lines.splice(start..end, lines).collect();
+        let line = start.saturating_add(first_difference(&removed, &self.lines[start..start.saturating_add(added)]));
+
+        let current = self.marks.clone();
+        self.marks.lines_replaced(start, removed.len(), added);
+        for (name, position) in marks.iter() {
+            if position.y >= start && position.y < start.saturating_add(added) {
+                self.marks.set(name, position.clone());
+            }
+        }
+        let inverse = Step {
+            id,
+            start,
+            suffix,
+            lines: removed,
+            marks: current,
+        };
+        (inverse, line)
+    }
+
+    fn undone(&mut self) {
+        self.recording = false;
+        self.changed = self.state() != self.saved;
+        self.revision = self.revision.wrapping_add(1);
+        // Back to what is on disk: a swap file would only hold that
+        if !self.changed {
+            self.remove_swap();
+        }
+    }
+
     pub fn insert(&mut self, at: &Position, c: char) {
         if at.y > self.lines.len() {
             return;
         }
-        self.changed = true;
+        self.touch(at.y, at.y.saturating_add(1));
 
         if c == '\n' {
             self.insert_newline(at);
+            self.marks.text_replaced(at, at, &Position { x: 0, y: at.y.saturating_add(1) });
             return;
         }
+        self.marks.text_replaced(at, at, &Position { x: at.x.saturating_add(1), y: at.y });
         if at.y == self.lines.len() {
             let mut line = Line::default();
             line.insert(0, c);
@@ -122,15 +424,17 @@ impl Document {
         if at.y >= len {
             return;
         }
-        self.changed = true;
+        self.touch(at.y, at.y.saturating_add(2));
 
         if at.x == self.lines[at.y].len() && at.y + 1 < len {
             let next_line = self.lines.remove(at.y + 1);
             let line = &mut self.lines[at.y];
             line.append(&next_line);
+            self.marks.text_replaced(at, &Position { x: 0, y: at.y + 1 }, at);
         } else {
             let line = &mut self.lines[at.y];
             line.delete(at.x);
+            self.marks.text_replaced(at, &Position { x: at.x + 1, y: at.y }, at);
         }
     }
 
@@ -150,6 +454,174 @@ impl Document {
         self.lines.insert(at.y + 1, new_line);
     }
 
+    // Replace the graphemes `start..end` of line `y` with what `edit` makes of them
+    pub fn edit_line<F: FnOnce(&str) -> String>(&mut self, y: usize, start: usize, end: usize, edit: F) {
+        let (text, edited) = match self.lines.get(y) {
+            Some(line) => {
+                let edited = edit(line.slice(start, end));
+                let text = format!("{}{}{}", line.slice(0, start), edited, line.slice(end, line.len()));
+                (text, edited.graphemes(true).count())
+            }
+            None => return,
+        };
+        self.touch(y, y.saturating_add(1));
+        if let Some(line) = self.lines.get_mut(y) {
+            *line = Line::from(text.as_str());
+        }
+        self.marks.text_replaced(
+            &Position { x: start, y },
+            &Position { x: end, y },
+            &Position { x: start.saturating_add(edited), y },
+        );
+    }
+
+    // The text from `start` up to `end`, with `\n` between lines; an `end` past a line's last
+    // character includes its line break
+    pub fn text(&self, start: &Position, end: &Position) -> String {
+        let mut text = String::new();
+        for y in start.y..=end.y.min(self.lines.len().saturating_sub(1)) {
+            if let Some(line) = self.lines.get(y) {
+                let from = if y == start.y { start.x } else { 0 };
+                if y == end.y {
+                    text.push_str(line.slice(from, end.x));
+                    if end.x > line.len() && y.saturating_add(1) < self.lines.len() {
+                        text.push('\n');
+                    }
+                } else {
+                    text.push_str(line.slice(from, line.len()));
+                    text.push('\n');
+                }
+            }
+        }
+        text
+    }
+
+    // Remove the text `text` would return for the same positions
+    #[allow(clippy::indexing_slicing)]
+    pub fn delete_text(&mut self, start: &Position, end: &Position) {
+        let last = self.lines.len().saturating_sub(1);
+        if start.y > last || self.lines.is_empty() {
+            return;
+        }
+        let (end_y, end_x) = if end.y > last {
+            (last, self.lines[last].len())
+        } else if end.x > self.lines[end.y].len() && end.y < last {
+            // The line break goes too
+            (end.y.saturating_add(1), 0)
+        } else {
+            (end.y, end.x)
+        };
+
+        let head = self.lines[start.y].slice(0, start.x).to_string();
+        let tail = self.lines[end_y].slice(end_x, self.lines[end_y].len()).to_string();
+        self.touch(start.y, end_y.saturating_add(1));
+        self.lines.drain(start.y..=end_y.max(start.y));
+        self.lines.insert(start.y, Line::from(format!("{}{}", head, tail).as_str()));
+        self.marks.text_replaced(start, &Position { x: end_x, y: end_y }, start);
+    }
+
+    // Insert `text`, line breaks and all, returning the position just after it
+    #[allow(clippy::indexing_slicing)]
+    pub fn insert_text(&mut self, at: &Position, text: &str) -> Position {
+        if at.y > self.lines.len() {
+            return at.clone();
+        }
+        self.touch(at.y, at.y.saturating_add(1));
+        if at.y == self.lines.len() {
+            self.lines.push(Line::default());
+        }
+        let line = &self.lines[at.y];
+        let head = line.slice(0, at.x).to_string();
+        let tail = line.slice(at.x, line.len()).to_string();
+
+        let mut lines = Vec::new();
+        let mut pieces = text.split('\n');
+        let mut current = format!("{}{}", head, pieces.next().unwrap_or(""));
+        for piece in pieces {
+            lines.push(Line::from(current.as_str()));
+            current = piece.to_string();
+        }
+        let end = Position {
+            x: current.graphemes(true).count(),
+            y: at.y.saturating_add(lines.len()),
+        };
+        current.push_str(&tail);
+        lines.push(Line::from(current.as_str()));
+
+        self.lines.splice(at.y..=at.y, lines);
+        self.marks.text_replaced(at, at, &end);
+        end
+    }
+
+    // The `open` bracket that a `close` typed at `at` would close, looking back from there
+    pub fn find_opener(&self, at: &Position, open: char, close: char) -> Option<Position> {
+        let mut depth: usize = 0;
+        for y in (0..=at.y.min(self.lines.len().saturating_sub(1))).rev() {
+            let line = self.lines.get(y)?;
+            let end = if y == at.y { at.x } else { line.len() };
+            let graphemes: Vec<&str> = line.slice(0, end).graphemes(true).collect();
+            for (x, grapheme) in graphemes.iter().enumerate().rev() {
+                let mut chars = grapheme.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if c == open && depth == 0 => return Some(Position { x, y }),
+                    (Some(c), None) if c == open => depth = depth.saturating_sub(1),
+                    (Some(c), None) if c == close => depth = depth.saturating_add(1),
+                    _ => (),
+                }
+            }
+        }
+        None
+    }
+
+    // The `close` bracket matching the `open` one at `at`, skipping nested pairs
+    pub fn find_closer(&self, at: &Position, open: char, close: char) -> Option<Position> {
+        let mut depth: usize = 0;
+        for y in at.y..self.lines.len() {
+            let line = self.lines.get(y)?;
+            let start = if y == at.y { at.x.saturating_add(1) } else { 0 };
+            for (x, grapheme) in line.as_str().graphemes(true).enumerate().skip(start) {
+                let mut chars = grapheme.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if c == close && depth == 0 => return Some(Position { x, y }),
+                    (Some(c), None) if c == close => depth = depth.saturating_sub(1),
+                    (Some(c), None) if c == open => depth = depth.saturating_add(1),
+                    _ => (),
+                }
+            }
+        }
+        None
+    }
+
+    pub fn delete_lines(&mut self, top: usize, bottom: usize) {
+        let bottom = bottom.min(self.lines.len().saturating_sub(1));
+        if top <= bottom && top < self.lines.len() {
+            self.touch(top, bottom.saturating_add(1));
+            self.lines.drain(top..=bottom);
+            self.marks.lines_replaced(top, bottom.saturating_sub(top).saturating_add(1), 0);
+        }
+    }
+
+    // Put `lines` in place of lines `top` to `bottom`
+    pub fn replace_lines(&mut self, top: usize, bottom: usize, lines: &[String]) {
+        let top = top.min(self.lines.len());
+        let bottom = bottom.saturating_add(1).min(self.lines.len()).max(top);
+        self.touch(top, bottom);
+        self.lines
+            .splice(top..bottom, lines.iter().map(|line| Line::from(line.as_str())));
+        self.marks.lines_replaced(top, bottom.saturating_sub(top), lines.len());
+    }
+
+    pub fn insert_line(&mut self, y: usize, text: &str) {
+        let y = y.min(self.lines.len());
+        self.touch(y, y);
+        self.lines.insert(y, Line::from(text));
+        self.marks.lines_replaced(y, 0, 1);
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.lines.iter().map(|line| line.as_str().to_string())
+    }
+
     pub fn line(&self, index: usize) -> Option<&Line> {
         self.lines.get(index)
     }
@@ -162,7 +634,24 @@ impl Document {
         self.lines.len()
     }
 
+    pub fn display_name(&self) -> String {
+        self.filename.clone().unwrap_or_else(|| "[untitled]".to_string())
+    }
+
     pub fn is_changed(&self) -> bool {
         self.changed
     }
-}
\ No newline at end of file
+
+    // Changes whenever the text does
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+// Index of the first line that differs between two versions of a document
+fn first_difference(a: &[Line], b: &[Line]) -> usize {
+    a.iter()
+        .zip(b.iter())
+        .position(|(a, b)| a.as_str() != b.as_str())
+        .unwrap_or_else(|| a.len().min(b.len()))
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// What the editor knows about a kind of file, picked by its extension.
+pub struct Filetype {
+    pub name: &'static str,
+    extensions: &'static [&'static str],
+    pub indent_after: &'static [char], // A line ending in one of these indents the next one
+    pub brackets: &'static [(char, char)], // Typing the closing one first on a line dedents it
+    line_comment: Option<&'static str>,
+    pub quotes: &'static [char], // Around strings, on a single line
+}
+
+const C_LIKE: &[char] = &['{', '(', '['];
+const BRACKETS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}')];
+
+const FILETYPES: &[Filetype] = &[
+    Filetype {
+        name: "rust",
+        extensions: &["rs"],
+        indent_after: C_LIKE,
+        brackets: BRACKETS,
+        line_comment: Some("//"),
+        quotes: &['"'], // Not `'`, lifetimes would pair up as strings
+    },
+    Filetype {
+        name: "c",
+        extensions: &["c", "h", "cc", "cpp", "cxx", "hpp", "hh"],
+        indent_after: C_LIKE,
+        brackets: BRACKETS,
+        line_comment: Some("//"),
+        quotes: &['"', '\''],
+    },
+    Filetype {
+        name: "javascript",
+        extensions: &["js", "jsx", "ts", "tsx", "mjs", "json"],
+        indent_after: C_LIKE,
+        brackets: BRACKETS,
+        line_comment: Some("//"),
+        quotes: &['"', '\'', '`'],
+    },
+    Filetype {
+        name: "go",
+        extensions: &["go"],
+        indent_after: C_LIKE,
+        brackets: BRACKETS,
+        line_comment: Some("//"),
+        quotes: &['"', '\'', '`'],
+    },
+    Filetype {
+        name: "java",
+        extensions: &["java", "kt", "scala", "cs", "swift"],
+        indent_after: C_LIKE,
+        brackets: BRACKETS,
+        line_comment: Some("//"),
+        quotes: &['"', '\''],
+    },
+    Filetype {
+        name: "css",
+        extensions: &["css", "scss", "less"],
+        indent_after: &['{'],
+        brackets: &[('{', '}')],
+        line_comment: None,
+        quotes: &['"', '\''],
+    },
+    Filetype {
+        name: "python",
+        extensions: &["py", "pyw"],
+        indent_after: &[':', '{', '(', '['],
+        brackets: BRACKETS,
+        line_comment: Some("#"),
+        quotes: &['"', '\''],
+    },
+    Filetype {
+        name: "shell",
+        extensions: &["sh", "bash", "zsh"],
+        indent_after: &['{', '('],
+        brackets: &[('(', ')'), ('{', '}')],
+        line_comment: Some("#"),
+        quotes: &['"', '\''],
+    },
+    Filetype {
+        name: "toml",
+        extensions: &["toml"],
+        indent_after: &['[', '{'],
+        brackets: &[('[', ']'), ('{', '}')],
+        line_comment: Some("#"),
+        quotes: &['"', '\''],
+    },
+];
+
+// The filetype of `filename`, if we know it
+pub fn detect(filename: Option<&str>) -> Option<&'static Filetype> {
+    let extension = Path::new(filename?).extension()?.to_str()?;
+    FILETYPES
+        .iter()
+        .find(|filetype| filetype.extensions.contains(&extension))
+}
+
+impl Filetype {
+    // For each grapheme of `text`, whether it is code rather than part of a string or comment.
+    // A quote with no closing one on the line, like a Rust lifetime, starts nothing.
+    pub fn code(&self, text: &str) -> Vec<bool> {
+        let graphemes: Vec<(usize, &str)> = text.grapheme_indices(true).collect();
+        let mut code = vec![true; graphemes.len()];
+        let mut x: usize = 0;
+        while let Some((byte, grapheme)) = graphemes.get(x) {
+            let rest = text.get(*byte..).unwrap_or("");
+            if self.line_comment.map_or(false, |comment| rest.starts_with(comment)) {
+                code.iter_mut().skip(x).for_each(|code| *code = false);
+                break;
+            }
+            let quote = grapheme.chars().next().filter(|c| self.quotes.contains(c));
+            let close = quote.and_then(|quote| {
+                let mut escaped = false;
+                graphemes.iter().enumerate().skip(x.saturating_add(1)).find_map(|(end, (_, grapheme))| {
+                    let found = !escaped && grapheme.starts_with(quote);
+                    escaped = !escaped && *grapheme == "\\";
+                    if found {
+                        Some(end)
+                    } else {
+                        None
+                    }
+                })
+            });
+            match close {
+                Some(close) => {
+                    code.iter_mut().take(close.saturating_add(1)).skip(x).for_each(|code| *code = false);
+                    x = close.saturating_add(1);
+                }
+                None => x = x.saturating_add(1),
+            }
+        }
+        code
+    }
+}
@@ -0,0 +1,145 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use ignore::WalkBuilder;
+use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::state::quickfix::Entry;
+use crate::state::{Editor, StatusMessage};
+
+// More matches than this and the search stops
+const MATCH_LIMIT: usize = 10_000;
+// Longer lines are cut in the results
+const TEXT_WIDTH: usize = 200;
+
+/// A search through the files under the working directory, running on a thread of its own
+/// and sending the matches of each file as it goes.
+pub struct Grep {
+    pattern: String,
+    results: Receiver<Vec<Entry>>,
+    files: usize, // With matches, so far
+}
+
+// The matches of `regex` in the file at `path`; none for files that are not UTF-8 text
+fn search_file(regex: &Regex, path: &Path) -> Vec<Entry> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) if !text.contains('\0') => text,
+        _ => return Vec::new(),
+    };
+    let mut entries = Vec::new();
+    for (y, line) in text.lines().enumerate() {
+        if let Some(found) = regex.find(line) {
+            let before = line.get(..found.start()).unwrap_or("");
+            entries.push(Entry {
+                path: path.to_path_buf(),
+                line: y,
+                column: before.graphemes(true).count(),
+                kind: None,
+                text: line.trim().graphemes(true).take(TEXT_WIDTH).collect(),
+            });
+        }
+    }
+    entries
+}
+
+// Walk the working directory, leaving out what .gitignore (and .ignore) files and hidden
+// files would, and send on what each file has
+fn search(regex: &Regex, results: &Sender<Vec<Entry>>) {
+    let root = match env::current_dir() {
+        Ok(root) => root,
+        Err(_) => return,
+    };
+    let mut found: usize = 0;
+    let walk = WalkBuilder::new(&root).require_git(false).sort_by_file_name(Ord::cmp).build();
+    for entry in walk.filter_map(Result::ok) {
+        if !entry.file_type().map_or(false, |kind| kind.is_file()) {
+            continue;
+        }
+        let mut entries = search_file(regex, entry.path());
+        if entries.is_empty() {
+            continue;
+        }
+        entries.truncate(MATCH_LIMIT.saturating_sub(found));
+        found = found.saturating_add(entries.len());
+        // Gone when the editor no longer wants the results
+        if results.send(entries).is_err() || found >= MATCH_LIMIT {
+            return;
+        }
+    }
+}
+
+impl Editor {
+    // `grep <regex>`: search the files of the project, putting the matches in the quickfix
+    // list as they are found, and show it once the search is over
+    pub(super) fn grep(&mut self, pattern: Option<&str>) {
+        let pattern = match pattern {
+            Some(pattern) => pattern,
+            None => return self.error("Usage: grep <regex>"),
+        };
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            // The last line says what is wrong, the others show where
+            Err(error) => {
+                let error = error.to_string();
+                return self.error(&format!("Invalid pattern: {}", error.lines().last().unwrap_or("")));
+            }
+        };
+        // Stops one still running, which finds nobody to send to
+        self.set_quickfix(Vec::new());
+        let (sender, results) = mpsc::channel();
+        thread::spawn(move || search(&regex, &sender));
+        self.grep = Some(Grep {
+            pattern: pattern.to_string(),
+            results,
+            files: 0,
+        });
+        self.status_message = StatusMessage::from(format!("Searching for {}...", pattern));
+    }
+
+    // Once per turn of the event loop: add the matches found since, and say how it went once
+    // the search is over
+    pub(super) fn grep_poll(&mut self) {
+        let grep = match self.grep.as_mut() {
+            Some(grep) => grep,
+            None => return,
+        };
+        let mut found = Vec::new();
+        let finished = loop {
+            match grep.results.try_recv() {
+                Ok(entries) => {
+                    grep.files = grep.files.saturating_add(1);
+                    found.extend(entries);
+                }
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+        let (pattern, files) = (grep.pattern.clone(), grep.files);
+        let more = !found.is_empty();
+        if more {
+            self.extend_quickfix(found);
+        }
+        let matches = self.quickfix_len();
+        if !finished {
+            if more {
+                self.status_message = StatusMessage::from(format!("Searching for {}... {} matches", pattern, matches));
+            }
+            return;
+        }
+        self.grep = None;
+        // Only now, so that the window does not change under someone still typing
+        if matches > 0 {
+            self.show_quickfix_list();
+        }
+        match (matches, files) {
+            (0, _) => self.error(&format!("No matches for {}", pattern)),
+            (1, _) => self.status_message = StatusMessage::from("1 match".to_string()),
+            (_, 1) => self.status_message = StatusMessage::from(format!("{} matches in 1 file", matches)),
+            _ => self.status_message = StatusMessage::from(format!("{} matches in {} files", matches, files)),
+        }
+    }
+}
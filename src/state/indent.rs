@@ -0,0 +1,328 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::state::filetype::{self, Filetype};
+use crate::state::{Editor, Position};
+use crate::Line;
+
+impl Editor {
+    pub(super) fn filetype(&self) -> Option<&'static Filetype> {
+        filetype::detect(self.document().filename.as_deref())
+    }
+
+    // Columns in one level of indentation
+    fn shift_width(&self) -> usize {
+        match self.options.shiftwidth {
+            0 => self.options.tab_width.max(1),
+            width => width,
+        }
+    }
+
+    // Columns taken by the indentation of `text`
+    fn indent_width(&self, text: &str) -> usize {
+        leading_whitespace(text).chars().fold(0, |width: usize, c| {
+            width.saturating_add(if c == '\t' { self.options.tab_width } else { 1 })
+        })
+    }
+
+    // Indentation `width` columns wide, with as many tabs as fit unless tabs are expanded
+    #[allow(clippy::integer_arithmetic)]
+    fn make_indent(&self, width: usize) -> String {
+        let tab_width = self.options.tab_width;
+        if self.options.expandtab || tab_width == 0 {
+            " ".repeat(width)
+        } else {
+            format!("{}{}", "\t".repeat(width / tab_width), " ".repeat(width % tab_width))
+        }
+    }
+
+    // Give line `y` indentation `width` columns wide; blank lines are left empty
+    fn set_indent(&mut self, y: usize, width: usize) {
+        let (old, blank) = match self.document().line(y) {
+            Some(line) => (leading_whitespace(line.as_str()).to_string(), line.as_str().trim().is_empty()),
+            None => return,
+        };
+        let indent = if blank { String::new() } else { self.make_indent(width) };
+        if indent != old {
+            let end = old.graphemes(true).count();
+            self.document_mut().edit_line(y, 0, end, |_| indent);
+        }
+    }
+
+    // Indentation for a line opened below line `y`, when the text before the break is `head`
+    pub(super) fn new_line_indent(&self, y: usize, head: &str) -> String {
+        if !self.options.autoindent {
+            return String::new();
+        }
+        let line = self.document().line(y).map_or("", Line::as_str);
+        if self.opens_block(head) {
+            self.make_indent(self.indent_width(line).saturating_add(self.shift_width()))
+        } else {
+            leading_whitespace(line).to_string()
+        }
+    }
+
+    // Does `head` end in something the filetype indents after?
+    fn opens_block(&self, head: &str) -> bool {
+        let last = head.trim_end().chars().last();
+        match (self.filetype(), last) {
+            (Some(filetype), Some(last)) if self.options.smartindent => filetype.indent_after.contains(&last),
+            _ => false,
+        }
+    }
+
+    // Break the line at the cursor, indenting the new one
+    pub(super) fn newline(&mut self) {
+        let Position { x, y } = self.window().cur_pos;
+        let (head, tail) = match self.document().line(y) {
+            Some(line) => (line.slice(0, x).to_string(), line.slice(x, line.len()).to_string()),
+            None => (String::new(), String::new()),
+        };
+        let indent = self.new_line_indent(y, &head);
+
+        // An autoindented line left empty loses its indentation
+        if self.options.autoindent && tail.is_empty() && !head.is_empty() && head.trim().is_empty() {
+            self.document_mut().edit_line(y, 0, x, |_| String::new());
+            self.window_mut().cur_pos.x = 0;
+        }
+        let at = self.window().cur_pos.clone();
+
+        // Between a pair of brackets, the closing one goes on a line of its own
+        let closer = tail.trim_start().chars().next();
+        let paired = self.filetype().map_or(false, |filetype| {
+            let opener = head.trim_end().chars().last();
+            filetype
+                .brackets
+                .iter()
+                .any(|(open, close)| Some(*open) == opener && Some(*close) == closer)
+        });
+        let text = if paired && self.options.smartindent {
+            format!("\n{}\n{}", indent, leading_whitespace(&head))
+        } else {
+            format!("\n{}", indent)
+        };
+        self.document_mut().insert_text(&at, &text);
+        self.window_mut().cur_pos = Position {
+            x: indent.graphemes(true).count(),
+            y: y.saturating_add(1),
+        };
+    }
+
+    // Leaving insert mode on a line holding nothing but indentation empties it
+    pub(super) fn drop_empty_indent(&mut self) {
+        let Position { x, y } = self.window().cur_pos;
+        let blank = self
+            .document()
+            .line(y)
+            .map_or(false, |line| !line.is_empty() && x == line.len() && line.as_str().trim().is_empty());
+        if self.options.autoindent && blank {
+            self.document_mut().edit_line(y, 0, x, |_| String::new());
+            self.window_mut().cur_pos.x = 0;
+        }
+    }
+
+    // A closing bracket typed first on its line lines up with the line holding its opener
+    pub(super) fn dedent_closer(&mut self, c: char) {
+        let Position { x, y } = self.window().cur_pos;
+        let column = x.saturating_sub(1);
+        let bracket = self
+            .filetype()
+            .and_then(|filetype| filetype.brackets.iter().find(|(_, close)| *close == c));
+        let open = match bracket {
+            Some((open, _)) if self.options.smartindent => *open,
+            _ => return,
+        };
+        let before = self.document().line(y).map_or("", |line| line.slice(0, column));
+        if !before.trim().is_empty() {
+            return;
+        }
+
+        let opener = self.document().find_opener(&Position { x: column, y }, open, c);
+        if let Some(opener) = opener {
+            let indent = self
+                .document()
+                .line(opener.y)
+                .map_or("", |line| leading_whitespace(line.as_str()))
+                .to_string();
+            if indent != before {
+                let width = indent.graphemes(true).count();
+                self.document_mut().edit_line(y, 0, column, |_| indent);
+                self.window_mut().cur_pos.x = width.saturating_add(1);
+            }
+        }
+    }
+
+    // `>` and `<`: lines `top` to `bottom` move one level right or left
+    pub(super) fn shift_lines(&mut self, top: usize, bottom: usize, right: bool) {
+        let shift = self.shift_width();
+        for y in top..=bottom.min(self.document().len().saturating_sub(1)) {
+            let width = self.document().line(y).map_or(0, |line| self.indent_width(line.as_str()));
+            let width = if right {
+                width.saturating_add(shift)
+            } else {
+                width.saturating_sub(shift)
+            };
+            self.set_indent(y, width);
+        }
+    }
+
+    // `=`: work out the indentation of lines `top` to `bottom` again from the lines above them,
+    // with the rules smartindent uses
+    pub(super) fn reindent(&mut self, top: usize, bottom: usize) {
+        let filetype = match self.filetype() {
+            Some(filetype) => filetype,
+            None => {
+                self.error("No indentation rules for this file type");
+                return;
+            }
+        };
+        for y in top..=bottom.min(self.document().len().saturating_sub(1)) {
+            let text = self.document().line(y).map_or("", Line::as_str).trim_start().to_string();
+            if text.is_empty() {
+                self.set_indent(y, 0);
+                continue;
+            }
+            let x = self.first_non_blank(y);
+            let closer = text.chars().next().and_then(|c| filetype.brackets.iter().find(|(_, close)| *close == c));
+            let width = match closer.and_then(|(open, close)| self.document().find_opener(&Position { x, y }, *open, *close)) {
+                // A closing bracket lines up with the line holding its opener
+                Some(opener) => self.statement_indent(opener.y),
+                None => match (0..y).rev().find(|y| self.document().line(*y).map_or(false, |line| !line.as_str().trim().is_empty())) {
+                    Some(above) => {
+                        let head = self.document().line(above).map_or("", Line::as_str);
+                        let extra = if filetype.indent_after.iter().any(|c| head.trim_end().ends_with(*c)) {
+                            self.shift_width()
+                        } else {
+                            0
+                        };
+                        self.statement_indent(above).saturating_add(extra)
+                    }
+                    None => 0,
+                },
+            };
+            self.set_indent(y, width);
+        }
+    }
+
+    // Indentation of the line where the statement that line `y` ends began: a line closing
+    // brackets opened further up belongs with the line that opened them
+    fn statement_indent(&self, y: usize) -> usize {
+        let document = self.document();
+        let text = document.line(y).map_or("", Line::as_str);
+        let brackets = self.filetype().map_or(&[][..], |filetype| filetype.brackets);
+        let mut depth: usize = 0;
+        let mut start = y;
+        for (x, grapheme) in text.graphemes(true).enumerate() {
+            if brackets.iter().any(|(open, _)| grapheme.starts_with(*open) && grapheme.len() == open.len_utf8()) {
+                depth = depth.saturating_add(1);
+            } else if let Some((open, close)) = brackets.iter().find(|(_, close)| grapheme.starts_with(*close) && grapheme.len() == close.len_utf8()) {
+                if depth > 0 {
+                    depth = depth.saturating_sub(1);
+                } else if let Some(opener) = document.find_opener(&Position { x, y }, *open, *close) {
+                    start = start.min(opener.y);
+                }
+            }
+        }
+        document.line(start).map_or(0, |line| self.indent_width(line.as_str()))
+    }
+
+    // `gq`: rewrap the paragraphs in lines `top` to `bottom` to fit in `textwidth` columns.
+    // Paragraphs are split by blank lines; their indentation, and a comment leader shared by
+    // all their lines, is kept on every line.
+    pub(super) fn reflow(&mut self, top: usize, bottom: usize) {
+        let bottom = bottom.min(self.document().len().saturating_sub(1));
+        if top > bottom {
+            return;
+        }
+        let lines: Vec<String> = (top..=bottom)
+            .map(|y| self.document().line(y).map_or("", Line::as_str).to_string())
+            .collect();
+
+        let mut result = Vec::new();
+        let mut paragraph: Vec<&str> = Vec::new();
+        let mut prefix = "";
+        for line in &lines {
+            let (leader, rest) = split_leader(line);
+            if rest.trim().is_empty() || leader != prefix {
+                self.wrap_paragraph(prefix, &paragraph, &mut result);
+                paragraph.clear();
+            }
+            if rest.trim().is_empty() {
+                result.push(line.trim_end().to_string());
+            } else {
+                prefix = leader;
+                paragraph.push(rest);
+            }
+        }
+        self.wrap_paragraph(prefix, &paragraph, &mut result);
+
+        if result != lines {
+            self.document_mut().replace_lines(top, bottom, &result);
+        }
+        let y = top.saturating_add(result.len()).saturating_sub(1);
+        self.window_mut().cur_pos = Position {
+            x: self.first_non_blank(y),
+            y,
+        };
+    }
+
+    // Fill lines starting with `prefix` with the words of `paragraph`, greedily
+    fn wrap_paragraph(&self, prefix: &str, paragraph: &[&str], result: &mut Vec<String>) {
+        let prefix_width = self
+            .indent_width(prefix)
+            .saturating_add(prefix.trim_start().graphemes(true).count());
+        let mut line = String::new();
+        let mut width = prefix_width;
+        for word in paragraph.iter().flat_map(|text| text.split_whitespace()) {
+            let length = word.graphemes(true).count();
+            if !line.is_empty() && width.saturating_add(length).saturating_add(1) > self.options.textwidth {
+                result.push(format!("{}{}", prefix, line));
+                line.clear();
+                width = prefix_width;
+            }
+            if !line.is_empty() {
+                line.push(' ');
+                width = width.saturating_add(1);
+            }
+            line.push_str(word);
+            width = width.saturating_add(length);
+        }
+        if !line.is_empty() {
+            result.push(format!("{}{}", prefix, line));
+        }
+    }
+
+    // Spaces up to the next tab stop, when tabs are expanded
+    pub(super) fn tab_text(&self) -> String {
+        if !self.options.expandtab {
+            return "\t".to_string();
+        }
+        let width = self.options.tab_width.max(1);
+        // On screen: an earlier tab or a wide character takes more than one column
+        let Position { x, y } = self.window().cur_pos;
+        let column = self.document().line(y).map_or(x, |line| line.width_to(x, self.options.tab_width));
+        #[allow(clippy::integer_arithmetic)]
+        let count = width - column % width;
+        " ".repeat(count)
+    }
+}
+
+// Comment leaders kept at the start of lines that are reflowed
+const LEADERS: &[&str] = &["///", "//!", "//", "#", "--", ";", ">"];
+
+// Split `text` after its indentation and comment leader, if it has one
+fn split_leader(text: &str) -> (&str, &str) {
+    let indent = leading_whitespace(text);
+    let rest = text.get(indent.len()..).unwrap_or("");
+    let leader = LEADERS.iter().find(|leader| rest.starts_with(*leader)).map_or(0, |leader| leader.len());
+    let after = rest.get(leader..).unwrap_or("");
+    let end = indent
+        .len()
+        .saturating_add(leader)
+        .saturating_add(leading_whitespace(after).len());
+    (text.get(..end).unwrap_or(""), text.get(end..).unwrap_or(""))
+}
+
+pub fn leading_whitespace(text: &str) -> &str {
+    let end = text.len().saturating_sub(text.trim_start().len());
+    text.get(..end).unwrap_or("")
+}
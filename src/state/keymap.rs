@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use termion::event::Key;
+
+use crate::error::Error;
+use crate::state::{keys, Editor, State, StatusMessage};
+use crate::terminal::Event;
+
+// How many mappings may expand into each other before we give up on a recursive mapping
+const MAX_MAP_DEPTH: usize = 1000;
+
+/// Something the editor can do in response to a key.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Nop,
+    Quit,
+    Search,
+    CommandPrompt,
+    Save,
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Delete, // Normal mode: an operator, waiting for a motion or text object
+    DeleteChar,
+    Backspace,
+    Accept, // Prompt only: run the command or search
+    Cancel,
+    HistoryBack, // Prompt only: bring back an older line
+    HistoryForward,
+    CompleteNext, // Insert mode and prompts: complete the word before the cursor
+    CompletePrevious,
+    CompleteLsp, // Insert mode: ask the language server instead
+    Hover, // Normal mode: what the language server knows about the word at the cursor
+    Definition,
+    References,
+    QuickfixNext,
+    QuickfixPrevious,
+    Split,
+    VerticalSplit,
+    NextWindow,
+    PreviousWindow,
+    WindowLeft,
+    WindowDown,
+    WindowUp,
+    WindowRight,
+    CloseWindow,
+    OnlyWindow,
+    QuitWindow,
+    Taller,
+    Shorter,
+    Wider,
+    Narrower,
+    Equalize,
+    WindowToTab,
+    NormalMode,
+    Insert,
+    Append,
+    AppendEnd,
+    InsertStart,
+    OpenBelow,
+    OpenAbove,
+    ReplaceMode,
+    Top,
+    Bottom,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    BigWordForward, // WORDs are only split by white space
+    BigWordBackward,
+    BigWordEnd,
+    SentenceForward,
+    SentenceBackward,
+    ParagraphForward,
+    ParagraphBackward,
+    MatchBracket,
+    SetMark, // Reads the mark name from the next key
+    JumpToMark,
+    JumpToMarkLine,
+    JumpBack,
+    JumpForward,
+    Visual,
+    VisualLine,
+    VisualBlock,
+    SwapAnchor, // Visual only: jump to the other end of the selection
+    InnerObject, // Visual only: select a text object, named by the next key
+    AroundObject,
+    Yank,
+    YankLine,
+    Change,
+    Indent,
+    Outdent,
+    Reindent,
+    Reflow,
+    ToggleCase,
+    Lowercase,
+    Uppercase,
+    DeleteLine,
+    Put,
+    PutBefore,
+    Register, // Reads the register name from the next key
+    Undo,
+    Redo,
+}
+
+const ACTIONS: &[(&str, Action)] = &[
+    ("nop", Action::Nop),
+    ("quit", Action::Quit),
+    ("search", Action::Search),
+    ("command_prompt", Action::CommandPrompt),
+    ("save", Action::Save),
+    ("up", Action::Up),
+    ("down", Action::Down),
+    ("left", Action::Left),
+    ("right", Action::Right),
+    ("page_up", Action::PageUp),
+    ("page_down", Action::PageDown),
+    ("home", Action::Home),
+    ("end", Action::End),
+    ("delete", Action::Delete),
+    ("delete_char", Action::DeleteChar),
+    ("backspace", Action::Backspace),
+    ("accept", Action::Accept),
+    ("cancel", Action::Cancel),
+    ("history_back", Action::HistoryBack),
+    ("history_forward", Action::HistoryForward),
+    ("complete_next", Action::CompleteNext),
+    ("complete_previous", Action::CompletePrevious),
+    ("complete_lsp", Action::CompleteLsp),
+    ("hover", Action::Hover),
+    ("definition", Action::Definition),
+    ("references", Action::References),
+    ("quickfix_next", Action::QuickfixNext),
+    ("quickfix_previous", Action::QuickfixPrevious),
+    ("split", Action::Split),
+    ("vsplit", Action::VerticalSplit),
+    ("window_next", Action::NextWindow),
+    ("window_previous", Action::PreviousWindow),
+    ("window_left", Action::WindowLeft),
+    ("window_down", Action::WindowDown),
+    ("window_up", Action::WindowUp),
+    ("window_right", Action::WindowRight),
+    ("window_close", Action::CloseWindow),
+    ("window_only", Action::OnlyWindow),
+    ("window_quit", Action::QuitWindow),
+    ("window_taller", Action::Taller),
+    ("window_shorter", Action::Shorter),
+    ("window_wider", Action::Wider),
+    ("window_narrower", Action::Narrower),
+    ("window_equalize", Action::Equalize),
+    ("window_to_tab", Action::WindowToTab),
+    ("normal_mode", Action::NormalMode),
+    ("insert", Action::Insert),
+    ("append", Action::Append),
+    ("append_end", Action::AppendEnd),
+    ("insert_start", Action::InsertStart),
+    ("open_below", Action::OpenBelow),
+    ("open_above", Action::OpenAbove),
+    ("replace_mode", Action::ReplaceMode),
+    ("top", Action::Top),
+    ("bottom", Action::Bottom),
+    ("word_forward", Action::WordForward),
+    ("word_backward", Action::WordBackward),
+    ("word_end", Action::WordEnd),
+    ("big_word_forward", Action::BigWordForward),
+    ("big_word_backward", Action::BigWordBackward),
+    ("big_word_end", Action::BigWordEnd),
+    ("sentence_forward", Action::SentenceForward),
+    ("sentence_backward", Action::SentenceBackward),
+    ("paragraph_forward", Action::ParagraphForward),
+    ("paragraph_backward", Action::ParagraphBackward),
+    ("match_bracket", Action::MatchBracket),
+    ("set_mark", Action::SetMark),
+    ("jump_to_mark", Action::JumpToMark),
+    ("jump_to_mark_line", Action::JumpToMarkLine),
+    ("jump_back", Action::JumpBack),
+    ("jump_forward", Action::JumpForward),
+    ("visual", Action::Visual),
+    ("visual_line", Action::VisualLine),
+    ("visual_block", Action::VisualBlock),
+    ("swap_anchor", Action::SwapAnchor),
+    ("inner_object", Action::InnerObject),
+    ("around_object", Action::AroundObject),
+    ("yank", Action::Yank),
+    ("yank_line", Action::YankLine),
+    ("change", Action::Change),
+    ("indent", Action::Indent),
+    ("outdent", Action::Outdent),
+    ("reindent", Action::Reindent),
+    ("reflow", Action::Reflow),
+    ("toggle_case", Action::ToggleCase),
+    ("lowercase", Action::Lowercase),
+    ("uppercase", Action::Uppercase),
+    ("delete_line", Action::DeleteLine),
+    ("put", Action::Put),
+    ("put_before", Action::PutBefore),
+    ("register", Action::Register),
+    ("undo", Action::Undo),
+    ("redo", Action::Redo),
+];
+
+// Shared by insert and normal mode
+const COMMON_DEFAULTS: &[(&str, Action)] = &[
+    ("<C-f>", Action::Search),
+    ("<C-p>", Action::CommandPrompt),
+    ("<C-s>", Action::Save),
+    ("<Up>", Action::Up),
+    ("<Down>", Action::Down),
+    ("<Left>", Action::Left),
+    ("<Right>", Action::Right),
+    ("<PageUp>", Action::PageUp),
+    ("<PageDown>", Action::PageDown),
+    ("<Home>", Action::Home),
+    ("<End>", Action::End),
+    ("<Del>", Action::DeleteChar),
+    ("<C-w>s", Action::Split),
+    ("<C-w>S", Action::Split),
+    ("<C-w><C-s>", Action::Split),
+    ("<C-w>v", Action::VerticalSplit),
+    ("<C-w><C-v>", Action::VerticalSplit),
+    ("<C-w>w", Action::NextWindow),
+    ("<C-w><C-w>", Action::NextWindow),
+    ("<C-w>W", Action::PreviousWindow),
+    ("<C-w>h", Action::WindowLeft),
+    ("<C-w><Left>", Action::WindowLeft),
+    ("<C-w>j", Action::WindowDown),
+    ("<C-w><Down>", Action::WindowDown),
+    ("<C-w>k", Action::WindowUp),
+    ("<C-w><Up>", Action::WindowUp),
+    ("<C-w>l", Action::WindowRight),
+    ("<C-w><Right>", Action::WindowRight),
+    ("<C-w>c", Action::CloseWindow),
+    ("<C-w>o", Action::OnlyWindow),
+    ("<C-w>q", Action::QuitWindow),
+    ("<C-w>+", Action::Taller),
+    ("<C-w>-", Action::Shorter),
+    ("<C-w>>", Action::Wider),
+    ("<C-w><lt>", Action::Narrower),
+    ("<C-w>=", Action::Equalize),
+    ("<C-w>T", Action::WindowToTab),
+];
+
+const INSERT_DEFAULTS: &[(&str, Action)] = &[
+    ("<Esc>", Action::NormalMode),
+    ("<BS>", Action::Backspace),
+    ("<C-n>", Action::CompleteNext),
+    ("<C-x><C-o>", Action::CompleteLsp),
+];
+
+// Moving around, in normal and visual mode
+const MOTION_DEFAULTS: &[(&str, Action)] = &[
+    ("h", Action::Left),
+    ("j", Action::Down),
+    ("k", Action::Up),
+    ("l", Action::Right),
+    ("<BS>", Action::Left),
+    ("<Space>", Action::Right),
+    ("0", Action::Home),
+    ("$", Action::End),
+    ("gg", Action::Top),
+    ("w", Action::WordForward),
+    ("b", Action::WordBackward),
+    ("e", Action::WordEnd),
+    ("W", Action::BigWordForward),
+    ("B", Action::BigWordBackward),
+    ("E", Action::BigWordEnd),
+    (")", Action::SentenceForward),
+    ("(", Action::SentenceBackward),
+    ("}", Action::ParagraphForward),
+    ("{", Action::ParagraphBackward),
+    ("%", Action::MatchBracket),
+    ("`", Action::JumpToMark),
+    ("'", Action::JumpToMarkLine),
+    ("G", Action::Bottom),
+    ("<C-b>", Action::PageUp),
+    ("<C-d>", Action::PageDown),
+];
+
+const NORMAL_DEFAULTS: &[(&str, Action)] = &[
+    ("<Esc>", Action::Quit),
+    ("i", Action::Insert),
+    ("<Insert>", Action::Insert),
+    ("a", Action::Append),
+    ("A", Action::AppendEnd),
+    ("I", Action::InsertStart),
+    ("o", Action::OpenBelow),
+    ("O", Action::OpenAbove),
+    ("R", Action::ReplaceMode),
+    ("v", Action::Visual),
+    ("V", Action::VisualLine),
+    ("<C-v>", Action::VisualBlock),
+    ("x", Action::DeleteChar),
+    ("X", Action::Backspace),
+    ("~", Action::ToggleCase),
+    ("d", Action::Delete),
+    ("y", Action::Yank),
+    ("Y", Action::YankLine),
+    ("c", Action::Change),
+    (">", Action::Indent),
+    ("<lt>", Action::Outdent),
+    ("=", Action::Reindent),
+    ("gq", Action::Reflow),
+    ("gu", Action::Lowercase),
+    ("gU", Action::Uppercase),
+    ("p", Action::Put),
+    ("P", Action::PutBefore),
+    ("\"", Action::Register),
+    ("m", Action::SetMark),
+    ("<C-o>", Action::JumpBack),
+    ("<Tab>", Action::JumpForward),
+    ("u", Action::Undo),
+    ("<C-r>", Action::Redo),
+    ("K", Action::Hover),
+    ("gd", Action::Definition),
+    ("gr", Action::References),
+    ("]q", Action::QuickfixNext),
+    ("[q", Action::QuickfixPrevious),
+    (":", Action::CommandPrompt),
+    (";", Action::CommandPrompt),
+    ("/", Action::Search),
+];
+
+const VISUAL_DEFAULTS: &[(&str, Action)] = &[
+    ("<Esc>", Action::NormalMode),
+    ("v", Action::Visual),
+    ("V", Action::VisualLine),
+    ("<C-v>", Action::VisualBlock),
+    ("o", Action::SwapAnchor),
+    ("i", Action::InnerObject),
+    ("a", Action::AroundObject),
+    ("d", Action::Delete),
+    ("x", Action::Delete),
+    ("<Del>", Action::Delete),
+    ("y", Action::Yank),
+    ("c", Action::Change),
+    ("s", Action::Change),
+    (">", Action::Indent),
+    ("<lt>", Action::Outdent),
+    ("=", Action::Reindent),
+    ("gq", Action::Reflow),
+    ("~", Action::ToggleCase),
+    ("u", Action::Lowercase),
+    ("U", Action::Uppercase),
+    ("p", Action::Put),
+    ("P", Action::Put),
+    ("\"", Action::Register),
+    ("<Up>", Action::Up),
+    ("<Down>", Action::Down),
+    ("<Left>", Action::Left),
+    ("<Right>", Action::Right),
+    ("<PageUp>", Action::PageUp),
+    ("<PageDown>", Action::PageDown),
+    ("<Home>", Action::Home),
+    ("<End>", Action::End),
+];
+
+const PROMPT_DEFAULTS: &[(&str, Action)] = &[
+    ("<CR>", Action::Accept),
+    ("<C-s>", Action::Accept),
+    ("<Esc>", Action::Cancel),
+    ("<BS>", Action::Backspace),
+    ("<C-p>", Action::HistoryBack),
+    ("<C-n>", Action::HistoryForward),
+    ("<Tab>", Action::CompleteNext),
+    ("<S-Tab>", Action::CompletePrevious),
+];
+
+// Letters put in front of `map`, `noremap`, `unmap` and `bind` to pick a mode
+const MODES: &[(char, State)] = &[
+    ('n', State::Normal),
+    ('v', State::Visual),
+    ('i', State::Insert),
+    ('c', State::Prompt),
+];
+
+impl Action {
+    pub fn parse(name: &str) -> Option<Self> {
+        ACTIONS.iter().find(|(action, _)| *action == name).map(|(_, action)| *action)
+    }
+
+    pub fn name(self) -> &'static str {
+        ACTIONS
+            .iter()
+            .find(|(_, action)| *action == self)
+            .map_or("?", |(name, _)| name)
+    }
+}
+
+#[derive(Clone)]
+pub enum Binding {
+    Action(Action),
+    // Typed in place of the mapped keys; non-recursive ones only see the default bindings
+    Keys { keys: Vec<Key>, recursive: bool },
+}
+
+enum Lookup {
+    None,
+    Prefix, // Only the start of longer bindings
+    Exact(Binding),
+    Ambiguous(Binding), // Bound, but also the start of longer bindings
+}
+
+/// What a key sequence does, per mode.
+pub struct Keymap {
+    active: HashMap<State, HashMap<Vec<Key>, Binding>>,
+    defaults: HashMap<State, HashMap<Vec<Key>, Binding>>,
+}
+
+/// A key after mappings were applied.
+pub enum Input {
+    Action(Action),
+    Key(Key), // Not bound to anything, typed as is
+    Paste(String),
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let mut defaults = HashMap::new();
+        let modes: &[(State, &[&[(&str, Action)]])] = &[
+            (State::Normal, &[COMMON_DEFAULTS, MOTION_DEFAULTS, NORMAL_DEFAULTS]),
+            (State::Visual, &[MOTION_DEFAULTS, VISUAL_DEFAULTS]),
+            (State::Insert, &[COMMON_DEFAULTS, INSERT_DEFAULTS]),
+            (State::Prompt, &[PROMPT_DEFAULTS]),
+        ];
+        for (state, tables) in modes {
+            let map: HashMap<Vec<Key>, Binding> = tables
+                .iter()
+                .flat_map(|bindings| bindings.iter())
+                .filter_map(|(keys, action)| Some((keys::parse(keys).ok()?, Binding::Action(*action))))
+                .collect();
+            defaults.insert(*state, map);
+        }
+        Self {
+            active: defaults.clone(),
+            defaults,
+        }
+    }
+}
+
+impl Keymap {
+    pub fn bind(&mut self, state: State, keys: Vec<Key>, binding: Binding) {
+        self.active.entry(state).or_default().insert(keys, binding);
+    }
+
+    pub fn unbind(&mut self, state: State, keys: &[Key]) -> bool {
+        self.active
+            .get_mut(&state)
+            .map_or(false, |bindings| bindings.remove(keys).is_some())
+    }
+
+    // Bindings of `state` as `lhs  rhs` lines, non-recursive mappings marked with `*`
+    pub fn list(&self, state: State) -> Vec<String> {
+        let mut list: Vec<String> = self
+            .active
+            .get(&state)
+            .iter()
+            .flat_map(|bindings| bindings.iter())
+            .map(|(keys, binding)| {
+                let rhs = match binding {
+                    Binding::Action(action) => action.name().to_string(),
+                    Binding::Keys { keys, recursive } => {
+                        format!("{}{}", if *recursive { "" } else { "* " }, keys::name(keys))
+                    }
+                };
+                format!("{:<16}{}", keys::name(keys), rhs)
+            })
+            .collect();
+        list.sort();
+        list
+    }
+
+    fn lookup(&self, state: State, keys: &[Key], remap: bool) -> Lookup {
+        let bindings = match if remap { &self.active } else { &self.defaults }.get(&state) {
+            Some(bindings) => bindings,
+            None => return Lookup::None,
+        };
+        let longer = bindings
+            .keys()
+            .any(|bound| bound.len() > keys.len() && bound.starts_with(keys));
+        match (bindings.get(keys).cloned(), longer) {
+            (Some(binding), true) => Lookup::Ambiguous(binding),
+            (Some(binding), false) => Lookup::Exact(binding),
+            (None, true) => Lookup::Prefix,
+            (None, false) => Lookup::None,
+        }
+    }
+}
+
+// `imap` is (Insert, "map"); a bare `map` applies to every mode but the prompt
+const MAPPING_COMMANDS: &[&str] = &["map", "noremap", "unmap", "bind"];
+
+pub fn mapping_command(name: &str) -> Option<(Vec<State>, &str)> {
+    let is_mapping = |command: &str| MAPPING_COMMANDS.contains(&command);
+    if is_mapping(name) {
+        return Some((vec![State::Normal, State::Visual, State::Insert], name));
+    }
+    let mut chars = name.chars();
+    let letter = chars.next()?;
+    let command = chars.as_str();
+    let (_, state) = MODES.iter().find(|(mode, _)| *mode == letter)?;
+    if is_mapping(command) {
+        Some((vec![*state], command))
+    } else {
+        None
+    }
+}
+
+// `map`, `nmap`, `cbind`... for Tab completion
+pub fn mapping_commands() -> Vec<String> {
+    let letters = std::iter::once(String::new()).chain(MODES.iter().map(|(letter, _)| letter.to_string()));
+    letters
+        .flat_map(|letter| MAPPING_COMMANDS.iter().map(move |command| format!("{}{}", letter, command)))
+        .collect()
+}
+
+fn mode_letter(state: State) -> char {
+    MODES
+        .iter()
+        .find(|(_, mode)| *mode == state)
+        .map_or(' ', |(letter, _)| *letter)
+}
+
+impl Editor {
+    // The next thing to do in `state`, with mappings applied; `None` if nothing was typed within `idle`
+    pub(super) fn next_input(&mut self, state: State, idle: Duration) -> Result<Option<Input>, Error> {
+        // Replace mode types like insert mode, and uses its mappings
+        let state = if state == State::Replace { State::Insert } else { state };
+        let mut expansions: usize = 0;
+        loop {
+            let mut sequence: Vec<(Key, bool)> = Vec::new();
+            let mut matched: Option<(usize, Binding)> = None;
+            loop {
+                let next = if let Some(next) = self.typeahead.pop_front() {
+                    Some(next)
+                } else if sequence.is_empty() {
+                    match self.terminal.read_event_timeout(idle)? {
+                        Some(Event::Key(key)) => Some((key, true)),
+                        Some(Event::Paste(text)) => return Ok(Some(Input::Paste(text))),
+                        None => None,
+                    }
+                } else {
+                    self.pending = sequence.iter().map(|(key, _)| *key).collect();
+                    self.refresh_screen()?;
+                    // An unfinished sequence is taken as it is if nothing follows quickly
+                    self.terminal
+                        .read_key_timeout(self.options.map_timeout)?
+                        .map(|key| (key, true))
+                };
+                let next = match next {
+                    Some(next) => next,
+                    None if sequence.is_empty() => return Ok(None),
+                    None => break,
+                };
+                sequence.push(next);
+
+                let keys: Vec<Key> = sequence.iter().map(|(key, _)| *key).collect();
+                let remap = sequence.first().map_or(true, |(_, remap)| *remap);
+                match self.keymap.lookup(state, &keys, remap) {
+                    Lookup::Prefix => (),
+                    Lookup::Ambiguous(binding) => matched = Some((keys.len(), binding)),
+                    Lookup::Exact(binding) => {
+                        matched = Some((keys.len(), binding));
+                        break;
+                    }
+                    Lookup::None => break,
+                }
+            }
+            self.pending.clear();
+
+            // Keys past the longest match are looked at again
+            let used = matched.as_ref().map_or(1, |(length, _)| *length);
+            for next in sequence.drain(used..).rev() {
+                self.typeahead.push_front(next);
+            }
+            let binding = match matched {
+                Some((_, binding)) => binding,
+                None => return Ok(sequence.first().map(|(key, _)| Input::Key(*key))),
+            };
+            match binding {
+                Binding::Action(action) => return Ok(Some(Input::Action(action))),
+                Binding::Keys { keys, recursive } => {
+                    expansions = expansions.saturating_add(1);
+                    if expansions > MAX_MAP_DEPTH {
+                        self.typeahead.clear();
+                        self.status_message = StatusMessage::error("Recursive mapping".to_string());
+                        return Ok(None);
+                    }
+                    for key in keys.into_iter().rev() {
+                        self.typeahead.push_front((key, recursive));
+                    }
+                }
+            }
+        }
+    }
+
+    // A single key typed as is, for commands that take one as an argument like `"a`
+    pub(super) fn next_key(&mut self) -> Result<Key, Error> {
+        match self.typeahead.pop_front() {
+            Some((key, _)) => Ok(key),
+            None => self.terminal.read_key(),
+        }
+    }
+
+    // `map <lhs> <rhs>`, `noremap <lhs> <rhs>`, `unmap <lhs>`, `bind <lhs> <action>`, in `states`
+    pub(super) fn configure_mapping(&mut self, states: &[State], command: &str, argument: &str) -> Result<(), String> {
+        let mut parts = argument.split_whitespace();
+        let lhs = parts.next();
+        let rhs = parts.next();
+        if parts.next().is_some() {
+            return Err(format!("Usage: {} <keys> <keys>, keys with spaces are written <Space>", command));
+        }
+
+        let lhs = match lhs {
+            Some(lhs) => keys::parse(lhs)?,
+            None if command == "unmap" => return Err("Usage: unmap <keys>".to_string()),
+            None => {
+                self.list_bindings(states);
+                return Ok(());
+            }
+        };
+        let binding = match (command, rhs) {
+            ("unmap", None) => {
+                let mut found = false;
+                for state in states {
+                    found |= self.keymap.unbind(*state, &lhs);
+                }
+                return if found {
+                    Ok(())
+                } else {
+                    Err(format!("No mapping for {}", keys::name(&lhs)))
+                };
+            }
+            ("bind", Some(action)) => {
+                Binding::Action(Action::parse(action).ok_or_else(|| format!("Unknown action: {}", action))?)
+            }
+            ("map", Some(rhs)) | ("noremap", Some(rhs)) => Binding::Keys {
+                keys: keys::parse(rhs)?,
+                recursive: command == "map",
+            },
+            ("bind", None) => return Err("Usage: bind <keys> <action>".to_string()),
+            _ => return Err(format!("Usage: {} <keys> <keys>", command)),
+        };
+        for state in states {
+            self.keymap.bind(*state, lhs.clone(), binding.clone());
+        }
+        Ok(())
+    }
+
+    // Show the bindings of `states` in a scratch buffer
+    fn list_bindings(&mut self, states: &[State]) {
+        let lines: Vec<String> = states
+            .iter()
+            .flat_map(|state| {
+                let letter = mode_letter(*state);
+                self.keymap
+                    .list(*state)
+                    .into_iter()
+                    .map(move |line| format!("{}  {}", letter, line))
+            })
+            .collect();
+        self.show_scratch(&lines);
+    }
+}
@@ -0,0 +1,107 @@
+use termion::event::Key;
+
+// Vim style key notation: `a`, `<C-x>`, `<A-x>`, `<Esc>`, `<CR>`, `<F5>`...
+const NAMED: &[(&str, Key)] = &[
+    ("esc", Key::Esc),
+    ("cr", Key::Char('\n')),
+    ("enter", Key::Char('\n')),
+    ("return", Key::Char('\n')),
+    ("tab", Key::Char('\t')),
+    ("space", Key::Char(' ')),
+    ("bs", Key::Backspace),
+    ("backspace", Key::Backspace),
+    ("del", Key::Delete),
+    ("delete", Key::Delete),
+    ("insert", Key::Insert),
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("pageup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+    ("s-tab", Key::BackTab),
+    ("lt", Key::Char('<')),
+    ("bar", Key::Char('|')),
+];
+
+// Parse a whole key sequence such as `<C-w>v` or `jk`
+pub fn parse(text: &str) -> Result<Vec<Key>, String> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some(end) = rest.find('>') {
+                if let Some(key) = parse_key(&rest[1..end]) {
+                    keys.push(key);
+                    rest = &rest[end.saturating_add(1)..];
+                    continue;
+                }
+                if end > 1 {
+                    return Err(format!("unknown key <{}>", &rest[1..end]));
+                }
+            }
+        }
+        keys.push(Key::Char(c));
+        rest = &rest[c.len_utf8()..];
+    }
+    if keys.is_empty() {
+        return Err("empty key sequence".to_string());
+    }
+    Ok(keys)
+}
+
+// The name of a key between `<` and `>`
+fn parse_key(name: &str) -> Option<Key> {
+    let lower = name.to_lowercase();
+    if let Some((_, key)) = NAMED.iter().find(|(named, _)| *named == lower) {
+        return Some(*key);
+    }
+    if let Some(number) = lower.strip_prefix('f').and_then(|number| number.parse::<u8>().ok()) {
+        return Some(Key::F(number));
+    }
+
+    let mut chars = name.chars();
+    let (modifier, separator, c) = (chars.next()?, chars.next()?, chars.next()?);
+    if separator != '-' || chars.next().is_some() {
+        return None;
+    }
+    match modifier.to_ascii_lowercase() {
+        'c' => Some(Key::Ctrl(c.to_ascii_lowercase())),
+        'a' | 'm' => Some(Key::Alt(c)),
+        _ => None,
+    }
+}
+
+// Inverse of `parse`, for showing bindings back to the user
+pub fn name(keys: &[Key]) -> String {
+    keys.iter().map(|key| key_name(*key)).collect()
+}
+
+fn key_name(key: Key) -> String {
+    match key {
+        Key::Char('<') => "<lt>".to_string(),
+        Key::Char(c) => {
+            if let Some((named, _)) = NAMED.iter().find(|(_, named)| *named == Key::Char(c)) {
+                format!("<{}>", capitalize(named))
+            } else {
+                c.to_string()
+            }
+        }
+        Key::Ctrl(c) => format!("<C-{}>", c),
+        Key::Alt(c) => format!("<A-{}>", c),
+        Key::F(number) => format!("<F{}>", number),
+        key => NAMED
+            .iter()
+            .find(|(_, named)| *named == key)
+            .map_or_else(|| "<?>".to_string(), |(named, _)| format!("<{}>", capitalize(named))),
+    }
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map_or_else(String::new, |first| first.to_uppercase().chain(chars).collect())
+}
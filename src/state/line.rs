@@ -1,8 +1,9 @@
 use std::cmp;
 use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 use crate::state::SearchDirection;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Line {
     string: String,
     len: usize,
@@ -18,7 +19,7 @@ impl From<&str> for Line {
 }
 
 impl Line {
-    pub fn render(&self, start: usize, end: usize) -> String {
+    pub fn render(&self, start: usize, end: usize, tab_width: usize) -> String {
         let end = cmp::min(end, self.string.len());
         let start = cmp::min(start, end);
 
@@ -31,7 +32,7 @@ impl Line {
             .take(end - start)
         {
             if grapheme == "\t" {
-                result.push_str("    ");
+                result.push_str(&" ".repeat(tab_width));
             } else {
                 result.push_str(grapheme);
             }
@@ -39,6 +40,42 @@ impl Line {
         result
     }
 
+    // Screen columns the graphemes before `x` take, drawn the way `render` draws them
+    pub fn width_to(&self, x: usize, tab_width: usize) -> usize {
+        self.render(0, x, tab_width).width()
+    }
+
+    // The graphemes drawn on screen columns `left..right`, end exclusive; one that is only partly
+    // in there counts
+    pub fn graphemes_between(&self, left: usize, right: usize, tab_width: usize) -> (usize, usize) {
+        let mut column: usize = 0;
+        let mut from = None;
+        for (x, grapheme) in self.string.graphemes(true).enumerate() {
+            if column >= right {
+                return (from.unwrap_or(x), x);
+            }
+            let width = if grapheme == "\t" { tab_width } else { grapheme.width() };
+            column = column.saturating_add(width);
+            if from.is_none() && column > left {
+                from = Some(x);
+            }
+        }
+        (from.unwrap_or(self.len), self.len)
+    }
+
+    // The first grapheme starting at screen column `column` or after it
+    pub fn index_at(&self, column: usize, tab_width: usize) -> usize {
+        let mut start: usize = 0;
+        for (x, grapheme) in self.string.graphemes(true).enumerate() {
+            if start >= column {
+                return x;
+            }
+            let width = if grapheme == "\t" { tab_width } else { grapheme.width() };
+            start = start.saturating_add(width);
+        }
+        self.len
+    }
+
     pub fn insert(&mut self, at: usize, c: char) {
         if at >= self.len() {
             self.string.push(c);
@@ -148,6 +185,25 @@ impl Line {
         None
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+
+    // The graphemes from `start` up to (not including) `end`
+    #[allow(clippy::indexing_slicing)]
+    pub fn slice(&self, start: usize, end: usize) -> &str {
+        let from = self.byte_index(start);
+        let to = self.byte_index(end.max(start));
+        &self.string[from..to]
+    }
+
+    fn byte_index(&self, at: usize) -> usize {
+        self.string
+            .grapheme_indices(true)
+            .nth(at)
+            .map_or(self.string.len(), |(index, _)| index)
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         self.string.as_bytes()
     }
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::transport::{read_message, write_message};
+use super::{Pending, Synced};
+
+// How long a server gets to answer `shutdown` before it is killed
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What came from a server, with responses matched to the request they answer.
+pub enum Message {
+    Response { pending: Pending, result: Result<Value, String> },
+    Notification { method: String, params: Value },
+    Request { id: Value, method: String, params: Value }, // Needs an answer
+}
+
+/// A language server for one filetype, talked to over any reader and writer; `spawn` gives it
+/// the pipes of a server process.
+pub struct Client {
+    pub filetype: &'static str,
+    pub command: String,
+    writer: Box<dyn Write + Send>,
+    incoming: Receiver<Value>, // Filled by a thread reading from the server
+    child: Option<Child>,
+    next_id: u64,
+    pending: HashMap<u64, Pending>,
+    pub ready: bool, // Answered `initialize`, documents can be opened
+    pub capabilities: Value,
+    pub documents: HashMap<String, Synced>, // By URI, the text the server has of each
+    pub running: bool, // False once the server is gone
+}
+
+impl Client {
+    pub fn new<R, W>(filetype: &'static str, command: &str, reader: R, writer: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            filetype,
+            command: command.to_string(),
+            writer: Box::new(writer),
+            incoming,
+            child: None,
+            next_id: 1,
+            pending: HashMap::new(),
+            ready: false,
+            capabilities: Value::Null,
+            documents: HashMap::new(),
+            running: true,
+        }
+    }
+
+    // Start `command` (a program and its arguments, split on white space) and talk to it over
+    // its standard input and output
+    pub fn spawn(filetype: &'static str, command: &str) -> io::Result<Self> {
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no server command"))?;
+        let mut child = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let (stdout, stdin) = match (child.stdout.take(), child.stdin.take()) {
+            (Some(stdout), Some(stdin)) => (stdout, stdin),
+            _ => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "no pipes to the server")),
+        };
+        let mut client = Self::new(filetype, command, stdout, stdin);
+        client.child = Some(child);
+        Ok(client)
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        self.send(&message)
+    }
+
+    // Ask something; the answer comes back from `receive` along with `pending`
+    pub fn request(&mut self, method: &str, params: Value, pending: Pending) -> io::Result<()> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        self.send(&message)?;
+        self.pending.insert(id, pending);
+        Ok(())
+    }
+
+    pub fn respond(&mut self, id: Value, result: Result<Value, (i64, &str)>) -> io::Result<()> {
+        let message = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => {
+                json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+            }
+        };
+        self.send(&message)
+    }
+
+    fn send(&mut self, message: &Value) -> io::Result<()> {
+        let sent = write_message(&mut self.writer, message);
+        if sent.is_err() {
+            self.running = false;
+        }
+        sent
+    }
+
+    // Everything the server sent since the last call
+    pub fn receive(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        loop {
+            match self.incoming.try_recv() {
+                Ok(message) => messages.extend(self.sort(message)),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.running = false;
+                    break;
+                }
+            }
+        }
+        messages
+    }
+
+    fn sort(&mut self, mut message: Value) -> Option<Message> {
+        let method = message.get("method").and_then(Value::as_str).map(String::from);
+        let params = message.get_mut("params").map_or(Value::Null, Value::take);
+        match (method, message.get_mut("id").map(Value::take)) {
+            (Some(method), Some(id)) => Some(Message::Request { id, method, params }),
+            (Some(method), None) => Some(Message::Notification { method, params }),
+            (None, Some(id)) => {
+                let pending = self.pending.remove(&id.as_u64()?)?;
+                let result = match message.get("error") {
+                    Some(error) => Err(error
+                        .get("message")
+                        .and_then(Value::as_str)
+                        .unwrap_or("request failed")
+                        .to_string()),
+                    None => Ok(message.get_mut("result").map_or(Value::Null, Value::take)),
+                };
+                Some(Message::Response { pending, result })
+            }
+            (None, None) => None,
+        }
+    }
+
+    // Ask the server to go away, and make sure it does
+    pub fn shutdown(&mut self) {
+        let started = Instant::now();
+        if self.running && self.request("shutdown", Value::Null, Pending::Shutdown).is_ok() {
+            loop {
+                let left = SHUTDOWN_TIMEOUT.saturating_sub(started.elapsed());
+                match self.incoming.recv_timeout(left) {
+                    Ok(message) => {
+                        if let Some(Message::Response { pending: Pending::Shutdown, .. }) = self.sort(message) {
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            let _ = self.notify("exit", Value::Null);
+        }
+        self.running = false;
+        if let Some(child) = self.child.as_mut() {
+            // What is left of the timeout to exit on its own
+            while matches!(child.try_wait(), Ok(None)) && started.elapsed() < SHUTDOWN_TIMEOUT {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if !matches!(child.try_wait(), Ok(Some(_))) {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufReader, Read, Write};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use serde_json::{json, Value};
+
+    use super::super::transport::{read_message, write_message};
+    use super::super::{diagnostic, full_text, line_change, Pending, Severity};
+    use super::{Client, Message, SHUTDOWN_TIMEOUT};
+
+    // One way of an in-memory pipe: what goes into the writer comes out of the reader, which
+    // sees the end once the writer is dropped
+    struct PipeWriter(Sender<Vec<u8>>);
+
+    struct PipeReader {
+        incoming: Receiver<Vec<u8>>,
+        buffer: Vec<u8>,
+        at: usize,
+    }
+
+    fn pipe() -> (PipeWriter, PipeReader) {
+        let (sender, incoming) = mpsc::channel();
+        let reader = PipeReader {
+            incoming,
+            buffer: Vec::new(),
+            at: 0,
+        };
+        (PipeWriter(sender), reader)
+    }
+
+    impl Write for PipeWriter {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(data.to_vec())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "reader gone"))?;
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for PipeReader {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            while self.at >= self.buffer.len() {
+                match self.incoming.recv() {
+                    Ok(data) => {
+                        self.buffer = data;
+                        self.at = 0;
+                    }
+                    Err(_) => return Ok(0),
+                }
+            }
+            let rest = &self.buffer[self.at..];
+            let count = rest.len().min(out.len());
+            out[..count].copy_from_slice(&rest[..count]);
+            self.at += count;
+            Ok(count)
+        }
+    }
+
+    // The other end of the client, played by the test
+    struct Server {
+        reader: BufReader<PipeReader>,
+        writer: PipeWriter,
+    }
+
+    impl Server {
+        fn read(&mut self) -> Value {
+            read_message(&mut self.reader).unwrap().unwrap()
+        }
+
+        fn send(&mut self, message: Value) {
+            write_message(&mut self.writer, &message).unwrap();
+        }
+    }
+
+    fn connect() -> (Client, Server) {
+        let (client_writer, server_reader) = pipe();
+        let (server_writer, client_reader) = pipe();
+        let client = Client::new("rust", "mock", client_reader, client_writer);
+        let server = Server {
+            reader: BufReader::new(server_reader),
+            writer: server_writer,
+        };
+        (client, server)
+    }
+
+    // What the reader thread passed on, waiting for `count` messages
+    fn receive(client: &mut Client, count: usize) -> Vec<Message> {
+        let started = Instant::now();
+        let mut messages = Vec::new();
+        while messages.len() < count && started.elapsed() < Duration::from_secs(5) {
+            messages.extend(client.receive());
+            thread::sleep(Duration::from_millis(5));
+        }
+        messages
+    }
+
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|line| String::from(*line)).collect()
+    }
+
+    #[test]
+    fn initialize_open_and_change() {
+        let (mut client, mut server) = connect();
+        client.request("initialize", json!({ "rootUri": "file:///w" }), Pending::Initialize).unwrap();
+        let request = server.read();
+        assert_eq!(request["jsonrpc"], "2.0");
+        assert_eq!(request["method"], "initialize");
+        assert_eq!(request["params"]["rootUri"], "file:///w");
+
+        let capabilities = json!({ "textDocumentSync": 2 });
+        server.send(json!({ "jsonrpc": "2.0", "id": request["id"], "result": { "capabilities": capabilities } }));
+        match receive(&mut client, 1).as_slice() {
+            [Message::Response { pending: Pending::Initialize, result: Ok(result) }] => {
+                assert_eq!(result["capabilities"], capabilities);
+            }
+            _ => panic!("no answer to initialize"),
+        }
+
+        let old = lines(&["fn main() {", "}"]);
+        let open = json!({ "textDocument": { "uri": "file:///w/main.rs", "version": 0, "text": full_text(&old) } });
+        client.notify("textDocument/didOpen", open).unwrap();
+        let opened = server.read();
+        assert_eq!(opened["method"], "textDocument/didOpen");
+        assert!(opened.get("id").is_none());
+        assert_eq!(opened["params"]["textDocument"]["text"], "fn main() {\n}\n");
+
+        let new = lines(&["fn main() {", "    let x = 1;", "}"]);
+        let change = json!({
+            "textDocument": { "uri": "file:///w/main.rs", "version": 1 },
+            "contentChanges": [line_change(&old, &new)],
+        });
+        client.notify("textDocument/didChange", change).unwrap();
+        let changed = server.read();
+        assert_eq!(changed["method"], "textDocument/didChange");
+        assert_eq!(
+            changed["params"]["contentChanges"][0],
+            json!({
+                "range": { "start": { "line": 1, "character": 0 }, "end": { "line": 1, "character": 0 } },
+                "text": "    let x = 1;\n",
+            })
+        );
+    }
+
+    #[test]
+    fn line_change_replaces_the_middle() {
+        let old = lines(&["a", "b", "c", "d"]);
+        let new = lines(&["a", "x", "d"]);
+        assert_eq!(
+            line_change(&old, &new),
+            json!({
+                "range": { "start": { "line": 1, "character": 0 }, "end": { "line": 3, "character": 0 } },
+                "text": "x\n",
+            })
+        );
+        assert_eq!(line_change(&new, &new)["text"], "");
+    }
+
+    #[test]
+    fn publish_diagnostics() {
+        let (mut client, mut server) = connect();
+        server.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {
+                "uri": "file:///w/main.rs",
+                "diagnostics": [{
+                    "range": { "start": { "line": 2, "character": 4 }, "end": { "line": 2, "character": 8 } },
+                    "severity": 1,
+                    "message": "cannot find value `y`",
+                }],
+            },
+        }));
+        let messages = receive(&mut client, 1);
+        let params = match messages.as_slice() {
+            [Message::Notification { method, params }] if method == "textDocument/publishDiagnostics" => params,
+            _ => panic!("no diagnostics"),
+        };
+        assert_eq!(params["uri"], "file:///w/main.rs");
+        let found = diagnostic(&params["diagnostics"][0]).unwrap();
+        assert_eq!((found.line, found.character), (2, 4));
+        assert!(found.severity == Severity::Error);
+        assert_eq!(found.message, "cannot find value `y`");
+    }
+
+    #[test]
+    fn responses_find_their_requests() {
+        let (mut client, mut server) = connect();
+        client.request("textDocument/hover", Value::Null, Pending::Hover).unwrap();
+        client.request("textDocument/definition", Value::Null, Pending::Definition).unwrap();
+        let (hover, definition) = (server.read(), server.read());
+        assert_ne!(hover["id"], definition["id"]);
+
+        // Out of order, with one for a request never made and one asking something of us
+        server.send(json!({ "jsonrpc": "2.0", "id": definition["id"], "result": [] }));
+        server.send(json!({ "jsonrpc": "2.0", "id": 99, "result": null }));
+        server.send(json!({ "jsonrpc": "2.0", "id": hover["id"], "error": { "code": -32601, "message": "no hover" } }));
+        server.send(json!({ "jsonrpc": "2.0", "id": "config", "method": "workspace/configuration", "params": {} }));
+
+        let messages = receive(&mut client, 3);
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(&messages[0], Message::Response { pending: Pending::Definition, result: Ok(Value::Array(_)) }));
+        match &messages[1] {
+            Message::Response { pending: Pending::Hover, result: Err(error) } => assert_eq!(error, "no hover"),
+            _ => panic!("hover error not passed on"),
+        }
+        match &messages[2] {
+            Message::Request { id, method, .. } => {
+                assert_eq!(id, "config");
+                assert_eq!(method, "workspace/configuration");
+            }
+            _ => panic!("server request not passed on"),
+        }
+
+        // A second answer to the same request is dropped
+        server.send(json!({ "jsonrpc": "2.0", "id": hover["id"], "result": null }));
+        thread::sleep(Duration::from_millis(50));
+        assert!(client.receive().is_empty());
+    }
+
+    #[test]
+    fn server_going_away() {
+        let (mut client, server) = connect();
+        drop(server);
+        let started = Instant::now();
+        while client.running && started.elapsed() < Duration::from_secs(5) {
+            client.receive();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!client.running);
+    }
+
+    #[test]
+    fn shutdown_answered() {
+        let (mut client, mut server) = connect();
+        let answering = thread::spawn(move || {
+            let request = server.read();
+            assert_eq!(request["method"], "shutdown");
+            server.send(json!({ "jsonrpc": "2.0", "id": request["id"], "result": null }));
+            assert_eq!(server.read()["method"], "exit");
+        });
+        let started = Instant::now();
+        client.shutdown();
+        assert!(started.elapsed() < SHUTDOWN_TIMEOUT);
+        assert!(!client.running);
+        answering.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_times_out() {
+        let (mut client, mut server) = connect();
+        let started = Instant::now();
+        client.shutdown();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= SHUTDOWN_TIMEOUT);
+        assert!(elapsed < SHUTDOWN_TIMEOUT * 4);
+        assert!(!client.running);
+        // Told to exit all the same
+        assert_eq!(server.read()["method"], "shutdown");
+        assert_eq!(server.read()["method"], "exit");
+    }
+}
@@ -0,0 +1,870 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::state::filetype;
+use crate::state::quickfix::Entry;
+use crate::state::session::{absolute, relative};
+use crate::state::{Editor, Position, State, StatusMessage};
+use crate::terminal::{Screen, Style};
+use crate::{Document, Line};
+
+mod client;
+mod transport;
+
+use client::{Client, Message};
+
+// Started for each filetype while the `lsp` option is on, unless `lsp <filetype> <command>`
+// names another one
+const SERVERS: &[(&str, &str)] = &[
+    ("rust", "rust-analyzer"),
+    ("c", "clangd"),
+    ("python", "pylsp"),
+    ("go", "gopls"),
+    ("javascript", "typescript-language-server --stdio"),
+];
+// Lines of hover text shown at most
+const HOVER_HEIGHT: usize = 15;
+
+/// What a request was for, to know what to do with the answer.
+pub enum Pending {
+    Initialize,
+    Shutdown,
+    Hover,
+    Definition,
+    References,
+    Rename,
+    Completion { uri: String, start: Position }, // Of the document asked about, buffer numbers change
+}
+
+/// A document as the server last heard of it.
+pub struct Synced {
+    version: i64,
+    revision: u64, // Of the `Document` at the time
+    lines: Vec<String>,
+}
+
+// Worst first
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+struct Diagnostic {
+    line: usize,
+    character: usize, // In UTF-16 code units, as the server counts
+    severity: Severity,
+    message: String,
+}
+
+/// The language servers, and what they told us.
+#[derive(Default)]
+pub struct Lsp {
+    clients: Vec<Client>,
+    servers: BTreeMap<String, String>, // Filetype to server command, set with `lsp`
+    failed: HashSet<&'static str>, // Filetypes whose server would not start or died, not tried again
+    diagnostics: HashMap<String, Vec<Diagnostic>>, // By URI
+    hover: Vec<String>, // Shown over the text until the next key
+}
+
+impl Lsp {
+    pub fn is_running(&self) -> bool {
+        !self.clients.is_empty()
+    }
+}
+
+// `file://` URIs, with anything but unreserved characters and slashes percent-encoded
+fn uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            uri.push(char::from(byte));
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+fn uri_path(uri: &str) -> Option<PathBuf> {
+    let mut rest = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::new();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .filter(|_| byte == b'%')
+            .and_then(|hex| u8::from_str_radix(&String::from_utf8_lossy(hex), 16).ok());
+        match escaped {
+            Some(value) => {
+                bytes.push(value);
+                rest = tail.get(2..).unwrap_or(&[]);
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+fn document_uri(document: &Document) -> Option<String> {
+    document.filename.as_deref().map(|filename| uri(&absolute(filename)))
+}
+
+// Servers count columns in UTF-16 code units, the editor in graphemes
+fn utf16_column(text: &str, x: usize) -> usize {
+    text.graphemes(true).take(x).map(|grapheme| grapheme.encode_utf16().count()).sum()
+}
+
+fn grapheme_column(text: &str, units: usize) -> usize {
+    let mut counted: usize = 0;
+    for (x, grapheme) in text.graphemes(true).enumerate() {
+        if counted >= units {
+            return x;
+        }
+        counted = counted.saturating_add(grapheme.encode_utf16().count());
+    }
+    text.graphemes(true).count()
+}
+
+fn number(value: &Value, key: &str) -> usize {
+    value.get(key).and_then(Value::as_u64).and_then(|number| usize::try_from(number).ok()).unwrap_or(0)
+}
+
+// The one change that turns `old` into `new`: the lines between what they start and end with.
+// Documents go to servers with a line break after every line, so a range can always end at
+// the start of the line after the last.
+fn line_change(old: &[String], new: &[String]) -> Value {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let old_rest = old.get(prefix..).unwrap_or(&[]);
+    let new_rest = new.get(prefix..).unwrap_or(&[]);
+    let suffix = old_rest.iter().rev().zip(new_rest.iter().rev()).take_while(|(a, b)| a == b).count();
+    let text = full_text(new_rest.get(..new_rest.len().saturating_sub(suffix)).unwrap_or(&[]));
+    json!({
+        "range": {
+            "start": { "line": prefix, "character": 0 },
+            "end": { "line": old.len().saturating_sub(suffix), "character": 0 },
+        },
+        "text": text,
+    })
+}
+
+fn full_text(lines: &[String]) -> String {
+    let mut text = String::new();
+    for line in lines {
+        text.push_str(line);
+        text.push('\n');
+    }
+    text
+}
+
+// Hover contents come as a string, markup, or a list of either; code fences are left out
+fn hover_lines(contents: &Value) -> Vec<String> {
+    let text = match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items.iter().flat_map(hover_lines).collect::<Vec<String>>().join("\n"),
+        Value::Object(_) => contents.get("value").and_then(Value::as_str).unwrap_or("").to_string(),
+        _ => String::new(),
+    };
+    let lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .map(|line| line.trim_end().to_string())
+        .collect();
+    let first = lines.iter().position(|line| !line.is_empty()).unwrap_or(lines.len());
+    let last = lines.iter().rposition(|line| !line.is_empty()).map_or(0, |last| last.saturating_add(1));
+    lines.get(first..last.max(first)).unwrap_or(&[]).to_vec()
+}
+
+// Where a definition or references answer points: files, lines and UTF-16 columns
+fn locations(result: &Value) -> Vec<(PathBuf, usize, usize)> {
+    let items = match result {
+        Value::Array(items) => items.iter().collect(),
+        Value::Object(_) => vec![result],
+        _ => Vec::new(),
+    };
+    items
+        .into_iter()
+        .filter_map(|item| {
+            // A `LocationLink` or a `Location`
+            let uri = item.get("targetUri").or_else(|| item.get("uri"))?.as_str()?;
+            let range = item.get("targetSelectionRange").or_else(|| item.get("range"))?;
+            let start = range.get("start")?;
+            Some((uri_path(uri)?, number(start, "line"), number(start, "character")))
+        })
+        .collect()
+}
+
+impl Editor {
+    // Once per turn of the event loop: start servers for the open files, tell them about
+    // changes, and act on what they sent
+    pub(super) fn lsp_poll(&mut self) {
+        if !self.options.lsp {
+            if self.lsp.is_running() {
+                self.lsp_stop();
+            }
+            return;
+        }
+        self.lsp_start();
+        self.lsp_sync();
+        for index in 0..self.lsp.clients.len() {
+            let messages = match self.lsp.clients.get_mut(index) {
+                Some(client) => client.receive(),
+                None => continue,
+            };
+            for message in messages {
+                self.lsp_handle(index, message);
+            }
+        }
+
+        let mut gone = Vec::new();
+        self.lsp.clients.retain(|client| {
+            if !client.running {
+                let uris: Vec<String> = client.documents.keys().cloned().collect();
+                gone.push((client.filetype, client.command.clone(), uris));
+            }
+            client.running
+        });
+        for (filetype, command, uris) in gone {
+            self.lsp.failed.insert(filetype);
+            // What the other servers said still holds
+            for uri in uris {
+                self.lsp.diagnostics.remove(&uri);
+            }
+            self.error(&format!("Language server {} exited", command));
+        }
+    }
+
+    fn server_command(&self, filetype: &str) -> Option<String> {
+        self.lsp.servers.get(filetype).cloned().or_else(|| {
+            SERVERS
+                .iter()
+                .find(|(name, _)| *name == filetype)
+                .map(|(_, command)| (*command).to_string())
+        })
+    }
+
+    // A server for every filetype among the open files that has one
+    fn lsp_start(&mut self) {
+        let filetypes: Vec<&'static str> = self
+            .documents
+            .iter()
+            .filter_map(|document| filetype::detect(document.filename.as_deref()))
+            .map(|filetype| filetype.name)
+            .collect();
+        for filetype in filetypes {
+            if self.lsp.failed.contains(filetype) || self.lsp.clients.iter().any(|client| client.filetype == filetype) {
+                continue;
+            }
+            let command = match self.server_command(filetype) {
+                Some(command) => command,
+                None => continue,
+            };
+            let root = env::current_dir().unwrap_or_default();
+            let params = json!({
+                "processId": std::process::id(),
+                "clientInfo": { "name": "sodium", "version": env!("CARGO_PKG_VERSION") },
+                "rootUri": uri(&root),
+                "workspaceFolders": [{ "uri": uri(&root), "name": root.to_string_lossy() }],
+                "capabilities": {
+                    "general": { "positionEncodings": ["utf-16"] },
+                    "textDocument": {
+                        "synchronization": { "didSave": true },
+                        "hover": { "contentFormat": ["plaintext", "markdown"] },
+                        "completion": { "completionItem": { "snippetSupport": false } },
+                        "definition": {},
+                        "references": {},
+                        "rename": {},
+                        "publishDiagnostics": {},
+                    },
+                    "workspace": {
+                        "applyEdit": true,
+                        "workspaceEdit": { "documentChanges": true },
+                        "configuration": true,
+                    },
+                },
+            });
+            let started = Client::spawn(filetype, &command)
+                .and_then(|mut client| client.request("initialize", params, Pending::Initialize).map(|_| client));
+            match started {
+                Ok(client) => self.lsp.clients.push(client),
+                Err(error) => {
+                    self.lsp.failed.insert(filetype);
+                    self.error(&format!("Could not start {}: {}", command, error));
+                }
+            }
+        }
+    }
+
+    // Stop every server, when quitting or when `lsp` is switched off
+    pub(super) fn lsp_stop(&mut self) {
+        for client in &mut self.lsp.clients {
+            client.shutdown();
+        }
+        self.lsp.clients.clear();
+        self.lsp.failed.clear();
+        self.lsp.diagnostics.clear();
+    }
+
+    // Open the documents each server has not seen yet, send what changed in the others, and
+    // close those no longer open
+    fn lsp_sync(&mut self) {
+        for client in self.lsp.clients.iter_mut().filter(|client| client.ready) {
+            let incremental = match client.capabilities.get("textDocumentSync") {
+                Some(Value::Number(kind)) => kind.as_u64() == Some(2),
+                Some(options) => options.get("change").and_then(Value::as_u64) == Some(2),
+                None => false,
+            };
+            let mut open = HashSet::new();
+            for document in &self.documents {
+                if filetype::detect(document.filename.as_deref()).map(|filetype| filetype.name) != Some(client.filetype) {
+                    continue;
+                }
+                let uri = match document_uri(document) {
+                    Some(uri) => uri,
+                    None => continue,
+                };
+                open.insert(uri.clone());
+                let sent = match client.documents.get_mut(&uri) {
+                    Some(synced) if synced.revision == document.revision() => continue,
+                    Some(synced) => {
+                        let lines: Vec<String> = document.lines().collect();
+                        let change = if incremental {
+                            line_change(&synced.lines, &lines)
+                        } else {
+                            json!({ "text": full_text(&lines) })
+                        };
+                        synced.version = synced.version.saturating_add(1);
+                        synced.revision = document.revision();
+                        synced.lines = lines;
+                        let params = json!({
+                            "textDocument": { "uri": uri, "version": synced.version },
+                            "contentChanges": [change],
+                        });
+                        client.notify("textDocument/didChange", params)
+                    }
+                    None => {
+                        let lines: Vec<String> = document.lines().collect();
+                        let params = json!({
+                            "textDocument": {
+                                "uri": uri,
+                                "languageId": client.filetype,
+                                "version": 0,
+                                "text": full_text(&lines),
+                            },
+                        });
+                        let synced = Synced {
+                            version: 0,
+                            revision: document.revision(),
+                            lines,
+                        };
+                        client.documents.insert(uri.clone(), synced);
+                        client.notify("textDocument/didOpen", params)
+                    }
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+            let closed: Vec<String> = client.documents.keys().filter(|uri| !open.contains(*uri)).cloned().collect();
+            for uri in closed {
+                client.documents.remove(&uri);
+                let _ = client.notify("textDocument/didClose", json!({ "textDocument": { "uri": uri } }));
+            }
+        }
+    }
+
+    // After `document` was written
+    pub(super) fn lsp_saved(&mut self, document: usize) {
+        if !self.lsp.is_running() {
+            return;
+        }
+        self.lsp_sync();
+        let uri = match self.documents.get(document).and_then(document_uri) {
+            Some(uri) => uri,
+            None => return,
+        };
+        for client in self.lsp.clients.iter_mut().filter(|client| client.documents.contains_key(&uri)) {
+            let _ = client.notify("textDocument/didSave", json!({ "textDocument": { "uri": uri } }));
+        }
+    }
+
+    fn lsp_handle(&mut self, index: usize, message: Message) {
+        match message {
+            Message::Response { pending, result: Err(error) } => {
+                if !matches!(pending, Pending::Shutdown) {
+                    self.error(&format!("Language server: {}", error));
+                }
+            }
+            Message::Response { pending, result: Ok(result) } => self.lsp_answer(index, pending, &result),
+            Message::Notification { method, params } => match method.as_str() {
+                "textDocument/publishDiagnostics" => {
+                    let uri = params.get("uri").and_then(Value::as_str).unwrap_or("").to_string();
+                    let diagnostics = params
+                        .get("diagnostics")
+                        .and_then(Value::as_array)
+                        .map(|diagnostics| diagnostics.iter().filter_map(diagnostic).collect())
+                        .unwrap_or_default();
+                    self.lsp.diagnostics.insert(uri, diagnostics);
+                }
+                "window/showMessage" => {
+                    let text = params.get("message").and_then(Value::as_str).unwrap_or("").to_string();
+                    if params.get("type").and_then(Value::as_u64) == Some(1) {
+                        self.error(&text);
+                    } else {
+                        self.status_message = StatusMessage::from(text);
+                    }
+                }
+                _ => (),
+            },
+            Message::Request { id, method, params } => {
+                let answer = match method.as_str() {
+                    // No settings of our own: the server's defaults
+                    "workspace/configuration" => {
+                        let items = params.get("items").and_then(Value::as_array).map_or(0, Vec::len);
+                        Ok(Value::Array(vec![Value::Null; items]))
+                    }
+                    "workspace/applyEdit" => {
+                        let applied = params.get("edit").map_or(false, |edit| self.apply_workspace_edit(edit) > 0);
+                        Ok(json!({ "applied": applied }))
+                    }
+                    "window/workDoneProgress/create" | "client/registerCapability" | "client/unregisterCapability" => {
+                        Ok(Value::Null)
+                    }
+                    _ => Err((-32601, "Method not found")),
+                };
+                if let Some(client) = self.lsp.clients.get_mut(index) {
+                    let _ = client.respond(id, answer);
+                }
+            }
+        }
+    }
+
+    fn lsp_answer(&mut self, index: usize, pending: Pending, result: &Value) {
+        match pending {
+            Pending::Initialize => {
+                if let Some(client) = self.lsp.clients.get_mut(index) {
+                    client.capabilities = result.get("capabilities").cloned().unwrap_or(Value::Null);
+                    client.ready = client.notify("initialized", json!({})).is_ok();
+                }
+                self.lsp_sync();
+            }
+            Pending::Shutdown => (),
+            Pending::Hover => {
+                self.lsp.hover = hover_lines(result.get("contents").unwrap_or(&Value::Null));
+                if self.lsp.hover.is_empty() {
+                    self.status_message = StatusMessage::from("No information here".to_string());
+                }
+            }
+            Pending::Definition => match locations(result).first() {
+                Some((path, line, character)) => {
+                    let here = self.window().cur_pos.clone();
+                    self.push_jump(here);
+                    self.go_to_location(path, *line, *character);
+                }
+                None => self.error("No definition found"),
+            },
+            Pending::References => {
+                let entries: Vec<Entry> = locations(result)
+                    .into_iter()
+                    .map(|(path, line, character)| {
+                        let text = self.file_line(&path, line);
+                        Entry {
+                            column: grapheme_column(&text, character),
+                            path,
+                            line,
+                            kind: None,
+                            text: text.trim().to_string(),
+                        }
+                    })
+                    .collect();
+                if entries.is_empty() {
+                    self.error("No references found");
+                    return;
+                }
+                self.set_quickfix(entries);
+                self.quickfix_open();
+            }
+            Pending::Rename => match self.apply_workspace_edit(result) {
+                0 => self.error("Nothing to rename"),
+                1 => self.status_message = StatusMessage::from("Renamed in 1 file".to_string()),
+                files => self.status_message = StatusMessage::from(format!("Renamed in {} files", files)),
+            },
+            Pending::Completion { uri, start } => self.lsp_completions(&uri, &start, result),
+        }
+    }
+
+    // Line `y` of `path`, from its buffer when it is open
+    fn file_line(&self, path: &Path, y: usize) -> String {
+        let open = self
+            .documents
+            .iter()
+            .find(|document| document.filename.as_deref().map(absolute).as_deref() == Some(path));
+        match open {
+            Some(document) => document.line(y).map_or("", Line::as_str).to_string(),
+            None => fs::read_to_string(path)
+                .ok()
+                .and_then(|text| text.lines().nth(y).map(String::from))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn go_to_location(&mut self, path: &Path, line: usize, character: usize) {
+        let index = self.buffer_for(&relative(path));
+        if index != self.window().document {
+            self.set_document(index);
+        }
+        let y = line.min(self.document().len().saturating_sub(1));
+        let x = grapheme_column(self.document().line(y).map_or("", Line::as_str), character);
+        self.window_mut().cur_pos = Position { x, y };
+    }
+
+    // Make the changes of a `WorkspaceEdit`, opening the files they are in; how many files
+    // were changed
+    fn apply_workspace_edit(&mut self, edit: &Value) -> usize {
+        let mut files: Vec<(String, Vec<Value>)> = Vec::new();
+        if let Some(changes) = edit.get("changes").and_then(Value::as_object) {
+            for (uri, edits) in changes {
+                files.push((uri.clone(), edits.as_array().cloned().unwrap_or_default()));
+            }
+        }
+        // Creating, renaming and deleting files are not supported
+        for change in edit.get("documentChanges").and_then(Value::as_array).into_iter().flatten() {
+            let uri = change.get("textDocument").and_then(|document| document.get("uri")).and_then(Value::as_str);
+            if let (Some(uri), Some(edits)) = (uri, change.get("edits").and_then(Value::as_array)) {
+                files.push((uri.to_string(), edits.clone()));
+            }
+        }
+
+        let mut changed: usize = 0;
+        for (uri, edits) in files {
+            let path = match uri_path(&uri) {
+                Some(path) => path,
+                None => continue,
+            };
+            let index = self.buffer_for(&relative(&path));
+            let document = match self.documents.get_mut(index) {
+                Some(document) => document,
+                None => continue,
+            };
+            let position = |value: &Value, key: &str, text: &Document| {
+                let at = value.get("range").and_then(|range| range.get(key)).unwrap_or(&Value::Null);
+                let y = number(at, "line");
+                let x = grapheme_column(text.line(y).map_or("", Line::as_str), number(at, "character"));
+                Position { x, y }
+            };
+            // By start, then by place in the list
+            let mut edits: Vec<(usize, Value)> = edits.into_iter().enumerate().collect();
+            edits.sort_by_key(|(index, change)| {
+                let start = change.get("range").and_then(|range| range.get("start")).unwrap_or(&Value::Null);
+                (number(start, "line"), number(start, "character"), *index)
+            });
+            // Edits starting at the same spot become one, their texts in the order the server sent
+            // them, so that making one cannot move or take out what another put there
+            let mut merged: Vec<(Position, Position, String)> = Vec::new();
+            for (_, change) in &edits {
+                let start = position(change, "start", document);
+                let end = position(change, "end", document);
+                let end = if end.x == 0 && end.y > start.y {
+                    // Up to the start of a line: through the end of the one before
+                    let y = end.y.saturating_sub(1);
+                    Position { x: document.line(y).map_or(0, Line::len).saturating_add(1), y }
+                } else {
+                    end
+                };
+                let text = change.get("newText").and_then(Value::as_str).unwrap_or("");
+                match merged.last_mut() {
+                    Some((first, last, joined)) if *first == start => {
+                        if (end.y, end.x) > (last.y, last.x) {
+                            *last = end;
+                        }
+                        joined.push_str(text);
+                    }
+                    _ => merged.push((start, end, text.to_string())),
+                }
+            }
+            document.seal();
+            // From the end, so that the ranges of the edits still to make stay put
+            for (start, end, text) in merged.iter().rev() {
+                if (start.y, start.x) < (end.y, end.x) {
+                    document.delete_text(start, end);
+                }
+                if !text.is_empty() {
+                    document.insert_text(start, text);
+                }
+            }
+            document.seal();
+            changed = changed.saturating_add(1);
+        }
+        changed
+    }
+
+    // The server for the focused document, ready to be asked things, after telling it about
+    // the latest changes
+    fn lsp_client(&mut self) -> Option<usize> {
+        if !self.options.lsp {
+            self.error("Language servers are off (set lsp)");
+            return None;
+        }
+        self.lsp_sync();
+        let filetype = filetype::detect(self.document().filename.as_deref()).map(|filetype| filetype.name);
+        let index = self.lsp.clients.iter().position(|client| Some(client.filetype) == filetype);
+        match index.and_then(|index| self.lsp.clients.get(index)) {
+            Some(client) if client.ready => index,
+            Some(client) => {
+                let message = format!("{} is still starting", client.command);
+                self.error(&message);
+                None
+            }
+            None => {
+                self.error("No language server for this buffer");
+                None
+            }
+        }
+    }
+
+    // Ask about the spot under the cursor, with `extra` parameters
+    fn lsp_request(&mut self, method: &str, extra: Value, pending: Pending) {
+        let index = match self.lsp_client() {
+            Some(index) => index,
+            None => return,
+        };
+        let Position { x, y } = self.window().cur_pos;
+        let text = self.document().line(y).map_or("", Line::as_str);
+        let mut params = json!({
+            "textDocument": { "uri": document_uri(self.document()) },
+            "position": { "line": y, "character": utf16_column(text, x) },
+        });
+        if let (Some(params), Value::Object(extra)) = (params.as_object_mut(), extra) {
+            params.extend(extra);
+        }
+        let sent = self.lsp.clients.get_mut(index).map(|client| client.request(method, params, pending));
+        if let Some(Err(error)) = sent {
+            self.error(&format!("Language server: {}", error));
+        }
+    }
+
+    // `K`, `gd`, `gr` and `:rename <name>`
+    pub(super) fn hover(&mut self) {
+        self.lsp_request("textDocument/hover", Value::Null, Pending::Hover);
+    }
+
+    pub(super) fn definition(&mut self) {
+        self.lsp_request("textDocument/definition", Value::Null, Pending::Definition);
+    }
+
+    pub(super) fn references(&mut self) {
+        let extra = json!({ "context": { "includeDeclaration": true } });
+        self.lsp_request("textDocument/references", extra, Pending::References);
+    }
+
+    pub(super) fn rename(&mut self, name: Option<&str>) {
+        match name {
+            Some(name) => self.lsp_request("textDocument/rename", json!({ "newName": name }), Pending::Rename),
+            None => self.error("Usage: rename <name>"),
+        }
+    }
+
+    // `Ctrl-X Ctrl-O` in insert mode: complete the word before the cursor with what the server
+    // suggests
+    pub(super) fn complete_lsp(&mut self) {
+        if self.mode != State::Insert {
+            return;
+        }
+        let (start, _) = self.word_before_cursor();
+        let pending = Pending::Completion {
+            uri: document_uri(self.document()).unwrap_or_default(),
+            start,
+        };
+        self.lsp_request("textDocument/completion", Value::Null, pending);
+    }
+
+    // Offer the completion items, if the cursor is still in the word they were asked for
+    fn lsp_completions(&mut self, uri: &str, start: &Position, result: &Value) {
+        let Position { x, y } = self.window().cur_pos;
+        let same = document_uri(self.document()).map_or(false, |current| current == uri);
+        if self.mode != State::Insert || !same || y != start.y || x < start.x {
+            return;
+        }
+        let prefix = self.document().line(y).map_or("", |line| line.slice(start.x, x)).to_string();
+        let items = match result {
+            Value::Array(items) => items,
+            _ => match result.get("items").and_then(Value::as_array) {
+                Some(items) => items,
+                None => return,
+            },
+        };
+        let mut sorted: Vec<&Value> = items.iter().collect();
+        sorted.sort_by_key(|item| {
+            item.get("sortText")
+                .or_else(|| item.get("label"))
+                .and_then(Value::as_str)
+                .unwrap_or("")
+                .to_string()
+        });
+        let mut candidates: Vec<String> = Vec::new();
+        for item in sorted {
+            let text = item
+                .get("textEdit")
+                .and_then(|edit| edit.get("newText"))
+                .or_else(|| item.get("insertText"))
+                .or_else(|| item.get("label"))
+                .and_then(Value::as_str)
+                .unwrap_or("");
+            if text.starts_with(&prefix) && text != prefix && !candidates.iter().any(|candidate| candidate == text) {
+                candidates.push(text.to_string());
+            }
+        }
+        if self.show_completions(start.clone(), prefix, candidates) {
+            self.select_candidate(true);
+        }
+    }
+
+    // `:lsp` lists the servers, `:lsp <filetype> <command>` picks the one for a filetype, and
+    // `:lsp restart` starts them all again
+    pub(super) fn lsp_command(&mut self, argument: &str) -> Result<(), String> {
+        let mut words = argument.splitn(2, ' ');
+        match (words.next().unwrap_or(""), words.next().map(str::trim)) {
+            ("", _) => {
+                let mut filetypes: Vec<&str> = SERVERS.iter().map(|(filetype, _)| *filetype).collect();
+                for filetype in self.lsp.servers.keys() {
+                    if !filetypes.contains(&filetype.as_str()) {
+                        filetypes.push(filetype);
+                    }
+                }
+                let mut list: Vec<String> = Vec::new();
+                for filetype in filetypes {
+                    let state = match self.lsp.clients.iter().find(|client| client.filetype == filetype) {
+                        Some(client) if client.ready => " (running)",
+                        Some(_) => " (starting)",
+                        None if self.lsp.failed.contains(filetype) => " (failed)",
+                        None => "",
+                    };
+                    list.push(format!("{}: {}{}", filetype, self.server_command(filetype).unwrap_or_default(), state));
+                }
+                let list = list.join("  ");
+                self.status_message = StatusMessage::from(if self.options.lsp { list } else { format!("off  {}", list) });
+                Ok(())
+            }
+            ("restart", None) => {
+                self.lsp_stop();
+                Ok(())
+            }
+            (filetype, Some(command)) if !command.is_empty() => {
+                self.lsp.servers.insert(filetype.to_string(), command.to_string());
+                Ok(())
+            }
+            _ => Err("Usage: lsp [<filetype> <command>] or lsp restart".to_string()),
+        }
+    }
+
+    fn diagnostics(&self, document: usize) -> Option<&Vec<Diagnostic>> {
+        let uri = self.documents.get(document).and_then(document_uri)?;
+        self.lsp.diagnostics.get(&uri).filter(|diagnostics| !diagnostics.is_empty())
+    }
+
+    fn severity_style(&self, severity: Severity) -> Style {
+        match severity {
+            Severity::Error => self.theme.error,
+            Severity::Warning => self.theme.warning,
+            Severity::Information | Severity::Hint => self.theme.gutter,
+        }
+    }
+
+    // Gutter signs for the lines of `document` with diagnostics, the worst on each
+    pub(super) fn diagnostic_signs(&self, document: usize) -> Vec<(usize, char, Style)> {
+        let mut worst: BTreeMap<usize, Severity> = BTreeMap::new();
+        for diagnostic in self.diagnostics(document).into_iter().flatten() {
+            let severity = worst.entry(diagnostic.line).or_insert(diagnostic.severity);
+            *severity = (*severity).min(diagnostic.severity);
+        }
+        worst
+            .into_iter()
+            .map(|(y, severity)| {
+                let sign = match severity {
+                    Severity::Error => 'E',
+                    Severity::Warning => 'W',
+                    Severity::Information => 'I',
+                    Severity::Hint => 'H',
+                };
+                (y, sign, self.severity_style(severity))
+            })
+            .collect()
+    }
+
+    // The worst diagnostic on the cursor line, for the message bar
+    pub(super) fn diagnostic_message(&self) -> Option<(String, Style)> {
+        let window = self.window();
+        let diagnostic = self
+            .diagnostics(window.document)?
+            .iter()
+            .filter(|diagnostic| diagnostic.line == window.cur_pos.y)
+            .min_by_key(|diagnostic| (diagnostic.severity, diagnostic.character))?;
+        let first = diagnostic.message.lines().next().unwrap_or("");
+        Some((first.to_string(), self.severity_style(diagnostic.severity)))
+    }
+
+    pub(super) fn close_hover(&mut self) {
+        self.lsp.hover.clear();
+    }
+
+    // Hover text in a box under the cursor, or over it when there is more room there
+    pub(super) fn draw_hover(&self, screen: &mut Screen) {
+        if self.lsp.hover.is_empty() {
+            return;
+        }
+        let window = self.window();
+        let row = window.rect.y.saturating_add(window.cur_pos.y.saturating_sub(window.offset.y));
+        let column = self.screen_column(window, &window.cur_pos);
+        let shown = self.lsp.hover.len().min(HOVER_HEIGHT);
+        let text_bottom = screen.height().saturating_sub(1);
+        let below = text_bottom.saturating_sub(row.saturating_add(1));
+        let top = if below >= shown || below >= row {
+            row.saturating_add(1)
+        } else {
+            row.saturating_sub(shown)
+        };
+        let width = self
+            .lsp
+            .hover
+            .iter()
+            .map(|line| line.width())
+            .max()
+            .unwrap_or(0)
+            .saturating_add(2)
+            .min(screen.width());
+        let left = column.min(screen.width().saturating_sub(width));
+        for (offset, line) in self.lsp.hover.iter().take(shown.min(text_bottom.saturating_sub(top))).enumerate() {
+            let y = top.saturating_add(offset);
+            screen.fill(left, y, width, self.theme.menu);
+            let text: String = line.graphemes(true).take(width.saturating_sub(2)).collect();
+            screen.put_str(left.saturating_add(1), y, &text, self.theme.menu);
+        }
+    }
+}
+
+fn diagnostic(value: &Value) -> Option<Diagnostic> {
+    let start = value.get("range")?.get("start")?;
+    let severity = match value.get("severity").and_then(Value::as_u64) {
+        Some(2) => Severity::Warning,
+        Some(3) => Severity::Information,
+        Some(4) => Severity::Hint,
+        _ => Severity::Error,
+    };
+    Some(Diagnostic {
+        line: number(start, "line"),
+        character: number(start, "character"),
+        severity,
+        message: value.get("message")?.as_str()?.to_string(),
+    })
+}
@@ -0,0 +1,101 @@
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+// JSON-RPC messages framed the way the Language Server Protocol sends them over stdio: a
+// `Content-Length` header, a blank line, then that many bytes of JSON. Anything that reads and
+// writes bytes will do, a server's pipes or a mock one's.
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+// Longer messages are refused rather than read, a broken server could otherwise take all the memory
+const MESSAGE_LIMIT: usize = 64 * 1024 * 1024;
+
+// The next message, or `None` once the other side is gone
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length: Option<usize> = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        // Other headers (only `Content-Type` is defined) are ignored
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "message without a Content-Length"))?;
+    if length > MESSAGE_LIMIT {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too long"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor, ErrorKind};
+
+    use serde_json::json;
+
+    use super::{read_message, write_message};
+
+    #[test]
+    fn frames_with_the_length_in_bytes() {
+        let mut out = Vec::new();
+        write_message(&mut out, &json!({ "text": "é" })).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Content-Length: 13\r\n\r\n{\"text\":\"é\"}");
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let mut out = Vec::new();
+        write_message(&mut out, &json!({ "id": 1 })).unwrap();
+        write_message(&mut out, &json!({ "method": "exit", "text": "über" })).unwrap();
+        // One byte at a time, so that no read gets a whole message
+        let mut reader = BufReader::with_capacity(1, Cursor::new(out));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "id": 1 })));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "method": "exit", "text": "über" })));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn skips_other_headers() {
+        let data = "content-length: 2\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}";
+        let mut reader = Cursor::new(data);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({})));
+    }
+
+    #[test]
+    fn needs_a_length() {
+        let mut reader = Cursor::new("Content-Type: text/plain\r\n\r\n{}");
+        let error = read_message(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_a_huge_length() {
+        let mut reader = Cursor::new("Content-Length: 999999999999\r\n\r\n{}");
+        let error = read_message(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fails_on_a_cut_off_body() {
+        let mut reader = Cursor::new("Content-Length: 10\r\n\r\n{}");
+        assert!(read_message(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+
+use termion::event::Key;
+
+use crate::error::Error;
+use crate::state::keymap::Action;
+use crate::state::{Editor, Position, State};
+use crate::Line;
+
+// Jumps remembered by each window
+const JUMP_LIMIT: usize = 100;
+
+fn before(a: &Position, b: &Position) -> bool {
+    (a.y, a.x) < (b.y, b.x)
+}
+
+/// Named positions in a document that follow the edits made to it.
+#[derive(Default, Clone)]
+pub struct Marks(BTreeMap<char, Position>);
+
+impl Marks {
+    pub fn get(&self, name: char) -> Option<&Position> {
+        self.0.get(&name)
+    }
+
+    pub fn set(&mut self, name: char, position: Position) {
+        self.0.insert(name, position);
+    }
+
+    pub fn remove(&mut self, name: char) -> Option<Position> {
+        self.0.remove(&name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (char, &Position)> {
+        self.0.iter().map(|(name, position)| (*name, position))
+    }
+
+    // Lines `top..top + removed` were replaced by `added` new ones; marks on lines that are gone
+    // go with them
+    pub fn lines_replaced(&mut self, top: usize, removed: usize, added: usize) {
+        self.0.retain(|_, mark| {
+            if mark.y < top {
+                true
+            } else if mark.y >= top.saturating_add(removed) {
+                mark.y = mark.y.saturating_add(added).saturating_sub(removed);
+                true
+            } else {
+                mark.y.saturating_sub(top) < added
+            }
+        });
+    }
+
+    // The text from `start` up to `end` was replaced by text that ends at `new_end`
+    pub fn text_replaced(&mut self, start: &Position, end: &Position, new_end: &Position) {
+        self.0.retain(|_, mark| {
+            if before(mark, start) {
+                return true;
+            }
+            if !before(mark, end) {
+                if mark.y == end.y {
+                    mark.x = mark.x.saturating_sub(end.x).saturating_add(new_end.x);
+                }
+                mark.y = mark.y.saturating_sub(end.y).saturating_add(new_end.y);
+                return true;
+            }
+            // Inside the replaced text: kept on the lines that are still there
+            if mark.y > new_end.y {
+                return false;
+            }
+            if mark.y == new_end.y {
+                mark.x = mark.x.min(new_end.x);
+            }
+            true
+        });
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Jump {
+    pub document: usize,
+    pub position: Position,
+}
+
+impl Jump {
+    fn same_line(&self, other: &Self) -> bool {
+        self.document == other.document && self.position.y == other.position.y
+    }
+}
+
+/// Where a window was before its long moves, to go back and forth between them.
+#[derive(Default, Clone)]
+pub struct JumpList {
+    entries: Vec<Jump>,
+    index: usize, // The entry we went back to, or `entries.len()` when not going through them
+    previous: Option<Jump>, // Before the latest jump, for the `'` mark
+}
+
+impl JumpList {
+    // Remember `jump` as the newest entry; one on the same line is dropped, and so is anything
+    // we had gone back past
+    pub fn push(&mut self, jump: Jump) {
+        self.entries.truncate(self.index);
+        self.entries.retain(|entry| !entry.same_line(&jump));
+        self.entries.push(jump.clone());
+        if self.entries.len() > JUMP_LIMIT {
+            self.entries.remove(0);
+        }
+        self.index = self.entries.len();
+        self.previous = Some(jump);
+    }
+
+    // The entry before the one at `current`, which is remembered first so that we can come back
+    pub fn back(&mut self, current: Jump) -> Option<Jump> {
+        if self.index >= self.entries.len() {
+            self.entries.retain(|entry| !entry.same_line(&current));
+            self.entries.push(current);
+            self.index = self.entries.len().saturating_sub(1);
+        }
+        self.index = self.index.checked_sub(1)?;
+        self.entries.get(self.index).cloned()
+    }
+
+    pub fn forward(&mut self) -> Option<Jump> {
+        let next = self.index.saturating_add(1);
+        let jump = self.entries.get(next).cloned()?;
+        self.index = next;
+        Some(jump)
+    }
+
+    // Buffer `removed` was deleted and the ones after it moved down
+    pub fn remove_document(&mut self, removed: usize) {
+        let shift = |jump: &mut Jump| {
+            if jump.document > removed {
+                jump.document = jump.document.saturating_sub(1);
+            }
+        };
+        self.entries.retain(|entry| entry.document != removed);
+        self.entries.iter_mut().for_each(shift);
+        self.index = self.index.min(self.entries.len());
+        if self.previous.as_ref().map_or(false, |jump| jump.document == removed) {
+            self.previous = None;
+        }
+        self.previous.iter_mut().for_each(shift);
+    }
+}
+
+// Motions that remember where they started in the jump list
+pub fn is_jump(action: Action) -> bool {
+    matches!(
+        action,
+        Action::Top
+            | Action::Bottom
+            | Action::MatchBracket
+            | Action::SentenceForward
+            | Action::SentenceBackward
+            | Action::ParagraphForward
+            | Action::ParagraphBackward
+    )
+}
+
+impl Editor {
+    fn here(&self) -> Jump {
+        Jump {
+            document: self.window().document,
+            position: self.window().cur_pos.clone(),
+        }
+    }
+
+    // Remember `position` in the focused window's document before jumping away from it
+    pub(super) fn push_jump(&mut self, position: Position) {
+        let jump = Jump {
+            document: self.window().document,
+            position,
+        };
+        self.window_mut().jumps.push(jump);
+    }
+
+    // `m<name>`: a-z for this document, A-Z for one place among all files
+    pub(super) fn set_mark(&mut self) -> Result<(), Error> {
+        let position = self.window().cur_pos.clone();
+        match self.next_key()? {
+            Key::Char(name) if name.is_ascii_lowercase() => self.document_mut().marks.set(name, position),
+            Key::Char(name) if name.is_ascii_uppercase() => {
+                for document in &mut self.documents {
+                    document.marks.remove(name);
+                }
+                self.file_marks.remove(&name);
+                self.document_mut().marks.set(name, position);
+            }
+            Key::Char('\'') | Key::Char('`') => self.push_jump(position),
+            Key::Esc => (),
+            _ => self.error("Marks are named a-z or A-Z"),
+        }
+        Ok(())
+    }
+
+    // `'<name>` goes to the first non-blank of the marked line, `` `<name> `` to the very spot;
+    // `''` returns to where the last jump started
+    pub(super) fn jump_to_mark(&mut self, exact: bool) -> Result<(), Error> {
+        let name = match self.next_key()? {
+            Key::Char(name) => name,
+            _ => return Ok(()),
+        };
+        let target = match name {
+            '\'' | '`' => self.window().jumps.previous.clone(),
+            'a'..='z' | '"' => self.document().marks.get(name).map(|position| Jump {
+                document: self.window().document,
+                position: position.clone(),
+            }),
+            'A'..='Z' => self.file_mark(name),
+            _ => None,
+        };
+        let target = match target {
+            Some(target) => target,
+            None => {
+                self.error(&format!("Mark not set: {}", name));
+                return Ok(());
+            }
+        };
+        if target.document != self.window().document && self.mode == State::Visual {
+            self.error("Mark is in another buffer");
+            return Ok(());
+        }
+        let here = self.window().cur_pos.clone();
+        self.push_jump(here);
+        self.go_to(&target, exact);
+        Ok(())
+    }
+
+    // Where global mark `name` is, opening its file if it was set in a buffer since closed
+    fn file_mark(&mut self, name: char) -> Option<Jump> {
+        let found = self.documents.iter().enumerate().find_map(|(document, open)| {
+            open.marks.get(name).map(|position| Jump {
+                document,
+                position: position.clone(),
+            })
+        });
+        if found.is_some() {
+            return found;
+        }
+        let (filename, position) = self.file_marks.remove(&name)?;
+        let document = self.buffer_for(&filename);
+        self.documents.get_mut(document)?.marks.set(name, position.clone());
+        Some(Jump { document, position })
+    }
+
+    // Show `jump` in the focused window, keeping within the document as it is now
+    fn go_to(&mut self, jump: &Jump, exact: bool) {
+        if jump.document != self.window().document {
+            self.set_document(jump.document);
+        }
+        let y = jump.position.y.min(self.document().len().saturating_sub(1));
+        let x = if exact {
+            jump.position.x.min(self.document().line(y).map_or(0, Line::len))
+        } else {
+            self.first_non_blank(y)
+        };
+        self.window_mut().cur_pos = Position { x, y };
+    }
+
+    // `Ctrl-O` and `Tab`: back to older places in the jump list, and forward again
+    pub(super) fn jump_back(&mut self) {
+        let here = self.here();
+        if let Some(jump) = self.window_mut().jumps.back(here) {
+            self.go_to(&jump, true);
+        }
+    }
+
+    pub(super) fn jump_forward(&mut self) {
+        if let Some(jump) = self.window_mut().jumps.forward() {
+            self.go_to(&jump, true);
+        }
+    }
+
+    // Global marks of a buffer about to be deleted are kept by file name
+    pub(super) fn keep_file_marks(&mut self, document: usize) {
+        if let Some(document) = self.documents.get(document) {
+            if let Some(filename) = &document.filename {
+                for (name, position) in document.marks.iter().filter(|(name, _)| name.is_ascii_uppercase()) {
+                    self.file_marks.insert(name, (filename.clone(), position.clone()));
+                }
+            }
+        }
+    }
+
+    // The text of line `y` of `document`, or the name of the file it is in when that is not
+    // the current one
+    fn mark_context(&self, document: usize, y: usize) -> String {
+        match self.documents.get(document) {
+            Some(open) if document == self.window().document => {
+                open.line(y).map_or("", Line::as_str).trim().to_string()
+            }
+            Some(open) => open.display_name(),
+            None => String::new(),
+        }
+    }
+
+    // `:marks`
+    pub(super) fn list_marks(&mut self) {
+        let current = self.window().document;
+        let mut marks: Vec<(char, Jump)> = Vec::new();
+        if let Some(jump) = &self.window().jumps.previous {
+            marks.push(('\'', jump.clone()));
+        }
+        for (name, position) in self.document().marks.iter().filter(|(name, _)| !name.is_ascii_uppercase()) {
+            marks.push((name, Jump { document: current, position: position.clone() }));
+        }
+        for (document, open) in self.documents.iter().enumerate() {
+            for (name, position) in open.marks.iter().filter(|(name, _)| name.is_ascii_uppercase()) {
+                marks.push((name, Jump { document, position: position.clone() }));
+            }
+        }
+        marks.sort_by_key(|(name, _)| (name.is_ascii_uppercase(), *name));
+
+        let mut lines = vec!["mark  line   col  file/text".to_string()];
+        for (name, jump) in &marks {
+            lines.push(format!(
+                " {}  {:>6} {:>5}  {}",
+                name,
+                jump.position.y.saturating_add(1),
+                jump.position.x,
+                self.mark_context(jump.document, jump.position.y)
+            ));
+        }
+        for (name, (filename, position)) in &self.file_marks {
+            lines.push(format!(" {}  {:>6} {:>5}  {}", name, position.y.saturating_add(1), position.x, filename));
+        }
+        self.show_scratch(&lines);
+    }
+
+    // `:jumps`, with `>` at the entry we went back to
+    pub(super) fn list_jumps(&mut self) {
+        let jumps = self.window().jumps.clone();
+        let mut lines = vec!["   line   col  file/text".to_string()];
+        for (index, jump) in jumps.entries.iter().enumerate() {
+            lines.push(format!(
+                "{} {:>6} {:>5}  {}",
+                if index == jumps.index { '>' } else { ' ' },
+                jump.position.y.saturating_add(1),
+                jump.position.x,
+                self.mark_context(jump.document, jump.position.y)
+            ));
+        }
+        if jumps.index >= jumps.entries.len() {
+            lines.push(">".to_string());
+        }
+        self.show_scratch(&lines);
+    }
+
+    // `:delmarks <names>` removes marks, `:delmarks!` every a-z mark of this document
+    pub(super) fn delete_marks(&mut self, names: Option<&str>, force: bool) {
+        let names: Vec<char> = match names {
+            _ if force => ('a'..='z').collect(),
+            Some(names) => names.chars().filter(|c| !c.is_whitespace()).collect(),
+            None => {
+                self.error("Usage: delmarks <names> or delmarks!");
+                return;
+            }
+        };
+        for name in names {
+            match name {
+                'a'..='z' => {
+                    self.document_mut().marks.remove(name);
+                }
+                'A'..='Z' => {
+                    for document in &mut self.documents {
+                        document.marks.remove(name);
+                    }
+                    self.file_marks.remove(&name);
+                }
+                _ => {
+                    self.error(&format!("Not a mark name: {}", name));
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -1,24 +1,72 @@
 /// The global editor state.
 use termion::event::Key;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::env;
+use std::fmt;
+use std::mem;
 use std::time::Duration;
 use std::time::Instant;
-use termion::color;
+use std::path::PathBuf;
+use unicode_width::UnicodeWidthStr;
 
+pub mod bracket;
+pub mod command;
+pub mod complete;
+pub mod config;
 pub mod document;
+pub mod filetype;
+pub mod grep;
+pub mod indent;
+pub mod keymap;
+pub mod keys;
 pub mod line;
+pub mod lsp;
+pub mod mark;
+pub mod motion;
+pub mod object;
+pub mod operator;
+pub mod options;
+pub mod quickfix;
+pub mod register;
+pub mod session;
+pub mod swap;
+pub mod tab;
+pub mod theme;
+pub mod visual;
+pub mod wildmenu;
+pub mod window;
 
-use crate::terminal::Terminal;
+use crate::error::Error;
+use crate::terminal::{Depth, Screen, Style, Terminal};
 use crate::Document;
 use crate::Line;
+use complete::Completion;
+use grep::Grep;
+use keymap::{Action, Input, Keymap};
+use lsp::Lsp;
+use options::Options;
+use quickfix::Quickfix;
+use register::Registers;
+use session::{FileState, History, HistoryKind};
+use tab::Tab;
+use theme::Theme;
+use visual::{BlockInsert, SelectionKind};
+use wildmenu::{Completer, Wildmenu};
+use window::{Layout, Rect, Window};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
-const STATUS_BG_COLOR: color::Rgb = color::Rgb(239, 239, 239);
-const STATUS_FG_COLOR: color::Rgb = color::Rgb(63, 63, 63);
-const QUIT_TIMES: u8 = 1;
+const SWAP_IDLE: Duration = Duration::from_secs(4); // Journal after this long without input
+const SWAP_UPDATE_COUNT: u64 = 200; // ...or after this many edits, whichever comes first
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How often to look at what language servers and searches in the background sent, while any
+// are running
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
+/// The modes keys are mapped in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum State {
     Normal,
+    Visual,
     Replace,
     Insert,
     Prompt,
@@ -27,6 +75,7 @@ pub enum State {
 struct StatusMessage {
     text: String,
     time: Instant,
+    error: bool,
 }
 
 impl StatusMessage {
@@ -34,11 +83,20 @@ impl StatusMessage {
         Self {
             time: Instant::now(),
             text: message,
+            error: false,
+        }
+    }
+
+    // Shown in red in the message bar
+    fn error(message: String) -> Self {
+        Self {
+            error: true,
+            ..Self::from(message)
         }
     }
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, PartialEq)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -47,11 +105,33 @@ pub struct Position {
 pub struct Editor {
     quit: bool,
     terminal: Terminal,
-    cur_pos: Position,
-    document: Document,
-    offset: Position,
+    documents: Vec<Document>, // Open buffers, never empty
+    tabs: Vec<Tab>, // Never empty either
+    tab: usize, // The tab on screen
     status_message: StatusMessage,
     quit_times: u8,
+    mode: State,
+    block_insert: Option<BlockInsert>, // Block change being typed
+    completion: Option<Completion>, // Popup menu of words, while completing in insert mode
+    wildmenu: Option<Wildmenu>, // Candidates gone through with Tab in a prompt
+    registers: Registers,
+    register: Option<char>, // Picked with `"` for the next yank, delete or put
+    file_marks: BTreeMap<char, (String, Position)>, // Global marks of files no longer open
+    remembered: Vec<FileState>, // Marks of files from the state file, most recent first
+    commands: History,
+    searches: History,
+    options: Options,
+    theme: Theme,
+    keymap: Keymap,
+    typeahead: VecDeque<(Key, bool)>, // Keys still to be handled, and whether mappings apply to them
+    pending: Vec<Key>, // Start of a key sequence, shown while waiting for the rest
+    highlight: Option<String>, // Search being typed, its matches are highlighted
+    lsp: Lsp,
+    quickfix: Quickfix,
+    grep: Option<Grep>, // Search through the project running in the background
+    sourcing: Vec<PathBuf>, // Config files being read, innermost last
+    last_key: Instant,
+    disk_checked: Instant, // Last time we looked for changes made to the files by others
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -62,35 +142,88 @@ pub enum SearchDirection {
 
 impl Editor {
 
-    pub fn default() -> Self {
+    pub fn new() -> Result<Self, Error> {
         let args: Vec<String> = env::args().collect();
-        let mut initial_status = String::from(" HELP: Ctrl-F | Ctrl-s = save | Esc = quit");
+        let mut initial_status = StatusMessage::from(String::from(
+            " HELP: Ctrl-F | Ctrl-s = save | Ctrl-p = command | Ctrl-w = window | Esc Esc = quit",
+        ));
 
-        let document = if let Some(file_name) = args.get(1) {
+        let mut documents = Vec::new();
+        let mut session = None;
+        let mut arguments = args.iter().skip(1);
+        while let Some(file_name) = arguments.next() {
+            // `-S <name>` starts with a saved session
+            if file_name == "-S" {
+                session = arguments.next().cloned();
+                continue;
+            }
             let doc = Document::open(file_name);
 
             if let Ok(doc) = doc {
-                doc
+                documents.push(doc);
             } else {
-                initial_status = format!("ERR: Could not open file: {}", file_name);
-                Document::new(file_name)
+                initial_status = StatusMessage::error(format!("Could not open file: {}", file_name));
+                documents.push(Document::new(file_name));
             }
-        } else {
-            Document::default()
-        };
+        }
+        if documents.is_empty() {
+            documents.push(Document::default());
+        }
 
-        Self {
+        let mut editor = Self {
             quit: false,
-            terminal: Terminal::new().expect("Failed to initialize terminal :("),
-            cur_pos: Position::default(),
-            document,
-            offset: Position::default(),
-            status_message: StatusMessage::from(initial_status),
-            quit_times: QUIT_TIMES,
+            terminal: Terminal::new()?,
+            documents,
+            tabs: vec![Tab::new(0)],
+            tab: 0,
+            status_message: initial_status,
+            quit_times: 0,
+            mode: State::Insert,
+            block_insert: None,
+            completion: None,
+            wildmenu: None,
+            registers: Registers::default(),
+            register: None,
+            file_marks: BTreeMap::new(),
+            remembered: Vec::new(),
+            commands: History::default(),
+            searches: History::default(),
+            options: Options::default(),
+            theme: Theme::default(),
+            keymap: Keymap::default(),
+            typeahead: VecDeque::new(),
+            pending: Vec::new(),
+            highlight: None,
+            lsp: Lsp::default(),
+            quickfix: Quickfix::default(),
+            grep: None,
+            sourcing: Vec::new(),
+            last_key: Instant::now(),
+            disk_checked: Instant::now(),
+        };
+        editor.load_config();
+        editor.load_state();
+        if let Some(name) = session {
+            editor.load_session(Some(&name));
         }
+        editor.quit_times = editor.options.quit_times;
+        Ok(editor)
+    }
+
+    // Runs until the user quits; on a fatal error unsaved work is written out before giving up
+    pub fn run(&mut self) -> Result<(), Error> {
+        self.event_loop().map_err(|error| {
+            let saved = self.emergency_save();
+            Terminal::clear_screen();
+            let _ = Terminal::flush();
+            Error::Aborted {
+                cause: Box::new(error),
+                saved,
+            }
+        })
     }
 
-    pub fn run(&mut self) {
+    fn event_loop(&mut self) -> Result<(), Error> {
 
         /* Attempt at screen refresh on terminal resize
         thread::spawn(move || {
@@ -106,58 +239,344 @@ impl Editor {
         });
         */
 
+        self.recover_all()?;
+
         loop {
-            if let Err(error) = self.refresh_screen() {
-                die(&error);
-            }
+            self.lsp_poll();
+            self.grep_poll();
+            self.refresh_screen()?;
             if self.quit {
-                break;
+                self.lsp_stop();
+                // Nowhere left to report a failure to
+                let _ = self.save_state();
+                for document in &mut self.documents {
+                    document.remove_swap();
+                }
+                return Ok(());
+            }
+            if self.mode == State::Visual && self.window().selection.is_none() {
+                self.mode = State::Normal;
+            }
+            // Servers and searches may have something to say before the next key
+            let idle = if self.lsp.is_running() || self.grep.is_some() {
+                POLL_INTERVAL
+            } else {
+                DISK_CHECK_INTERVAL
+            };
+            if let Some(input) = self.next_input(self.mode, idle)? {
+                self.last_key = Instant::now();
+                if let Err(error) = self.process_input(input) {
+                    if error.is_fatal() {
+                        return Err(error);
+                    }
+                    self.report("Error", &error);
+                }
+                // Everything typed in one go in insert mode is undone together
+                if !matches!(self.mode, State::Insert | State::Replace) {
+                    self.documents.iter_mut().for_each(Document::seal);
+                }
+            } else if self.last_key.elapsed() >= SWAP_IDLE {
+                self.journal();
             }
-            if let Err(error) = self.process_insert_keypress() {
-                die(&error);
+            if self.documents.iter().any(|document| document.unjournaled() >= SWAP_UPDATE_COUNT) {
+                self.journal();
             }
+            self.check_disk()?;
         }
     }
 
-    pub fn refresh_screen(&self) -> Result<(), std::io::Error> {
-        Terminal::cursor_hide();
-        Terminal::cursor_pos(&Position::default());
-        if self.quit {
-            Terminal::clear_screen();
+    #[allow(clippy::indexing_slicing)]
+    fn layout(&self) -> &Layout {
+        &self.tabs[self.tab].layout
+    }
+
+    #[allow(clippy::indexing_slicing)]
+    fn layout_mut(&mut self) -> &mut Layout {
+        &mut self.tabs[self.tab].layout
+    }
+
+    fn window(&self) -> &Window {
+        self.layout().focused()
+    }
+
+    fn window_mut(&mut self) -> &mut Window {
+        self.layout_mut().focused_mut()
+    }
+
+    // The document shown in the focused window
+    #[allow(clippy::indexing_slicing)]
+    fn document(&self) -> &Document {
+        &self.documents[self.window().document]
+    }
+
+    #[allow(clippy::indexing_slicing)]
+    fn document_mut(&mut self) -> &mut Document {
+        let index = self.window().document;
+        &mut self.documents[index]
+    }
+
+    // Surface a recoverable error in the message bar
+    fn report(&mut self, context: &str, error: &dyn fmt::Display) {
+        self.status_message = StatusMessage::error(format!("{}: {}", context, error));
+    }
+
+    // Write every modified buffer somewhere safe, returning where they went
+    fn emergency_save(&mut self) -> Vec<PathBuf> {
+        self.documents.iter_mut().filter_map(Document::emergency_save).collect()
+    }
+
+    // Reload documents that were changed on disk, asking first if that would lose edits
+    #[allow(clippy::indexing_slicing)]
+    fn check_disk(&mut self) -> Result<(), Error> {
+        if self.disk_checked.elapsed() < DISK_CHECK_INTERVAL {
+            return Ok(());
+        }
+        self.disk_checked = Instant::now();
+
+        for index in 0..self.documents.len() {
+            let document = &mut self.documents[index];
+            if !document.changed_on_disk() {
+                document.disk_warned = false;
+                continue;
+            }
+            if document.disk_warned {
+                continue;
+            }
+
+            let name = document.display_name();
+            if !document.is_changed()
+                || self.confirm(&format!("{} changed on disk. Reload and lose your changes? (y/n)", name))?
+            {
+                match self.documents[index].reload() {
+                    Ok(()) => {
+                        self.clamp_cursors();
+                        self.status_message = StatusMessage::from(format!("{} changed on disk, reloaded.", name));
+                    }
+                    Err(error) => self.report("Could not reload file", &error),
+                }
+            } else {
+                self.documents[index].disk_warned = true;
+                self.status_message = StatusMessage::from("Kept your changes, saving will ask before overwriting.".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // Ask a yes/no question in the message bar
+    fn confirm(&mut self, question: &str) -> Result<bool, Error> {
+        loop {
+            self.status_message = StatusMessage::from(question.to_string());
+            self.refresh_screen()?;
+
+            match self.terminal.read_key()? {
+                Key::Char('y') | Key::Char('Y') => return Ok(true),
+                Key::Char('n') | Key::Char('N') | Key::Esc => return Ok(false),
+                _ => (),
+            }
+        }
+    }
+
+    // Keep every cursor inside its document, which may have been edited through another window
+    fn clamp_cursors(&mut self) {
+        for window in self.tabs.iter_mut().flat_map(|tab| tab.layout.windows.iter_mut()) {
+            if let Some(document) = self.documents.get(window.document) {
+                window.cur_pos.y = window.cur_pos.y.min(document.len());
+                let width = document.line(window.cur_pos.y).map_or(0, Line::len);
+                window.cur_pos.x = window.cur_pos.x.min(width);
+            }
+            Self::scroll_window(window);
+        }
+    }
+
+    fn journal(&mut self) {
+        for document in &mut self.documents {
+            if let Err(error) = document.journal() {
+                self.status_message = StatusMessage::error(format!("Could not write swap file: {}", error));
+            }
+        }
+    }
+
+    fn recover_all(&mut self) -> Result<(), Error> {
+        let window = self.window().clone();
+        for index in 0..self.documents.len() {
+            if self.documents.get(index).map_or(false, |document| document.recovery.is_some()) {
+                self.window_mut().document = index;
+                self.recover()?;
+            }
+        }
+        *self.window_mut() = window;
+        self.clamp_cursors();
+        Ok(())
+    }
+
+    // Offer to recover the unsaved changes from a stale swap file
+    fn recover(&mut self) -> Result<(), Error> {
+        let recovery = if let Some(recovery) = self.document_mut().recovery.take() {
+            recovery
         } else {
-            self.draw_lines();
-            self.draw_status_bar();
-            self.draw_message_bar();
-            Terminal::cursor_pos(&Position {
-                x: self.cur_pos.x.saturating_sub(self.offset.x),
-                y: self.cur_pos.y.saturating_sub(self.offset.y),
+            return Ok(());
+        };
+        // Another editor still has the file open: its swap file is only there to look at
+        let running = recovery.is_running();
+
+        // Holds the real document while the diff is being shown in its place
+        let mut original: Option<Document> = None;
+        loop {
+            self.status_message = StatusMessage::from(match (original.is_some(), running) {
+                (true, false) => "Diff of file (-) against swap file (+): [r]estore, [d] back, [x] discard, Esc = keep".to_string(),
+                (true, true) => "Diff of file (-) against swap file (+): [d] back, Esc = open read-only".to_string(),
+                (false, false) => "Swap file found: [r]estore, [d]iff, [x] discard, Esc = keep".to_string(),
+                (false, true) => format!("Swap file in use by process {}: [d]iff, Esc = open read-only", recovery.pid),
             });
+            self.refresh_screen()?;
+
+            let key = self.terminal.read_key()?;
+            if key == Key::Char('d') {
+                if let Some(document) = original.take() {
+                    *self.document_mut() = document;
+                } else {
+                    let lines: Vec<String> = self.document().lines().collect();
+                    let diff = Document::scratch(&swap::diff(&lines, &recovery.lines));
+                    original = Some(mem::replace(self.document_mut(), diff));
+                }
+                self.window_mut().cur_pos = Position::default();
+                self.scroll();
+                continue;
+            }
+            let answered = match key {
+                Key::Char('r') | Key::Char('x') => !running,
+                Key::Esc => true,
+                _ => false,
+            };
+            if !answered {
+                continue;
+            }
+
+            if let Some(document) = original.take() {
+                *self.document_mut() = document;
+            }
+            self.window_mut().cur_pos = Position::default();
+            self.scroll();
+            self.status_message = StatusMessage::from(match key {
+                Key::Char('r') => {
+                    self.document_mut().restore(&recovery);
+                    "Recovered unsaved changes from swap file.".to_string()
+                }
+                Key::Char('x') => {
+                    swap::remove(&recovery.path);
+                    "Swap file discarded.".to_string()
+                }
+                _ if running => {
+                    self.document_mut().read_only = true;
+                    format!("Opened read-only, process {} is editing this file.", recovery.pid)
+                }
+                _ => format!("Swap file kept: {}", recovery.path.display()),
+            });
+            return Ok(());
         }
-        Terminal::cursor_show();
-        Terminal::flush()
     }
 
-    fn draw_status_bar(&self) {
-        let mut status;
-        let width = self.terminal.size.width() as usize;
-        let mut filename = "[untitled]".to_string();
+    pub fn refresh_screen(&mut self) -> Result<(), Error> {
+        if self.quit {
+            Terminal::clear_screen();
+            return Terminal::flush();
+        }
 
-        let modified_indicator = if self.document.is_changed() {
-            " (changed)"
+        self.terminal.set_colors(self.options.colors.unwrap_or_else(Depth::detect));
+        let mut screen = self.terminal.frame();
+        // The tab line only shows up once there is more than one tab
+        let top = if self.tabs.len() > 1 {
+            self.draw_tab_line(&mut screen);
+            1
         } else {
-            ""
+            0
         };
+        let height = screen.height().saturating_sub(top).saturating_sub(1);
+        let width = screen.width();
+        self.layout_mut().arrange(Rect {
+            x: 0,
+            y: top,
+            width,
+            height,
+        });
+        let number = self.options.number;
+        let signs: Vec<bool> = (0..self.documents.len()).map(|index| !self.signs(index).is_empty()).collect();
+        for window in self.tabs.iter_mut().flat_map(|tab| tab.layout.windows.iter_mut()) {
+            let numbers = match self.documents.get(window.document) {
+                Some(document) if number => document.len().max(1).to_string().len().saturating_add(1),
+                _ => 0,
+            };
+            // A column of diagnostic signs, and a space after it
+            let signs = if signs.get(window.document).copied().unwrap_or(false) { 2 } else { 0 };
+            window.gutter = numbers.saturating_add(signs);
+        }
+        self.clamp_cursors();
+
+        let layout = self.layout();
+        for (index, window) in layout.windows.iter().enumerate() {
+            self.draw_window(&mut screen, window, index == layout.focus);
+        }
+        for separator in &layout.separators {
+            for row in separator.y..separator.y.saturating_add(separator.height) {
+                screen.put_str(separator.x, row, "|", self.theme.status_inactive);
+            }
+        }
+        self.draw_completion(&mut screen);
+        self.draw_wildmenu(&mut screen);
+        self.draw_hover(&mut screen);
+        self.draw_message_bar(&mut screen);
+
+        let window = self.window();
+        screen.cursor = Some(Position {
+            x: self.screen_column(window, &window.cur_pos),
+            y: window.rect.y.saturating_add(window.cur_pos.y.saturating_sub(window.offset.y)),
+        });
+        self.terminal.render(screen)
+    }
+
+    fn draw_tab_line(&self, screen: &mut Screen) {
+        let inactive = self.theme.status_inactive;
+        let active = self.theme.status;
+        screen.fill(0, 0, screen.width(), inactive);
+
+        let mut x: usize = 0;
+        for (index, tab) in self.tabs.iter().enumerate() {
+            let label = format!(" {}:{} ", index.saturating_add(1), tab.label(&self.documents));
+            let style = if index == self.tab { active } else { inactive };
+            x = x.saturating_add(screen.put_str(x, 0, &label, style));
+            x = x.saturating_add(screen.put_str(x, 0, "|", inactive));
+        }
+    }
 
-        if let Some(name) = &self.document.filename {
-            filename = name.clone();
-            filename.truncate(20);
+    fn draw_window(&self, screen: &mut Screen, window: &Window, focused: bool) {
+        if let Some(document) = self.documents.get(window.document) {
+            self.draw_lines(screen, window, document, focused);
+            self.draw_status_bar(screen, window, document, focused);
         }
+    }
 
-        status = format!(" {} - {} lines{}", filename, self.document.len(), modified_indicator);
+    fn draw_status_bar(&self, screen: &mut Screen, window: &Window, document: &Document, focused: bool) {
+        let mut status;
+        let width = window.rect.width;
+        let mut filename = document.display_name();
+
+        let modified_indicator = match (document.is_changed(), document.read_only) {
+            (true, true) => " (changed, read-only)",
+            (true, false) => " (changed)",
+            (false, true) => " (read-only)",
+            (false, false) => "",
+        };
+
+        filename.truncate(20);
+
+        status = format!(" {} - {} lines{}", filename, document.len(), modified_indicator);
+        let filetype = filetype::detect(document.filename.as_deref())
+            .map_or_else(String::new, |filetype| format!("{} | ", filetype.name));
         let line_indicator = format!(
-            "{}/{}",
-            self.cur_pos.y.saturating_add(1),
-            self.document.len()
+            "{}{}/{}",
+            filetype,
+            window.cur_pos.y.saturating_add(1),
+            document.len()
         );
 
         #[allow(clippy::integer_arithmetic)]
@@ -167,29 +586,64 @@ impl Editor {
         status = format!("{}{}  ", status, line_indicator);
         status.truncate(width);
 
-        Terminal::set_bg_color(STATUS_BG_COLOR);
-        Terminal::set_fg_color(STATUS_FG_COLOR);
-        println!("{}\r", status);
-        Terminal::reset_bg_color();
-        Terminal::reset_fg_color();
+        let row = window.rect.y.saturating_add(window.text_height());
+        let style = if focused { self.theme.status } else { self.theme.status_inactive };
+        screen.fill(window.rect.x, row, width, style);
+        screen.put_str(window.rect.x, row, &status, style);
     }
 
-    fn draw_message_bar(&self) {
-        Terminal::clear_current_line();
+    // Gutter signs by line: language server diagnostics over quickfix entries
+    fn signs(&self, document: usize) -> HashMap<usize, (char, Style)> {
+        self.quickfix_signs(document)
+            .into_iter()
+            .chain(self.diagnostic_signs(document))
+            .map(|(y, sign, style)| (y, (sign, style)))
+            .collect()
+    }
+
+    fn draw_message_bar(&self, screen: &mut Screen) {
+        let row = screen.height().saturating_sub(1);
+        if !self.pending.is_empty() {
+            let pending = keys::name(&self.pending);
+            let x = screen.width().saturating_sub(pending.len()).saturating_sub(1);
+            screen.put_str(x, row, &pending, self.theme.message);
+        }
+
         let message = &self.status_message;
-        if Instant::now() - message.time < Duration::new(5, 0) {
-            let mut text = message.text.clone();
-            text.truncate(self.terminal.size.width() as usize);
-            print!("{}", text);
+        if message.time.elapsed() < self.options.message_timeout && !message.text.is_empty() {
+            let style = if message.error { self.theme.error } else { self.theme.message };
+            screen.put_str(0, row, &message.text, style);
+        } else if let Some(mode) = self.mode_name() {
+            screen.put_str(0, row, &format!("-- {} --", mode), self.theme.message);
+        } else if let Some((text, style)) = self.diagnostic_message().filter(|_| self.mode == State::Normal) {
+            screen.put_str(0, row, &text, style);
         }
     }
 
-    fn process_insert_keypress(&mut self) -> Result<(), std::io::Error> {
-        let key = Terminal::read_key()?;
+    fn mode_name(&self) -> Option<&'static str> {
+        match (self.mode, self.window().selection.as_ref().map(|selection| selection.kind)) {
+            (State::Insert, _) => Some("INSERT"),
+            (State::Replace, _) => Some("REPLACE"),
+            (State::Visual, Some(SelectionKind::Line)) => Some("VISUAL LINE"),
+            (State::Visual, Some(SelectionKind::Block)) => Some("VISUAL BLOCK"),
+            (State::Visual, _) => Some("VISUAL"),
+            (State::Normal, _) | (State::Prompt, _) => None,
+        }
+    }
 
-        match key {
-            Key::Esc => {
-                if self.quit_times > 0 && self.document.is_changed() {
+    fn process_input(&mut self, input: Input) -> Result<(), Error> {
+        self.close_hover();
+        if self.completion_key(&input) {
+            self.scroll();
+            return Ok(());
+        }
+        // Typing in the word being completed keeps the menu open, anything else but stepping
+        // through it closes it
+        let typing = matches!(input, Input::Key(Key::Char(_)) | Input::Action(Action::Backspace));
+        let stepping = matches!(input, Input::Action(Action::CompleteNext | Action::CompletePrevious));
+        match input {
+            Input::Action(Action::Quit) => {
+                if self.quit_times > 0 && self.documents.iter().any(Document::is_changed) {
                     self.status_message = StatusMessage::from(format!(
                         "WARNING! File has unsaved changes. Press Esc {} more times to quit.",
                         self.quit_times
@@ -201,61 +655,108 @@ impl Editor {
                 }
                 self.quit = true
             }
-            Key::Ctrl('f') => self.search(),
-            Key::Char(c) => {
-                self.document.insert(&self.cur_pos, c);
+            Input::Action(action) => self.perform(action)?,
+            Input::Key(Key::Char('\n')) if matches!(self.mode, State::Insert | State::Replace) => self.newline(),
+            Input::Key(Key::Char('\n')) if self.mode == State::Normal => {
+                self.quickfix_enter();
+            }
+            Input::Key(Key::Char('\t')) if matches!(self.mode, State::Insert | State::Replace) => {
+                let at = self.window().cur_pos.clone();
+                let text = self.tab_text();
+                self.window_mut().cur_pos = self.document_mut().insert_text(&at, &text);
+            }
+            Input::Key(Key::Char(c)) if matches!(self.mode, State::Insert | State::Replace) => {
+                if !self.auto_pair(c) {
+                    let at = self.window().cur_pos.clone();
+                    let overwrite = self.document().line(at.y).map_or(false, |line| at.x < line.len());
+                    if self.mode == State::Replace && overwrite {
+                        self.document_mut().delete(&at);
+                    }
+                    self.document_mut().insert(&at, c);
 
-                self.move_cursor(Key::Right);
-            },
-            Key::Delete => self.document.delete(&self.cur_pos),
-            Key::Backspace => {
-                if self.cur_pos.x > 0 || self.cur_pos.y > 0 {
-                    self.move_cursor(Key::Left);
-                    self.document.delete(&self.cur_pos);
+                    self.move_cursor(Key::Right);
+                    self.dedent_closer(c);
                 }
-            }
-            Key::Ctrl('s') => self.save(),
-            Key::Up
-            | Key::Down
-            | Key::Left
-            | Key::Right
-            | Key::PageUp
-            | Key::PageDown
-            | Key::End
-            | Key::Home=> self.move_cursor(key),
-            _ => (),
+            },
+            Input::Key(_) => (),
+            Input::Paste(text) => self.paste(&text),
         };
+        if !stepping {
+            self.update_completion(typing);
+        }
         self.scroll();
-        if self.quit_times < QUIT_TIMES {
-            self.quit_times = QUIT_TIMES;
+        if self.quit_times < self.options.quit_times {
+            self.quit_times = self.options.quit_times;
             self.status_message = StatusMessage::from(String::new());
         }
         Ok(())
     }
 
-    fn save(&mut self) {
-        if self.document.filename.is_none() || self.document.name {
-            let new_name = self.prompt("Save as: ", |_, _, _| {}, true).unwrap_or(None);
+    // Insert pasted text as it is, in a single undo step of its own
+    fn paste(&mut self, text: &str) {
+        if self.window_mut().selection.take().is_some() {
+            self.mode = State::Normal;
+        }
+        self.documents.iter_mut().for_each(Document::seal);
+        let at = self.window().cur_pos.clone();
+        let end = self.document_mut().insert_text(&at, text);
+        self.document_mut().seal();
+        self.window_mut().cur_pos = end;
+    }
+
+    fn save(&mut self) -> Result<(), Error> {
+        if self.document().filename.is_none() || self.document().name {
+            let new_name = self.prompt("Save as: ", |_, _, _| {}, true, None, Some(Completer::File))?;
             if new_name.is_none() {
                 self.status_message = StatusMessage::from("Save aborted.".to_string());
-                return;
+                return Ok(());
             }
-            self.document.filename = new_name;
-        } 
-
-        if self.document.save().is_ok() {
-            self.status_message = StatusMessage::from(
-                "File saved successfully."
-                    .to_string()
-            );
-        } else {
-            self.status_message = StatusMessage::from("Error writing file!".to_string());
+            return self.write(new_name);
+        }
+        self.write(None)
+    }
+
+    // Write the focused document, under a new name if `filename` is given
+    fn write(&mut self, filename: Option<String>) -> Result<(), Error> {
+        let renamed = filename.is_some() && filename != self.document().filename;
+        if let Some(filename) = filename {
+            self.document_mut().filename = Some(filename);
+            self.document_mut().name = true;
+        }
+
+        if !renamed
+            && self.document().read_only
+            && !self.confirm("WARNING! Another session is editing this file. Write anyway? (y/n)")?
+        {
+            self.status_message = StatusMessage::from("Save aborted.".to_string());
+            return Ok(());
+        }
+        if !renamed
+            && self.document_mut().changed_on_disk()
+            && !self.confirm("WARNING! File changed on disk since it was read. Overwrite? (y/n)")?
+        {
+            self.status_message = StatusMessage::from("Save aborted.".to_string());
+            return Ok(());
+        }
+
+        match self.document_mut().save() {
+            Ok(()) => {
+                self.document_mut().disk_warned = false;
+                let index = self.window().document;
+                self.lsp_saved(index);
+                self.status_message = StatusMessage::from(
+                    "File saved successfully."
+                        .to_string()
+                );
+            }
+            Err(error) => self.report("Error writing file", &error),
         }
+        Ok(())
     }
 
-    fn search(&mut self) {
+    fn search(&mut self) -> Result<(), Error> {
         {
-            let prev_pos = self.cur_pos.clone();
+            let prev_pos = self.window().cur_pos.clone();
             let mut direction = SearchDirection::Forward;
             let query = self.prompt("Search(ESC to cancel, Arrows to navigate): ", |editor, key, query| {
                 let mut moved = false;
@@ -268,50 +769,102 @@ impl Editor {
                     Key::Left | Key::Up => direction = SearchDirection::Backward,
                     _ => direction = SearchDirection::Forward,
                 }
-                if let Some(position) = editor.document.find(&query, &editor.cur_pos, direction) {
-                    editor.cur_pos = position;
+                editor.highlight = Some(query.clone());
+                if let Some(position) = editor.document().find(&query, &editor.window().cur_pos, direction) {
+                    editor.window_mut().cur_pos = position;
                     editor.scroll();
                 } else if moved {
                     editor.move_cursor(Key::Left);
                 }
-            },false).unwrap_or(None);
+            }, false, Some(HistoryKind::Search), None)?;
+            self.highlight = None;
 
-            if query.is_none() {
-                self.cur_pos = prev_pos;
+            if query.is_some() && self.window().cur_pos != prev_pos {
+                self.push_jump(prev_pos);
+            } else if query.is_none() {
+                self.window_mut().cur_pos = prev_pos;
                 self.scroll();
                 self.status_message = StatusMessage::from(format!("Search cancelled"));
             }
         }
+        Ok(())
     }
 
-    fn prompt<C>(&mut self, prompt: &str, mut callback: C, show_name: bool) -> Result<Option<String>, std::io::Error>
+    // With a `history`, what is typed goes into it, and <C-p> and <C-n> bring back older lines;
+    // with a `completer`, <Tab> and <S-Tab> complete it
+    fn prompt<C>(
+        &mut self,
+        prompt: &str,
+        mut callback: C,
+        show_name: bool,
+        history: Option<HistoryKind>,
+        completer: Option<Completer>,
+    ) -> Result<Option<String>, Error>
     where
         C: FnMut(&mut Self, Key, &String),
     {
         let mut result = String::new();
+        let mut recalled: Option<usize> = None; // History entry on show
+        let mut typed = String::new(); // What was typed before going through the history
 
-        if self.document.name && show_name {
-            result = String::from(self.document.filename.as_ref().unwrap());
+        if show_name && self.document().name {
+            if let Some(filename) = &self.document().filename {
+                result = filename.clone();
+            }
         }
         loop {
             self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
             self.refresh_screen()?;
 
-            let key = Terminal::read_key()?;
-            match key {
-                Key::Char('\n') | Key::Ctrl('s') => break,
-                Key::Char(c) => {
-                    if !c.is_control() {
-                        result.push(c);
-                    }
-                },
-                Key::Backspace => result.truncate(result.len().saturating_sub(1)),
-                Key::Esc => {
-                    result.truncate(0);
+            let input = self.next_input(State::Prompt, DISK_CHECK_INTERVAL)?;
+            if !matches!(input, None | Some(Input::Action(Action::CompleteNext | Action::CompletePrevious))) {
+                self.wildmenu = None;
+            }
+            let key = match input {
+                Some(Input::Action(Action::Accept)) => break,
+                Some(Input::Action(Action::Cancel)) => {
+                    self.status_message = StatusMessage::from(String::new());
                     return Ok(None);
                 }
-                _ => (),
-            }
+                Some(Input::Action(Action::Backspace)) => {
+                    result.pop();
+                    Key::Backspace
+                }
+                Some(Input::Key(key)) => {
+                    if let Key::Char(c) = key {
+                        if !c.is_control() {
+                            result.push(c);
+                        }
+                    }
+                    key
+                }
+                Some(Input::Action(action)) if matches!(action, Action::HistoryBack | Action::HistoryForward) => {
+                    if let Some(kind) = history {
+                        if recalled.is_none() {
+                            typed = result.clone();
+                        }
+                        recalled = self.history(kind).step(recalled, action == Action::HistoryBack);
+                        result = match recalled {
+                            Some(index) => self.history(kind).get(index).unwrap_or("").to_string(),
+                            None => typed.clone(),
+                        };
+                    }
+                    Key::Null
+                }
+                Some(Input::Action(action)) if matches!(action, Action::CompleteNext | Action::CompletePrevious) => {
+                    if let Some(completer) = completer {
+                        self.complete_prompt(completer, &mut result, action == Action::CompleteNext);
+                    }
+                    Key::Null
+                }
+                Some(Input::Action(_)) => Key::Null,
+                Some(Input::Paste(text)) => {
+                    // Line breaks would end the prompt
+                    result.push_str(&text.replace('\n', " "));
+                    Key::Null
+                }
+                None => continue,
+            };
             callback(self, key, &result);
         }
 
@@ -319,14 +872,22 @@ impl Editor {
         if result.is_empty() {
             return Ok(None);
         }
+        if let Some(kind) = history {
+            self.history_mut(kind).add(&result);
+        }
         Ok(Some(result))
     }
 
     fn scroll(&mut self) {
-        let Position{ x, y} = self.cur_pos;
-        let width = self.terminal.size.width() as usize;
-        let height = self.terminal.size.height() as usize;
-        let mut offset = &mut self.offset;
+        Self::scroll_window(self.window_mut());
+    }
+
+    // Bring the window's cursor into view
+    fn scroll_window(window: &mut Window) {
+        let Position{ x, y} = window.cur_pos;
+        let width = window.text_width();
+        let height = window.text_height();
+        let offset = &mut window.offset;
 
         if y < offset.y {
             offset.y = y;
@@ -342,10 +903,10 @@ impl Editor {
     }
 
     fn move_cursor(&mut self, key: Key) {
-        let terminal_height = self.terminal.size().height as usize;
-        let Position { mut y, mut x } = self.cur_pos;
-        let height = self.document.len();
-        let mut width = if let Some(line) = self.document.line(y) {
+        let terminal_height = self.window().text_height();
+        let Position { mut y, mut x } = self.window().cur_pos;
+        let height = self.document().len();
+        let mut width = if let Some(line) = self.document().line(y) {
             line.len() + 1
         } else {
             0
@@ -363,7 +924,7 @@ impl Editor {
                     x -= 1;
                 } else if y > 0 {
                     y -= 1;
-                    if let Some(line) = self.document.line(y) {
+                    if let Some(line) = self.document().line(y) {
                         x = line.len();
                     } else {
                         x = 0;
@@ -392,11 +953,11 @@ impl Editor {
                     height
                 }
             },
-            Key::Home => x = 1,
+            Key::Home => x = 0,
             Key::End => x = width,
             _ => (),
         }
-        width = if let Some(line) = self.document.line(y) {
+        width = if let Some(line) = self.document().line(y) {
             line.len()
         } else {
             0
@@ -406,70 +967,133 @@ impl Editor {
             x = width;
         }
 
-        self.cur_pos = Position {x, y};
+        self.window_mut().cur_pos = Position {x, y};
     }
 
-    fn process_welcome(&self) {
-
-        let mut blank = format!("");
-
-        let mut message1 = format!("Sodium - A next generation Vi-like editor");
-        let mut message2 = format!("version {}", VERSION);
-        let mut message3 = format!("By Divith et al.");
-        let mut message4 = format!("Sodium is FOSS :)");
-
-        self.draw_welcome(&mut message1);
-        self.draw_welcome(&mut blank);
-        self.draw_welcome(&mut message2);
-        self.draw_welcome(&mut message3);
-        self.draw_welcome(&mut message4);
+    fn welcome_message() -> Vec<String> {
+        vec![
+            "Sodium - A next generation Vi-like editor".to_string(),
+            String::new(),
+            format!("version {}", VERSION),
+            "By Divith et al.".to_string(),
+            "Sodium is FOSS :)".to_string(),
+        ]
     }
 
-    fn draw_welcome(&self, welcome_message: &mut String) {
-
-        Terminal::clear_current_line();
-        let width = self.terminal.size.width() as usize;
+    fn draw_welcome(&self, screen: &mut Screen, window: &Window, row: usize, welcome_message: &str) {
+        let width = window.text_width();
         let len = welcome_message.len();
         #[allow(clippy::integer_arithmetic, clippy::integer_division)]
         let padding = width.saturating_sub(len) / 2;
         let spaces = " ".repeat(padding.saturating_sub(1));
 
-        let welcome_message = &mut format!("~{}{}", spaces, welcome_message);
+        let mut welcome_message = format!("~{}{}", spaces, welcome_message);
         welcome_message.truncate(width);
-        println!("{}\r", welcome_message);
+        screen.put_str(window.rect.x, row, &welcome_message, self.theme.text);
     }
 
-    fn draw_line(&self, line: &Line) {
-        let width = self.terminal.size.width() as usize;
-        let start = self.offset.x;
-        let end = self.offset.x.saturating_add(width);
+    fn draw_line(&self, screen: &mut Screen, window: &Window, y: usize, row: usize, line: &Line, style: Style) {
+        let start = window.offset.x;
+        let end = window.offset.x.saturating_add(window.text_width());
+        let x = window.rect.x.saturating_add(window.gutter);
+        let tab_width = self.options.tab_width;
+
+        let rendered = line.render(start, end, tab_width);
+        screen.put_str(x, row, &rendered, style);
 
-        let line = line.render(start, end);
-        println!("{}\r", line);
+        let selected = window
+            .selection
+            .as_ref()
+            .zip(self.documents.get(window.document))
+            .and_then(|(selection, document)| selection.columns(&window.cur_pos, y, document, tab_width));
+        if let Some((from, to)) = selected {
+            let (from, to) = (from.max(start), to.min(end));
+            if from < to {
+                let column = line.render(start, from, tab_width).width();
+                let mut text = line.render(from, to, tab_width);
+                // The line break shows up as one selected cell
+                if to > line.len() {
+                    text.push(' ');
+                }
+                screen.put_str(x.saturating_add(column), row, &text, self.theme.selection.or(style));
+            }
+        }
+        let line = rendered;
+
+        let query = self.highlight.as_deref().unwrap_or("");
+        if query.is_empty() {
+            return;
+        }
+        let search = self.theme.search.or(style);
+        for (index, found) in line.match_indices(query) {
+            let column = line[..index].width();
+            screen.put_str(x.saturating_add(column), row, found, search);
+        }
+    }
+
+    // Screen column of `position` in the document shown by `window`, going by how wide the
+    // text before it is drawn
+    pub(super) fn screen_column(&self, window: &Window, position: &Position) -> usize {
+        let before = self
+            .documents
+            .get(window.document)
+            .and_then(|document| document.line(position.y))
+            .map_or(0, |line| line.render(window.offset.x, position.x, self.options.tab_width).width());
+        window.rect.x.saturating_add(window.gutter).saturating_add(before)
+    }
+
+    // Restyle the grapheme of `line` at `x`, if it is in view
+    fn draw_cell(&self, screen: &mut Screen, window: &Window, row: usize, line: &Line, x: usize, style: Style) {
+        let start = window.offset.x;
+        if x < start || x >= start.saturating_add(window.text_width()) {
+            return;
+        }
+        let tab_width = self.options.tab_width;
+        let column = line.render(start, x, tab_width).width();
+        let text = line.render(x, x.saturating_add(1), tab_width);
+        let left = window.rect.x.saturating_add(window.gutter).saturating_add(column);
+        screen.put_str(left, row, &text, style);
     }
 
     #[allow(clippy::integer_division, clippy::integer_arithmetic)]
-    fn draw_lines(&self) {
-        let height = self.terminal.size.height();
+    fn draw_lines(&self, screen: &mut Screen, window: &Window, document: &Document, focused: bool) {
+        let height = window.text_height();
+        let welcome = Self::welcome_message();
+        let text = self.theme.text;
+        let brackets = self.matching_brackets(window, document, focused);
+        let signs = self.signs(window.document);
 
         for term_line in 0..height {
-            Terminal::clear_current_line();
+            let row = window.rect.y + term_line;
+            let y = window.offset.y.saturating_add(term_line);
+            let style = if focused && self.options.cursor_line && y == window.cur_pos.y {
+                self.theme.cursor_line.or(text)
+            } else {
+                text
+            };
+            screen.fill(window.rect.x, row, window.rect.width, style);
 
-            if let Some(line) = self
-                .document
-                .line(self.offset.y.saturating_add(term_line as usize))
+            if let Some(line) = document.line(y) {
+                let columns = if signs.is_empty() { 0 } else { 2 };
+                if let Some((sign, sign_style)) = signs.get(&y) {
+                    screen.put_str(window.rect.x, row, &sign.to_string(), sign_style.or(style));
+                }
+                if window.gutter > columns {
+                    let number = format!("{:>width$} ", y + 1, width = window.gutter - columns - 1);
+                    screen.put_str(window.rect.x + columns, row, &number, self.theme.gutter.or(style));
+                }
+                self.draw_line(screen, window, y, row, line, style);
+                for bracket in brackets.iter().filter(|bracket| bracket.y == y) {
+                    self.draw_cell(screen, window, row, line, bracket.x, self.theme.bracket.or(style));
+                }
+            } else if let Some(message) = welcome
+                .get(term_line.wrapping_sub(height / 3))
+                .filter(|_| self.options.welcome && document.is_empty())
             {
-                self.draw_line(line);
-            } else if self.document.is_empty() && term_line == height/3 {
-                self.process_welcome();
+                self.draw_welcome(screen, window, row, message);
             } else {
-                println!("~\r");
+                screen.put_str(window.rect.x, row, "~", text);
             }
         }
     }
 }
-
-fn die(e: &std::io::Error) {
-    Terminal::clear_screen();
-    panic!("{}", e);
-}
@@ -0,0 +1,160 @@
+use crate::state::object::{sentence_starts, words, Class};
+use crate::state::{Editor, Position};
+use crate::Line;
+
+impl Editor {
+    // Starts (or ends) of the words of line `y`, white space left out
+    fn word_stops(&self, y: usize, big: bool, end: bool) -> Vec<usize> {
+        let text = self.document().line(y).map_or("", Line::as_str);
+        words(text, big)
+            .into_iter()
+            .filter(|(class, _, _)| *class != Class::Space)
+            .map(|(_, first, last)| if end { last } else { first })
+            .collect()
+    }
+
+    // `w` and `W`: the start of the next word; an empty line counts as one
+    pub(super) fn word_forward(&mut self, big: bool) {
+        let Position { x, y } = self.window().cur_pos;
+        if let Some(next) = self.word_stops(y, big, false).into_iter().find(|first| *first > x) {
+            self.window_mut().cur_pos.x = next;
+            return;
+        }
+        for y in y.saturating_add(1)..self.document().len() {
+            let empty = self.document().line(y).map_or(true, Line::is_empty);
+            if let Some(first) = self.word_stops(y, big, false).first().copied().or(if empty { Some(0) } else { None }) {
+                self.window_mut().cur_pos = Position { x: first, y };
+                return;
+            }
+        }
+        self.to_end();
+    }
+
+    // `e` and `E`: the end of this word, or of the next one when already there
+    pub(super) fn word_end(&mut self, big: bool) {
+        let Position { x, y } = self.window().cur_pos;
+        if let Some(last) = self.word_stops(y, big, true).into_iter().find(|last| *last > x) {
+            self.window_mut().cur_pos.x = last;
+            return;
+        }
+        for y in y.saturating_add(1)..self.document().len() {
+            if let Some(last) = self.word_stops(y, big, true).first().copied() {
+                self.window_mut().cur_pos = Position { x: last, y };
+                return;
+            }
+        }
+        self.to_end();
+    }
+
+    // `b` and `B`: the start of this word, or of the one before when already there
+    pub(super) fn word_backward(&mut self, big: bool) {
+        let Position { x, y } = self.window().cur_pos;
+        if let Some(first) = self.word_stops(y, big, false).into_iter().rev().find(|first| *first < x) {
+            self.window_mut().cur_pos.x = first;
+            return;
+        }
+        for y in (0..y).rev() {
+            let empty = self.document().line(y).map_or(true, Line::is_empty);
+            if let Some(first) = self.word_stops(y, big, false).last().copied().or(if empty { Some(0) } else { None }) {
+                self.window_mut().cur_pos = Position { x: first, y };
+                return;
+            }
+        }
+        self.window_mut().cur_pos = Position::default();
+    }
+
+    // Past the last character of the file, so that operators take it in
+    fn to_end(&mut self) {
+        let y = self.document().len().saturating_sub(1);
+        let x = self.document().line(y).map_or(0, Line::len);
+        self.window_mut().cur_pos = Position { x, y };
+    }
+
+    // `)` and `(`: the start of the next or previous sentence; blank lines between paragraphs
+    // are stops of their own
+    pub(super) fn sentence_motion(&mut self, forward: bool) {
+        let cursor = self.window().cur_pos.clone();
+        let last = self.document().len().saturating_sub(1);
+        let (top, bottom) = self.paragraph_bounds(cursor.y);
+        let here = (cursor.y, cursor.x);
+
+        let blank = self.document().line(cursor.y).map_or(true, |line| line.as_str().trim().is_empty());
+        let starts: Vec<Position> = if blank {
+            Vec::new()
+        } else {
+            let chars = self.flatten(top, bottom);
+            sentence_starts(&chars)
+                .into_iter()
+                .filter_map(|index| chars.get(index).map(|(position, _)| position.clone()))
+                .collect()
+        };
+        let found = if forward {
+            starts.into_iter().find(|start| (start.y, start.x) > here)
+        } else {
+            starts.into_iter().rev().find(|start| (start.y, start.x) < here)
+        };
+        if let Some(position) = found {
+            self.window_mut().cur_pos = position;
+            return;
+        }
+
+        let position = match (forward, blank) {
+            // Off the end of a paragraph onto the blank line after it, or from blank lines onto
+            // the first sentence after them
+            (true, _) if bottom >= last => Position {
+                x: self.document().line(last).map_or(0, Line::len),
+                y: last,
+            },
+            (true, false) => Position {
+                x: 0,
+                y: bottom.saturating_add(1),
+            },
+            (true, true) => {
+                let y = bottom.saturating_add(1);
+                Position {
+                    x: self.first_non_blank(y),
+                    y,
+                }
+            }
+            (false, _) if top == 0 => Position::default(),
+            (false, false) => Position {
+                x: 0,
+                y: top.saturating_sub(1),
+            },
+            // From blank lines back to the last sentence of the paragraph before them
+            (false, true) => {
+                let y = top.saturating_sub(1);
+                let (above, _) = self.paragraph_bounds(y);
+                let chars = self.flatten(above, y);
+                sentence_starts(&chars)
+                    .last()
+                    .and_then(|index| chars.get(*index))
+                    .map_or(Position { x: 0, y: above }, |(position, _)| position.clone())
+            }
+        };
+        self.window_mut().cur_pos = position;
+    }
+
+    // `}` and `{`: the next or previous blank line after a paragraph, or either end of the file
+    pub(super) fn paragraph_motion(&mut self, forward: bool) {
+        let y = self.window().cur_pos.y;
+        let len = self.document().len();
+        let blank = |y: usize| self.document().line(y).map_or(true, |line| line.as_str().trim().is_empty());
+        let found = if forward {
+            (y.saturating_add(1)..len).find(|y| blank(*y) && !blank(y.saturating_sub(1)))
+        } else {
+            (0..y).rev().find(|y| blank(*y) && !blank(y.saturating_add(1)))
+        };
+        self.window_mut().cur_pos = match found {
+            Some(y) => Position { x: 0, y },
+            None if forward => {
+                let y = len.saturating_sub(1);
+                Position {
+                    x: self.document().line(y).map_or(0, Line::len),
+                    y,
+                }
+            }
+            None => Position::default(),
+        };
+    }
+}
@@ -0,0 +1,374 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::state::visual::SelectionKind;
+use crate::state::{Editor, Position};
+use crate::Line;
+
+/// How words are told apart: runs of one class make up a word, or a WORD when only white
+/// space separates them.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Class {
+    Space,
+    Word,
+    Punctuation,
+}
+
+/// What `i<c>` or `a<c>` picked: `start` to `end`, both included, or nothing at `start` when
+/// `end` is `None`, like the inside of `()`.
+pub struct Object {
+    pub kind: SelectionKind,
+    pub start: Position,
+    pub end: Option<Position>,
+}
+
+impl Object {
+    fn chars(start: Position, end: Position) -> Self {
+        Self {
+            kind: SelectionKind::Char,
+            start,
+            end: Some(end),
+        }
+    }
+
+    fn lines(top: usize, bottom: usize) -> Self {
+        Self {
+            kind: SelectionKind::Line,
+            start: Position { x: 0, y: top },
+            end: Some(Position { x: 0, y: bottom }),
+        }
+    }
+}
+
+fn class(segment: &str, big: bool) -> Class {
+    if segment.chars().all(char::is_whitespace) {
+        Class::Space
+    } else if big || segment.chars().any(|c| c.is_alphanumeric() || c == '_') {
+        Class::Word
+    } else {
+        Class::Punctuation
+    }
+}
+
+// Runs of graphemes of one class in `text`, as the class and the first and last grapheme.
+// Words end at Unicode word boundaries, and where letters meet punctuation inside them, as
+// in `foo.bar`.
+pub fn words(text: &str, big: bool) -> Vec<(Class, usize, usize)> {
+    let mut runs: Vec<(Class, usize, usize)> = Vec::new();
+    let mut x: usize = 0;
+    for segment in text.split_word_bounds() {
+        let mut boundary = true;
+        for grapheme in segment.graphemes(true) {
+            let class = class(grapheme, big);
+            match runs.last_mut() {
+                Some(run) if run.0 == class && (!boundary || class != Class::Word || big) => run.2 = x,
+                _ => runs.push((class, x, x)),
+            }
+            boundary = false;
+            x = x.saturating_add(1);
+        }
+    }
+    runs
+}
+
+// Where sentences start in `chars`: at the first non-blank, and after white space following
+// `.`, `!` or `?` and any closing brackets and quotes
+pub fn sentence_starts(chars: &[(Position, char)]) -> Vec<usize> {
+    let blank = |index: usize| chars.get(index).map_or(true, |(_, c)| c.is_whitespace());
+    let mut starts = Vec::new();
+    let mut index = (0..chars.len()).find(|index| !blank(*index)).unwrap_or(chars.len());
+    while index < chars.len() {
+        starts.push(index);
+        let mut end = index;
+        loop {
+            end = match (end..chars.len()).find(|end| matches!(chars.get(*end), Some((_, '.')) | Some((_, '!')) | Some((_, '?')))) {
+                Some(end) => end.saturating_add(1),
+                None => chars.len(),
+            };
+            while matches!(chars.get(end), Some((_, ')')) | Some((_, ']')) | Some((_, '"')) | Some((_, '\''))) {
+                end = end.saturating_add(1);
+            }
+            if blank(end) {
+                break;
+            }
+        }
+        index = (end..chars.len()).find(|index| !blank(*index)).unwrap_or(chars.len());
+    }
+    starts
+}
+
+impl Editor {
+    // The object named by `c` around the cursor; `around` takes in the white space or
+    // delimiters around it
+    pub(super) fn text_object(&self, around: bool, c: char) -> Option<Object> {
+        match c {
+            'w' | 'W' => self.word_object(around, c == 'W'),
+            's' => self.sentence_object(around),
+            'p' => self.paragraph_object(around),
+            '"' | '\'' | '`' => self.quote_object(around, c),
+            '(' | ')' | 'b' => self.bracket_object(around, '(', ')'),
+            '[' | ']' => self.bracket_object(around, '[', ']'),
+            '{' | '}' | 'B' => self.bracket_object(around, '{', '}'),
+            '<' | '>' => self.bracket_object(around, '<', '>'),
+            't' => self.tag_object(around),
+            _ => None,
+        }
+    }
+
+    // Graphemes of lines `top` to `bottom` by their first character and position, each line
+    // followed by a `\n` one past its end
+    pub(super) fn flatten(&self, top: usize, bottom: usize) -> Vec<(Position, char)> {
+        let mut chars = Vec::new();
+        for y in top..=bottom.min(self.document().len().saturating_sub(1)) {
+            let text = self.document().line(y).map_or("", Line::as_str);
+            let mut x: usize = 0;
+            for grapheme in text.graphemes(true) {
+                chars.push((Position { x, y }, grapheme.chars().next().unwrap_or(' ')));
+                x = x.saturating_add(1);
+            }
+            chars.push((Position { x, y }, '\n'));
+        }
+        chars
+    }
+
+    fn word_object(&self, around: bool, big: bool) -> Option<Object> {
+        let Position { x, y } = self.window().cur_pos;
+        let runs = words(self.document().line(y)?.as_str(), big);
+        let index = runs
+            .iter()
+            .position(|(_, _, last)| *last >= x)
+            .or_else(|| runs.len().checked_sub(1))?;
+        let (class, mut first, mut last) = *runs.get(index)?;
+        if around {
+            let next = runs.get(index.saturating_add(1));
+            let previous = index.checked_sub(1).and_then(|index| runs.get(index));
+            match (class, next, previous) {
+                // White space goes with the word after it, a word with the white space after it
+                // or failing that before it
+                (Class::Space, Some(next), _) => last = next.2,
+                (_, Some(next), _) if next.0 == Class::Space => last = next.2,
+                (_, _, Some(previous)) if previous.0 == Class::Space => first = previous.1,
+                _ => (),
+            }
+        }
+        Some(Object::chars(Position { x: first, y }, Position { x: last, y }))
+    }
+
+    fn sentence_object(&self, around: bool) -> Option<Object> {
+        let cursor = self.window().cur_pos.clone();
+        let (top, bottom) = self.paragraph_bounds(cursor.y);
+        let chars = self.flatten(top, bottom);
+        let starts = sentence_starts(&chars);
+        let index = chars
+            .iter()
+            .position(|(position, _)| (position.y, position.x) >= (cursor.y, cursor.x))
+            .unwrap_or(0);
+        let sentence = starts.iter().rposition(|start| *start <= index).unwrap_or(0);
+        let start = *starts.get(sentence)?;
+        let next = starts.get(sentence.saturating_add(1)).copied();
+        let end = match next {
+            Some(next) if around => next.saturating_sub(1),
+            _ => {
+                let limit = next.unwrap_or(chars.len());
+                (start..limit).rev().find(|index| chars.get(*index).map_or(false, |(_, c)| !c.is_whitespace()))?
+            }
+        };
+        Some(Object::chars(chars.get(start)?.0.clone(), chars.get(end)?.0.clone()))
+    }
+
+    fn blank(&self, y: usize) -> bool {
+        self.document().line(y).map_or(true, |line| line.as_str().trim().is_empty())
+    }
+
+    // First and last lines of the run of blank or non-blank lines around line `y`
+    pub(super) fn paragraph_bounds(&self, y: usize) -> (usize, usize) {
+        let blank = self.blank(y);
+        let mut top = y;
+        while top > 0 && self.blank(top.saturating_sub(1)) == blank {
+            top = top.saturating_sub(1);
+        }
+        let mut bottom = y;
+        while bottom.saturating_add(1) < self.document().len() && self.blank(bottom.saturating_add(1)) == blank {
+            bottom = bottom.saturating_add(1);
+        }
+        (top, bottom)
+    }
+
+    fn paragraph_object(&self, around: bool) -> Option<Object> {
+        let y = self.window().cur_pos.y;
+        let (mut top, mut bottom) = self.paragraph_bounds(y);
+        if around {
+            // With the blank lines after the paragraph, or before it at the end of the file
+            if bottom.saturating_add(1) < self.document().len() {
+                bottom = self.paragraph_bounds(bottom.saturating_add(1)).1;
+            } else if top > 0 && !self.blank(y) {
+                top = self.paragraph_bounds(top.saturating_sub(1)).0;
+            }
+        }
+        Some(Object::lines(top, bottom))
+    }
+
+    // Quotes pair up from the start of the line; the pair around the cursor, or the next one
+    fn quote_object(&self, around: bool, quote: char) -> Option<Object> {
+        let Position { x, y } = self.window().cur_pos;
+        let chars: Vec<char> = self.flatten(y, y).into_iter().map(|(_, c)| c).collect();
+        let mut quotes = Vec::new();
+        let mut escaped = false;
+        for (index, c) in chars.iter().enumerate() {
+            if *c == quote && !escaped {
+                quotes.push(index);
+            }
+            escaped = *c == '\\' && !escaped;
+        }
+        let pairs: Vec<(usize, usize)> = quotes.chunks_exact(2).filter_map(|pair| Some((*pair.first()?, *pair.get(1)?))).collect();
+        let (open, close) = pairs
+            .iter()
+            .find(|(open, close)| *open <= x && x <= *close)
+            .or_else(|| pairs.iter().find(|(open, _)| *open > x))
+            .copied()?;
+
+        if !around {
+            return Some(if close > open.saturating_add(1) {
+                Object::chars(Position { x: open.saturating_add(1), y }, Position { x: close.saturating_sub(1), y })
+            } else {
+                Object {
+                    kind: SelectionKind::Char,
+                    start: Position { x: close, y },
+                    end: None,
+                }
+            });
+        }
+        let space = |index: usize| chars.get(index).map_or(false, |c| *c == ' ' || *c == '\t');
+        let mut first = open;
+        let mut last = close;
+        while space(last.saturating_add(1)) {
+            last = last.saturating_add(1);
+        }
+        if last == close {
+            while first > 0 && space(first.saturating_sub(1)) {
+                first = first.saturating_sub(1);
+            }
+        }
+        Some(Object::chars(Position { x: first, y }, Position { x: last, y }))
+    }
+
+    fn bracket_object(&self, around: bool, open: char, close: char) -> Option<Object> {
+        let cursor = self.window().cur_pos.clone();
+        let document = self.document();
+        let under = document
+            .line(cursor.y)
+            .and_then(|line| line.slice(cursor.x, cursor.x.saturating_add(1)).chars().next());
+        let opener = if under == Some(open) {
+            cursor
+        } else {
+            document.find_opener(&cursor, open, close)?
+        };
+        let closer = document.find_closer(&opener, open, close)?;
+        if around {
+            return Some(Object::chars(opener, closer));
+        }
+
+        // A block whose brackets end and start their lines is taken as the lines in between
+        let line_len = |y: usize| document.line(y).map_or(0, Line::len);
+        let after_opener = document.line(opener.y).map_or("", |line| line.slice(opener.x.saturating_add(1), line.len()));
+        let before_closer = document.line(closer.y).map_or("", |line| line.slice(0, closer.x));
+        if closer.y > opener.y && after_opener.trim().is_empty() && before_closer.trim().is_empty() {
+            return Some(if closer.y > opener.y.saturating_add(1) {
+                Object::lines(opener.y.saturating_add(1), closer.y.saturating_sub(1))
+            } else {
+                Object {
+                    kind: SelectionKind::Char,
+                    start: closer,
+                    end: None,
+                }
+            });
+        }
+
+        let start = if opener.x < line_len(opener.y) {
+            Position {
+                x: opener.x.saturating_add(1),
+                y: opener.y,
+            }
+        } else {
+            Position {
+                x: 0,
+                y: opener.y.saturating_add(1),
+            }
+        };
+        if start == closer {
+            return Some(Object {
+                kind: SelectionKind::Char,
+                start,
+                end: None,
+            });
+        }
+        let end = match closer.x.checked_sub(1) {
+            Some(x) => Position { x, y: closer.y },
+            None => {
+                let y = closer.y.saturating_sub(1);
+                Position { x: line_len(y), y }
+            }
+        };
+        Some(Object::chars(start, end))
+    }
+
+    // Tags pair up like brackets, by name; the innermost pair around the cursor
+    fn tag_object(&self, around: bool) -> Option<Object> {
+        let cursor = self.window().cur_pos.clone();
+        let chars = self.flatten(0, self.document().len().saturating_sub(1));
+        let at = |index: usize| chars.get(index).map(|(_, c)| *c);
+        let here = chars
+            .iter()
+            .position(|(position, _)| (position.y, position.x) >= (cursor.y, cursor.x))
+            .unwrap_or(0);
+
+        // (name, where `<` is, where `>` is)
+        let mut open: Vec<(String, usize, usize)> = Vec::new();
+        let mut best: Option<(usize, usize, usize, usize)> = None;
+        let mut index: usize = 0;
+        while index < chars.len() {
+            if at(index) != Some('<') {
+                index = index.saturating_add(1);
+                continue;
+            }
+            let closing = at(index.saturating_add(1)) == Some('/');
+            let name_start = if closing { index.saturating_add(2) } else { index.saturating_add(1) };
+            let name: String = (name_start..chars.len())
+                .map_while(|index| at(index).filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.')))
+                .collect();
+            let end = match (name_start..chars.len()).find(|index| at(*index) == Some('>')) {
+                Some(end) if !name.is_empty() => end,
+                _ => {
+                    index = index.saturating_add(1);
+                    continue;
+                }
+            };
+            if !closing && at(end.saturating_sub(1)) != Some('/') {
+                open.push((name, index, end));
+            } else if closing {
+                if let Some(pair) = open.iter().rposition(|(open, _, _)| *open == name) {
+                    let (_, start, start_end) = open.get(pair)?.clone();
+                    open.truncate(pair);
+                    let inner = best.map_or(true, |(best, _, _, _)| start > best);
+                    if start <= here && here <= end && inner {
+                        best = Some((start, start_end, index, end));
+                    }
+                }
+            }
+            index = end.saturating_add(1);
+        }
+
+        let (start, start_end, end_start, end) = best?;
+        let position = |index: usize| chars.get(index).map(|(position, _)| position.clone());
+        if around {
+            return Some(Object::chars(position(start)?, position(end)?));
+        }
+        if start_end.saturating_add(1) == end_start {
+            return Some(Object {
+                kind: SelectionKind::Char,
+                start: position(end_start)?,
+                end: None,
+            });
+        }
+        Some(Object::chars(position(start_end.saturating_add(1))?, position(end_start.saturating_sub(1))?))
+    }
+}
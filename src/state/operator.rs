@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use termion::event::Key;
+
+use crate::error::Error;
+use crate::state::keymap::{Action, Input};
+use crate::state::object::Object;
+use crate::state::visual::{Selection, SelectionKind};
+use crate::state::{Editor, Position, State};
+use crate::Line;
+
+// What a motion after an operator covers
+enum Reach {
+    Lines,     // Every line from the cursor to where the motion went
+    Exclusive, // Up to where the motion went, not including it
+    Inclusive, // Up to and including where the motion went
+}
+
+fn reach(motion: Action) -> Option<Reach> {
+    match motion {
+        Action::Up
+        | Action::Down
+        | Action::PageUp
+        | Action::PageDown
+        | Action::Top
+        | Action::Bottom
+        | Action::JumpToMarkLine => Some(Reach::Lines),
+        Action::Left
+        | Action::Right
+        | Action::Home
+        | Action::WordForward
+        | Action::WordBackward
+        | Action::BigWordForward
+        | Action::BigWordBackward
+        | Action::SentenceForward
+        | Action::SentenceBackward
+        | Action::ParagraphForward
+        | Action::ParagraphBackward
+        | Action::JumpToMark => Some(Reach::Exclusive),
+        Action::End | Action::WordEnd | Action::BigWordEnd | Action::MatchBracket => Some(Reach::Inclusive),
+        _ => None,
+    }
+}
+
+// Typing an operator's last key again makes it work on the current line, as in `dd` or `gqq`
+fn line_key(operator: Action) -> Option<char> {
+    match operator {
+        Action::Delete => Some('d'),
+        Action::Yank => Some('y'),
+        Action::Change => Some('c'),
+        Action::Indent => Some('>'),
+        Action::Outdent => Some('<'),
+        Action::Reindent => Some('='),
+        Action::Reflow => Some('q'),
+        Action::Lowercase => Some('u'),
+        Action::Uppercase => Some('U'),
+        _ => None,
+    }
+}
+
+impl Editor {
+    // In normal mode an operator waits for what to work on: a motion, `i` or `a` and a text
+    // object, or its own key again for the current line
+    pub(super) fn pending_operator(&mut self, operator: Action) -> Result<(), Error> {
+        let line_key = match line_key(operator) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let cursor = self.window().cur_pos.clone();
+        let target = match self.next_key()? {
+            Key::Char(c) if c == line_key => Some((cursor.clone(), cursor.clone(), SelectionKind::Line)),
+            Key::Char(c) if c == 'i' || c == 'a' => match self.next_key()? {
+                Key::Char(name) => match self.text_object(c == 'a', name) {
+                    Some(Object {
+                        kind,
+                        start,
+                        end: Some(end),
+                    }) => Some((start, end, kind)),
+                    // Nothing inside, but a change still starts typing there
+                    Some(Object { start, end: None, .. }) => {
+                        self.window_mut().cur_pos = start;
+                        if operator == Action::Change {
+                            self.mode = State::Insert;
+                        }
+                        None
+                    }
+                    None => None,
+                },
+                _ => None,
+            },
+            key => {
+                self.typeahead.push_front((key, true));
+                let motion = match self.next_input(State::Normal, Duration::from_secs(0))? {
+                    Some(Input::Action(motion)) => self.change_word(operator, motion),
+                    _ => Action::Nop,
+                };
+                match reach(motion) {
+                    Some(reach) => {
+                        let document = self.window().document;
+                        self.perform(motion)?;
+                        // A mark in another file takes us there instead
+                        if self.window().document != document {
+                            self.register = None;
+                            return Ok(());
+                        }
+                        let target = self.motion_target(&cursor, reach);
+                        self.window_mut().cur_pos = cursor.clone();
+                        target
+                    }
+                    None => None,
+                }
+            }
+        };
+
+        match target {
+            Some((start, end, kind)) => {
+                self.window_mut().selection = Some(Selection { kind, anchor: start });
+                self.window_mut().cur_pos = end;
+                self.operate(operator)
+            }
+            None => {
+                self.register = None;
+                Ok(())
+            }
+        }
+    }
+
+    // `cw` on a word changes to its end, leaving the white space after it alone, like `ce`
+    fn change_word(&self, operator: Action, motion: Action) -> Action {
+        let Position { x, y } = self.window().cur_pos;
+        let on_word = self
+            .document()
+            .line(y)
+            .map_or(false, |line| !line.slice(x, x.saturating_add(1)).trim().is_empty());
+        match motion {
+            Action::WordForward if operator == Action::Change && on_word => Action::WordEnd,
+            Action::BigWordForward if operator == Action::Change && on_word => Action::BigWordEnd,
+            _ => motion,
+        }
+    }
+
+    // The text between `from` and the cursor, after a motion took it there
+    fn motion_target(&self, from: &Position, reach: Reach) -> Option<(Position, Position, SelectionKind)> {
+        let to = self.window().cur_pos.clone();
+        let (start, end) = if (to.y, to.x) < (from.y, from.x) {
+            (to, from.clone())
+        } else {
+            (from.clone(), to)
+        };
+        let line_len = |y: usize| self.document().line(y).map_or(0, Line::len);
+        let end = match reach {
+            Reach::Lines => return Some((start, end, SelectionKind::Line)),
+            Reach::Inclusive if line_len(end.y) == 0 => return None,
+            Reach::Inclusive => Position {
+                x: end.x.min(line_len(end.y).saturating_sub(1)),
+                y: end.y,
+            },
+            // One that ends at the start of a later line stops at the end of the one before
+            Reach::Exclusive => match end.x.checked_sub(1) {
+                Some(x) => Position { x, y: end.y },
+                None if end.y > start.y => {
+                    let y = end.y.saturating_sub(1);
+                    Position {
+                        x: line_len(y).saturating_sub(1),
+                        y,
+                    }
+                }
+                None => return None,
+            },
+        };
+        if (end.y, end.x) < (start.y, start.x) {
+            return None;
+        }
+        Some((start, end, SelectionKind::Char))
+    }
+
+    // `i` or `a` in visual mode: select the text object named by the next key
+    pub(super) fn select_object(&mut self, around: bool) -> Result<(), Error> {
+        let name = match self.next_key()? {
+            Key::Char(name) => name,
+            _ => return Ok(()),
+        };
+        if let Some(Object {
+            kind,
+            start,
+            end: Some(end),
+        }) = self.text_object(around, name)
+        {
+            self.window_mut().selection = Some(Selection { kind, anchor: start });
+            self.window_mut().cur_pos = end;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use std::time::Duration;
+
+use crate::state::quickfix::{DEFAULT_ERRORFORMAT, DEFAULT_MAKEPRG};
+use crate::terminal::Depth;
+
+/// Settings changed with `set`, `unset` and `toggle`, from the prompt or the config file.
+pub struct Options {
+    pub tab_width: usize,
+    pub quit_times: u8, // Extra Esc presses needed to quit with unsaved changes
+    pub message_timeout: Duration,
+    pub welcome: bool, // Show the welcome message in empty buffers
+    pub map_timeout: Duration, // How long to wait for the rest of an ambiguous key sequence
+    pub cursor_line: bool, // Highlight the line the cursor is on
+    pub number: bool, // Line numbers in the gutter
+    pub colors: Option<Depth>, // Detected from the environment when not set
+    pub clipboard_copy: Option<String>, // Shell command given the text for `"+`, instead of OSC 52
+    pub clipboard_paste: Option<String>, // Shell command printing the clipboard
+    pub autoindent: bool, // New lines start with the indentation of the previous one
+    pub smartindent: bool, // ...plus a level after an opening bracket, depending on the filetype
+    pub expandtab: bool, // Indent with spaces rather than tabs
+    pub shiftwidth: usize, // Columns per indentation level, 0 for `tab_width`
+    pub textwidth: usize, // Where `reflow` wraps lines
+    pub match_brackets: bool, // Highlight the bracket matching the one at the cursor
+    pub autopairs: bool, // Type closing brackets and quotes along with opening ones
+    pub lsp: bool, // Run language servers for the open files
+    pub makeprg: String, // Shell command run by `make`
+    pub errorformat: String, // Comma separated patterns for lines of `make` output
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            quit_times: 1,
+            message_timeout: Duration::from_secs(5),
+            welcome: true,
+            map_timeout: Duration::from_millis(1000),
+            cursor_line: false,
+            number: false,
+            colors: None,
+            clipboard_copy: None,
+            clipboard_paste: None,
+            autoindent: true,
+            smartindent: true,
+            expandtab: false,
+            shiftwidth: 0,
+            textwidth: 79,
+            match_brackets: true,
+            autopairs: false,
+            lsp: false,
+            makeprg: DEFAULT_MAKEPRG.to_string(),
+            errorformat: DEFAULT_ERRORFORMAT.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Name {
+    TabWidth,
+    QuitTimes,
+    MessageTimeout,
+    Welcome,
+    MapTimeout,
+    CursorLine,
+    Number,
+    Colors,
+    ClipboardCopy,
+    ClipboardPaste,
+    Autoindent,
+    Smartindent,
+    Expandtab,
+    Shiftwidth,
+    Textwidth,
+    MatchBrackets,
+    Autopairs,
+    Lsp,
+    Makeprg,
+    Errorformat,
+}
+
+// Full names first, they are what `get` shows
+const NAMES: &[(&str, Name)] = &[
+    ("tab_width", Name::TabWidth),
+    ("ts", Name::TabWidth),
+    ("quit_times", Name::QuitTimes),
+    ("message_timeout", Name::MessageTimeout),
+    ("welcome", Name::Welcome),
+    ("map_timeout", Name::MapTimeout),
+    ("cursor_line", Name::CursorLine),
+    ("cul", Name::CursorLine),
+    ("number", Name::Number),
+    ("nu", Name::Number),
+    ("colors", Name::Colors),
+    ("clipboard_copy", Name::ClipboardCopy),
+    ("clipboard_paste", Name::ClipboardPaste),
+    ("autoindent", Name::Autoindent),
+    ("ai", Name::Autoindent),
+    ("smartindent", Name::Smartindent),
+    ("si", Name::Smartindent),
+    ("expandtab", Name::Expandtab),
+    ("et", Name::Expandtab),
+    ("shiftwidth", Name::Shiftwidth),
+    ("sw", Name::Shiftwidth),
+    ("textwidth", Name::Textwidth),
+    ("tw", Name::Textwidth),
+    ("match_brackets", Name::MatchBrackets),
+    ("mb", Name::MatchBrackets),
+    ("autopairs", Name::Autopairs),
+    ("ap", Name::Autopairs),
+    ("lsp", Name::Lsp),
+    ("makeprg", Name::Makeprg),
+    ("mp", Name::Makeprg),
+    ("errorformat", Name::Errorformat),
+    ("efm", Name::Errorformat),
+];
+
+fn lookup(name: &str) -> Result<Name, String> {
+    NAMES
+        .iter()
+        .find(|(option, _)| *option == name)
+        .map(|(_, option)| *option)
+        .ok_or_else(|| format!("Unknown option: {}", name))
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got `{}`", name, value))
+}
+
+fn flag(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => Err(format!("{} expects on or off, got `{}`", name, value)),
+    }
+}
+
+fn on_off(flag: bool) -> String {
+    (if flag { "on" } else { "off" }).to_string()
+}
+
+impl Options {
+    // `set name=value`, or `set name` to switch a flag on
+    pub fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (name, value) = match setting.find('=') {
+            Some(index) => (setting[..index].trim(), Some(setting[index.saturating_add(1)..].trim())),
+            None => (setting.trim(), None),
+        };
+        let option = lookup(name)?;
+        let value = match (value, option) {
+            (Some(value), _) => value,
+            (None, option) if self.flag_value(option).is_some() => "on",
+            (None, _) => return Err(format!("Usage: set {}=<value>", name)),
+        };
+
+        match option {
+            Name::TabWidth => {
+                let width: usize = number(name, value)?;
+                if width == 0 {
+                    return Err(format!("{} must be at least 1", name));
+                }
+                self.tab_width = width;
+            }
+            Name::QuitTimes => self.quit_times = number(name, value)?,
+            Name::Shiftwidth => self.shiftwidth = number(name, value)?,
+            Name::Textwidth => {
+                let width: usize = number(name, value)?;
+                if width == 0 {
+                    return Err(format!("{} must be at least 1", name));
+                }
+                self.textwidth = width;
+            }
+            Name::MessageTimeout => self.message_timeout = Duration::from_secs(number(name, value)?),
+            Name::Welcome
+            | Name::CursorLine
+            | Name::Number
+            | Name::Autoindent
+            | Name::Smartindent
+            | Name::Expandtab
+            | Name::MatchBrackets
+            | Name::Autopairs
+            | Name::Lsp => *self.flag_mut(name)? = flag(name, value)?,
+            Name::MapTimeout => self.map_timeout = Duration::from_millis(number(name, value)?),
+            Name::Colors if value == "auto" => self.colors = None,
+            Name::Colors => {
+                let depth = Depth::parse(value)
+                    .ok_or_else(|| format!("{} expects auto, truecolor, 256 or 16, got `{}`", name, value))?;
+                self.colors = Some(depth);
+            }
+            // Empty to go back to the default
+            Name::ClipboardCopy => self.clipboard_copy = Some(value.to_string()).filter(|value| !value.is_empty()),
+            Name::ClipboardPaste => self.clipboard_paste = Some(value.to_string()).filter(|value| !value.is_empty()),
+            Name::Makeprg if value.is_empty() => self.makeprg = DEFAULT_MAKEPRG.to_string(),
+            Name::Makeprg => self.makeprg = value.to_string(),
+            Name::Errorformat if value.is_empty() => self.errorformat = DEFAULT_ERRORFORMAT.to_string(),
+            Name::Errorformat => self.errorformat = value.to_string(),
+        }
+        Ok(())
+    }
+
+    // Full names of the options, or of the on/off ones only, for Tab completion
+    pub fn names(&self, flags: bool) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = Vec::new();
+        let mut seen: Vec<Name> = Vec::new();
+        for (name, option) in NAMES {
+            if !seen.contains(option) && (!flags || self.flag_value(*option).is_some()) {
+                names.push(name);
+            }
+            seen.push(*option);
+        }
+        names
+    }
+
+    pub fn unset(&mut self, name: &str) -> Result<(), String> {
+        *self.flag_mut(name)? = false;
+        Ok(())
+    }
+
+    pub fn toggle(&mut self, name: &str) -> Result<(), String> {
+        let flag = self.flag_mut(name)?;
+        *flag = !*flag;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<String, String> {
+        let option = lookup(name)?;
+        let value = match option {
+            Name::TabWidth => self.tab_width.to_string(),
+            Name::QuitTimes => self.quit_times.to_string(),
+            Name::Shiftwidth => self.shiftwidth.to_string(),
+            Name::Textwidth => self.textwidth.to_string(),
+            Name::MessageTimeout => self.message_timeout.as_secs().to_string(),
+            Name::Welcome
+            | Name::CursorLine
+            | Name::Number
+            | Name::Autoindent
+            | Name::Smartindent
+            | Name::Expandtab
+            | Name::MatchBrackets
+            | Name::Autopairs
+            | Name::Lsp => on_off(self.flag_value(option).unwrap_or_default()),
+            Name::MapTimeout => self.map_timeout.as_millis().to_string(),
+            Name::Colors => self.colors.map_or("auto", Depth::name).to_string(),
+            Name::ClipboardCopy => self.clipboard_copy.clone().unwrap_or_default(),
+            Name::ClipboardPaste => self.clipboard_paste.clone().unwrap_or_default(),
+            Name::Makeprg => self.makeprg.clone(),
+            Name::Errorformat => self.errorformat.clone(),
+        };
+        Ok(format!("{}={}", name, value))
+    }
+
+    fn flag_value(&self, option: Name) -> Option<bool> {
+        match option {
+            Name::Welcome => Some(self.welcome),
+            Name::CursorLine => Some(self.cursor_line),
+            Name::Number => Some(self.number),
+            Name::Autoindent => Some(self.autoindent),
+            Name::Smartindent => Some(self.smartindent),
+            Name::Expandtab => Some(self.expandtab),
+            Name::MatchBrackets => Some(self.match_brackets),
+            Name::Autopairs => Some(self.autopairs),
+            Name::Lsp => Some(self.lsp),
+            _ => None,
+        }
+    }
+
+    fn flag_mut(&mut self, name: &str) -> Result<&mut bool, String> {
+        match lookup(name)? {
+            Name::Welcome => Ok(&mut self.welcome),
+            Name::CursorLine => Ok(&mut self.cursor_line),
+            Name::Number => Ok(&mut self.number),
+            Name::Autoindent => Ok(&mut self.autoindent),
+            Name::Smartindent => Ok(&mut self.smartindent),
+            Name::Expandtab => Ok(&mut self.expandtab),
+            Name::MatchBrackets => Ok(&mut self.match_brackets),
+            Name::Autopairs => Ok(&mut self.autopairs),
+            Name::Lsp => Ok(&mut self.lsp),
+            _ => Err(format!("{} is not an on/off option", name)),
+        }
+    }
+}
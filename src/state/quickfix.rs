@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::error::Error;
+use crate::state::session::{absolute, relative};
+use crate::state::{Editor, Position, StatusMessage};
+use crate::terminal::Style;
+use crate::{Document, Line};
+
+// Tried in order on the lines of output not in the rustc format; see `match_format`
+pub const DEFAULT_ERRORFORMAT: &str = "%f:%l:%c: %t: %m,%f:%l:%c: %m,%f:%l: %m,%f:%l:%m";
+pub const DEFAULT_MAKEPRG: &str = "cargo build";
+
+/// A place in a file the quickfix list points at, and what was said about it.
+pub struct Entry {
+    pub path: PathBuf, // Absolute
+    pub line: usize,
+    pub column: usize,
+    pub kind: Option<char>, // E for errors, W for warnings...
+    pub text: String,
+}
+
+/// Locations collected from compiler or grep output, gone through with `cnext` and
+/// `cprevious` and listed by `copen`.
+#[derive(Default)]
+pub struct Quickfix {
+    entries: Vec<Entry>,
+    current: Option<usize>,
+}
+
+#[derive(Default)]
+struct Fields<'a> {
+    file: &'a str,
+    line: &'a str,
+    column: &'a str,
+    kind: &'a str,
+    message: &'a str,
+}
+
+impl Fields<'_> {
+    fn entry(&self, kind: Option<char>, text: &str) -> Option<Entry> {
+        let line: usize = self.line.parse().ok()?;
+        Some(Entry {
+            path: absolute(self.file),
+            line: line.saturating_sub(1),
+            column: self.column.parse::<usize>().map_or(0, |column| column.saturating_sub(1)),
+            kind: kind.or_else(|| self.kind.chars().next().map(|c| c.to_ascii_uppercase())),
+            text: text.trim().to_string(),
+        })
+    }
+}
+
+// Whether all of `text` matches `pattern`, an `errorformat` item: %f is a file name, %l a line
+// and %c a column number, %t a word whose first letter is the kind of entry (error, warning),
+// %m the message and %% a percent sign. Anything else stands for itself.
+fn match_format<'a>(pattern: &str, text: &'a str, fields: &mut Fields<'a>) -> bool {
+    let mut chars = pattern.chars();
+    let field = match chars.next() {
+        None => return text.is_empty(),
+        Some('%') => match chars.next() {
+            Some('%') | None => None,
+            Some(field) => Some(field),
+        },
+        Some(_) => None,
+    };
+    let rest = chars.as_str();
+    let field = match field {
+        Some(field) => field,
+        None => {
+            let literal = pattern.get(..pattern.len().saturating_sub(rest.len())).unwrap_or("");
+            let literal = if literal == "%%" { "%" } else { literal };
+            return match text.strip_prefix(literal) {
+                Some(text) => match_format(rest, text, fields),
+                None => false,
+            };
+        }
+    };
+
+    // Where the field could end: numbers and words as long as possible first, file names and
+    // messages as short as possible
+    let run = match field {
+        'l' | 'c' => text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len()),
+        't' => text.find(|c: char| !c.is_alphabetic()).unwrap_or(text.len()),
+        _ => text.len(),
+    };
+    let mut ends: Vec<usize> = (1..=run).filter(|end| text.is_char_boundary(*end)).collect();
+    if matches!(field, 'l' | 'c' | 't') {
+        ends.reverse();
+    }
+    for end in ends {
+        let (value, tail) = text.split_at(end);
+        if field == 'f' && value.starts_with(char::is_whitespace) {
+            return false;
+        }
+        if match_format(rest, tail, fields) {
+            match field {
+                'f' => fields.file = value,
+                'l' => fields.line = value,
+                'c' => fields.column = value,
+                't' => fields.kind = value,
+                _ => fields.message = value,
+            }
+            return true;
+        }
+    }
+    false
+}
+
+// rustc and cargo give the message on one line and where it is on a later one:
+//     error[E0308]: mismatched types
+//       --> src/main.rs:4:18
+fn rust_header(line: &str) -> Option<(char, &str)> {
+    for (word, kind) in &[("error", 'E'), ("warning", 'W'), ("note", 'N'), ("help", 'H')] {
+        if let Some(rest) = line.strip_prefix(word) {
+            let rest = match rest.strip_prefix('[') {
+                Some(code) => code.get(code.find(']')?.saturating_add(1)..)?,
+                None => rest,
+            };
+            return rest.strip_prefix(':').map(|message| (*kind, message));
+        }
+    }
+    None
+}
+
+// The entries in compiler or grep output, in the rustc format or one of the comma separated
+// `errorformat` patterns
+pub fn parse(output: &str, errorformat: &str) -> Vec<Entry> {
+    let patterns: Vec<&str> = errorformat.split(',').filter(|pattern| !pattern.is_empty()).collect();
+    let mut header: Option<(char, &str)> = None;
+    let mut entries = Vec::new();
+    for line in output.lines() {
+        if let Some(found) = rust_header(line) {
+            header = Some(found);
+            continue;
+        }
+        if let Some(location) = line.trim_start().strip_prefix("--> ") {
+            let mut fields = Fields::default();
+            if let Some((kind, message)) = header.take() {
+                if match_format("%f:%l:%c", location, &mut fields) {
+                    entries.extend(fields.entry(Some(kind), message));
+                }
+            }
+            continue;
+        }
+        for pattern in &patterns {
+            let mut fields = Fields::default();
+            if match_format(pattern, line, &mut fields) {
+                entries.extend(fields.entry(None, fields.message));
+                break;
+            }
+        }
+    }
+    entries
+}
+
+fn kind_name(kind: Option<char>) -> &'static str {
+    match kind {
+        Some('E') => "error: ",
+        Some('W') => "warning: ",
+        Some('N') => "note: ",
+        Some('H') => "help: ",
+        Some('I') => "info: ",
+        _ => "",
+    }
+}
+
+impl Editor {
+    // Replace the quickfix list, and what `copen` shows of it if it is open; a search still
+    // filling it in is stopped
+    pub(super) fn set_quickfix(&mut self, entries: Vec<Entry>) {
+        self.grep = None;
+        self.quickfix = Quickfix { entries, current: None };
+        self.update_quickfix_list();
+    }
+
+    // Add to the end of the list, as the results of a search come in
+    pub(super) fn extend_quickfix(&mut self, entries: Vec<Entry>) {
+        self.quickfix.entries.extend(entries);
+        self.update_quickfix_list();
+    }
+
+    pub(super) fn quickfix_len(&self) -> usize {
+        self.quickfix.entries.len()
+    }
+
+    fn update_quickfix_list(&mut self) {
+        let lines = self.quickfix_lines();
+        for document in self.documents.iter_mut().filter(|document| document.quickfix) {
+            *document = Document::scratch(&lines);
+            document.quickfix = true;
+        }
+    }
+
+    fn quickfix_lines(&self) -> Vec<String> {
+        self.quickfix
+            .entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{}:{}:{}: {}{}",
+                    relative(&entry.path),
+                    entry.line.saturating_add(1),
+                    entry.column.saturating_add(1),
+                    kind_name(entry.kind),
+                    entry.text
+                )
+            })
+            .collect()
+    }
+
+    // Fill the list from `output`; with `jump` go to the first entry. Whether there were any.
+    fn load_quickfix(&mut self, output: &str, jump: bool) -> bool {
+        let entries = parse(output, &self.options.errorformat);
+        let count = |kind: char| entries.iter().filter(|entry| entry.kind == Some(kind)).count();
+        let (errors, warnings, total) = (count('E'), count('W'), entries.len());
+        self.set_quickfix(entries);
+        if total == 0 {
+            return false;
+        }
+        if jump {
+            self.quickfix_go(0);
+        } else {
+            self.status_message = StatusMessage::from(format!(
+                "{} entries: {} errors, {} warnings",
+                total, errors, warnings
+            ));
+        }
+        true
+    }
+
+    // `make [arguments]`: run `makeprg` with the arguments and collect the errors it prints,
+    // going to the first unless `make!`
+    pub(super) fn make(&mut self, arguments: Option<&str>, force: bool) -> Result<(), Error> {
+        let command = match arguments {
+            Some(arguments) => format!("{} {}", self.options.makeprg, arguments),
+            None => self.options.makeprg.clone(),
+        };
+        self.status_message = StatusMessage::from(format!("Running `{}`...", command));
+        self.refresh_screen()?;
+        // Compilers report on standard error, which goes in with the rest in order
+        let output = Command::new("sh")
+            .args(&["-c", &format!("exec 2>&1; {}", command)])
+            .stdin(Stdio::null())
+            .output();
+        let output = match output {
+            Ok(output) => output,
+            Err(error) => {
+                self.report(&format!("Could not run `{}`", command), &error);
+                return Ok(());
+            }
+        };
+        let text = String::from_utf8_lossy(&output.stdout);
+        if !self.load_quickfix(&text, !force) {
+            let outcome = if output.status.success() { "finished" } else { "failed" };
+            self.status_message = StatusMessage::from(format!("`{}` {}", command, outcome));
+        }
+        Ok(())
+    }
+
+    // `cfile <filename>`: read the errors from a file of saved compiler output
+    pub(super) fn quickfix_file(&mut self, filename: Option<&str>, force: bool) {
+        let filename = match filename {
+            Some(filename) => filename,
+            None => return self.error("Usage: cfile <filename>"),
+        };
+        match fs::read_to_string(filename) {
+            Ok(text) => {
+                if !self.load_quickfix(&text, !force) {
+                    self.error("No errors found");
+                }
+            }
+            Err(error) => self.report(&format!("Could not read {}", filename), &error),
+        }
+    }
+
+    // Open the file of entry `index` and put the cursor on it
+    pub(super) fn quickfix_go(&mut self, index: usize) {
+        let entry = match self.quickfix.entries.get(index) {
+            Some(entry) => entry,
+            None => return self.error("No such entry"),
+        };
+        let (path, line, column) = (entry.path.clone(), entry.line, entry.column);
+        let message = format!(
+            "({} of {}) {}{}",
+            index.saturating_add(1),
+            self.quickfix.entries.len(),
+            kind_name(entry.kind),
+            entry.text
+        );
+        self.quickfix.current = Some(index);
+
+        let here = self.window().cur_pos.clone();
+        self.push_jump(here);
+        let document = self.buffer_for(&relative(&path));
+        if document != self.window().document {
+            self.set_document(document);
+        }
+        let y = line.min(self.document().len().saturating_sub(1));
+        let x = column.min(self.document().line(y).map_or(0, Line::len));
+        self.window_mut().cur_pos = Position { x, y };
+        self.status_message = StatusMessage::from(message);
+    }
+
+    // `cnext` and `cprevious`, `]q` and `[q`
+    pub(super) fn quickfix_step(&mut self, forward: bool) {
+        if self.quickfix.entries.is_empty() {
+            return self.error("No quickfix entries");
+        }
+        let last = self.quickfix.entries.len().saturating_sub(1);
+        let index = match (self.quickfix.current, forward) {
+            (None, _) => 0,
+            (Some(current), true) if current < last => current.saturating_add(1),
+            (Some(current), false) if current > 0 => current.saturating_sub(1),
+            _ => return self.error("No more entries"),
+        };
+        self.quickfix_go(index);
+    }
+
+    // `cc [n]`: go to entry `n`, or back to the current one
+    pub(super) fn quickfix_current(&mut self, number: Option<&str>) {
+        match number.map(str::parse::<usize>) {
+            Some(Ok(number)) if number > 0 => self.quickfix_go(number.saturating_sub(1)),
+            Some(_) => self.error("Usage: cc [<number>]"),
+            None if self.quickfix.entries.is_empty() => self.error("No quickfix entries"),
+            None => self.quickfix_go(self.quickfix.current.unwrap_or(0)),
+        }
+    }
+
+    // `copen`: list the entries in a buffer, where <CR> goes to the one under the cursor
+    pub(super) fn quickfix_open(&mut self) {
+        if self.quickfix.entries.is_empty() {
+            return self.error("No quickfix entries");
+        }
+        self.show_quickfix_list();
+    }
+
+    // The list buffer, made if there is none yet, in the focused window
+    pub(super) fn show_quickfix_list(&mut self) {
+        let index = match self.documents.iter().position(|document| document.quickfix) {
+            Some(index) => index,
+            None => {
+                let mut document = Document::scratch(&self.quickfix_lines());
+                document.quickfix = true;
+                self.documents.push(document);
+                self.documents.len().saturating_sub(1)
+            }
+        };
+        self.show_document(index);
+        let y = self.quickfix.current.unwrap_or(0);
+        self.window_mut().cur_pos = Position { x: 0, y };
+    }
+
+    // <CR> in normal mode: in the list, go to the entry under the cursor. Whether it was.
+    pub(super) fn quickfix_enter(&mut self) -> bool {
+        if !self.document().quickfix {
+            return false;
+        }
+        let y = self.window().cur_pos.y;
+        self.quickfix_go(y);
+        true
+    }
+
+    // Markers for the lines of `document` with entries, the worst kind on each
+    pub(super) fn quickfix_signs(&self, document: usize) -> Vec<(usize, char, Style)> {
+        let path = match self.documents.get(document).and_then(|document| document.filename.as_deref()) {
+            Some(filename) if !self.quickfix.entries.is_empty() => absolute(filename),
+            _ => return Vec::new(),
+        };
+        let rank = |kind: Option<char>| match kind {
+            Some('E') => 0,
+            Some('W') => 1,
+            _ => 2,
+        };
+        let mut worst: BTreeMap<usize, Option<char>> = BTreeMap::new();
+        for entry in self.quickfix.entries.iter().filter(|entry| entry.path == path) {
+            let kind = worst.entry(entry.line).or_insert(entry.kind);
+            if rank(entry.kind) < rank(*kind) {
+                *kind = entry.kind;
+            }
+        }
+        worst
+            .into_iter()
+            .map(|(y, kind)| match kind {
+                Some('E') => (y, 'E', self.theme.error),
+                Some('W') => (y, 'W', self.theme.warning),
+                _ => (y, '>', self.theme.gutter),
+            })
+            .collect()
+    }
+}
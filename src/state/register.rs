@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use termion::event::Key;
+
+use crate::error::Error;
+use crate::state::visual::SelectionKind;
+use crate::state::{Editor, Position, State, StatusMessage};
+use crate::Line;
+
+const UNNAMED: char = '"';
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Text kept by a yank or a delete, and how it was selected.
+#[derive(Clone)]
+pub struct Register {
+    pub kind: SelectionKind,
+    pub text: String, // Lines separated by `\n`, without a trailing one
+}
+
+/// The unnamed register `"`, the last yank `0`, older deletes `1` to `9`, small deletes `-`,
+/// named registers `a` to `z` and the clipboard `+` (or `*`). `_` throws text away.
+#[derive(Default)]
+pub struct Registers {
+    registers: HashMap<char, Register>,
+}
+
+impl Registers {
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || matches!(name, '"' | '-' | '+' | '*' | '_')
+    }
+
+    pub fn get(&self, name: char) -> Option<&Register> {
+        self.registers.get(&name.to_ascii_lowercase())
+    }
+
+    // Keep `register` in `name`, or in `0`, `1` or `-` as vi would when no name was given;
+    // the unnamed register always ends up with it too. Capitals append to their register.
+    pub fn store(&mut self, name: Option<char>, register: Register, delete: bool) {
+        let target = match name {
+            Some('_') => return,
+            Some(name) if name != UNNAMED => name,
+            _ if !delete => '0',
+            _ if register.kind == SelectionKind::Char && !register.text.contains('\n') => '-',
+            _ => {
+                for number in (1..9).rev() {
+                    if let (Some(from), Some(to)) = (char::from_digit(number, 10), char::from_digit(number.saturating_add(1), 10)) {
+                        if let Some(older) = self.registers.remove(&from) {
+                            self.registers.insert(to, older);
+                        }
+                    }
+                }
+                '1'
+            }
+        };
+
+        let register = match self.registers.get(&target.to_ascii_lowercase()) {
+            Some(existing) if target.is_ascii_uppercase() => {
+                let linewise = existing.kind == SelectionKind::Line || register.kind == SelectionKind::Line;
+                Register {
+                    kind: if linewise { SelectionKind::Line } else { existing.kind },
+                    text: format!("{}{}{}", existing.text, if linewise { "\n" } else { "" }, register.text),
+                }
+            }
+            _ => register,
+        };
+        self.registers.insert(UNNAMED, register.clone());
+        let target = if target == '*' { '+' } else { target.to_ascii_lowercase() };
+        self.registers.insert(target, register);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (char, &Register)> {
+        self.registers.iter().map(|(name, register)| (*name, register))
+    }
+
+    // Put `register` back in `name` as it was, as when restoring them from the state file
+    pub fn set(&mut self, name: char, register: Register) {
+        self.registers.insert(name, register);
+    }
+
+    // One line per register, as `:registers` shows them
+    pub fn list(&self) -> Vec<String> {
+        let mut names: Vec<&char> = self.registers.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let register = self.registers.get(name)?;
+                let kind = match register.kind {
+                    SelectionKind::Char => "c",
+                    SelectionKind::Line => "l",
+                    SelectionKind::Block => "b",
+                };
+                Some(format!("{}  \"{}  {}", kind, name, register.text.replace('\n', "^J")))
+            })
+            .collect()
+    }
+}
+
+impl Editor {
+    // `"x`: the next yank, delete or put uses register `x`
+    pub(super) fn select_register(&mut self) -> Result<(), Error> {
+        self.pending = vec![Key::Char(UNNAMED)];
+        self.refresh_screen()?;
+        let key = self.next_key()?;
+        self.pending.clear();
+        match key {
+            Key::Char(name) if Registers::is_valid(name) => self.register = Some(name),
+            _ => self.status_message = StatusMessage::error("Invalid register".to_string()),
+        }
+        Ok(())
+    }
+
+    // Store a yank or delete in the register picked with `"`, if any
+    pub(super) fn store_register(&mut self, register: Register, delete: bool) -> Result<(), Error> {
+        let name = self.register.take();
+        if matches!(name, Some('+') | Some('*')) {
+            self.copy_to_clipboard(&register.text)?;
+        }
+        self.registers.store(name, register, delete);
+        Ok(())
+    }
+
+    // Put the register picked with `"` (the unnamed one by default) after the cursor, or before;
+    // in visual mode it replaces the selection
+    pub(super) fn put(&mut self, before: bool) -> Result<(), Error> {
+        let name = self.register.take().unwrap_or(UNNAMED);
+        let register = if name == '+' || name == '*' {
+            self.paste_from_clipboard()?
+        } else {
+            self.registers.get(name).cloned()
+        };
+        let register = match register {
+            Some(register) => register,
+            None => {
+                self.error(&format!("Nothing in register {}", name));
+                return Ok(());
+            }
+        };
+
+        let mut before = before;
+        if let Some(selection) = self.window_mut().selection.take() {
+            let cursor = self.window().cur_pos.clone();
+            self.mode = State::Normal;
+            self.delete_selection(&selection, &cursor);
+            before = true;
+        }
+
+        let cursor = self.window().cur_pos.clone();
+        let line_len = self.document().line(cursor.y).map_or(0, Line::len);
+        // After the character under the cursor, unless the line is empty
+        let column = if before || line_len == 0 {
+            cursor.x
+        } else {
+            cursor.x.saturating_add(1).min(line_len)
+        };
+        match register.kind {
+            SelectionKind::Line => {
+                let y = if before {
+                    cursor.y
+                } else {
+                    cursor.y.saturating_add(1).min(self.document().len())
+                };
+                for (index, text) in register.text.split('\n').enumerate() {
+                    self.document_mut().insert_line(y.saturating_add(index), text);
+                }
+                self.window_mut().cur_pos = Position {
+                    x: self.first_non_blank(y),
+                    y,
+                };
+            }
+            SelectionKind::Char => {
+                let at = Position { x: column, y: cursor.y };
+                let end = self.document_mut().insert_text(&at, &register.text);
+                self.window_mut().cur_pos = Position {
+                    x: end.x.saturating_sub(1),
+                    y: end.y,
+                };
+            }
+            SelectionKind::Block => {
+                // Every line gets its piece at the same screen column
+                let tab_width = self.options.tab_width;
+                let left = self.document().line(cursor.y).map_or(0, |line| line.width_to(column, tab_width));
+                for (index, text) in register.text.split('\n').enumerate() {
+                    let y = cursor.y.saturating_add(index);
+                    if y >= self.document().len() {
+                        let len = self.document().len();
+                        self.document_mut().insert_line(len, "");
+                    }
+                    // Short lines are padded so the block keeps its shape
+                    let (at, width) = self.document().line(y).map_or((0, 0), |line| {
+                        (line.index_at(left, tab_width), line.width_to(line.len(), tab_width))
+                    });
+                    let padding = " ".repeat(left.saturating_sub(width));
+                    self.document_mut()
+                        .edit_line(y, at, at, |_| format!("{}{}", padding, text));
+                }
+                self.window_mut().cur_pos = Position { x: column, y: cursor.y };
+            }
+        }
+        Ok(())
+    }
+
+    // Through `clipboard_copy` when it is set, otherwise with an OSC 52 escape sequence that
+    // most terminals (and tmux) hand on to the system clipboard
+    fn copy_to_clipboard(&mut self, text: &str) -> Result<(), Error> {
+        match self.options.clipboard_copy.clone() {
+            Some(command) => {
+                let mut child = Command::new("sh")
+                    .args(&["-c", &command])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .spawn()?;
+                if let Some(stdin) = child.stdin.as_mut() {
+                    stdin.write_all(text.as_bytes())?;
+                }
+                drop(child.stdin.take());
+                if !child.wait()?.success() {
+                    return Err(io::Error::new(io::ErrorKind::Other, format!("`{}` failed", command)).into());
+                }
+                Ok(())
+            }
+            None => self.terminal.write_raw(&format!("\x1b]52;c;{}\x07", base64(text.as_bytes()))),
+        }
+    }
+
+    // Terminals rarely let programs read the clipboard, so without a `clipboard_paste` command
+    // this is whatever we last copied to it
+    fn paste_from_clipboard(&mut self) -> Result<Option<Register>, Error> {
+        let command = match self.options.clipboard_paste.clone() {
+            Some(command) => command,
+            None => return Ok(self.registers.get('+').cloned()),
+        };
+        let output = Command::new("sh").args(&["-c", &command]).stderr(Stdio::null()).output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("`{}` failed", command)).into());
+        }
+        let text = String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n");
+        // Whole lines go in as lines, like a linewise yank
+        Ok(Some(match text.strip_suffix('\n') {
+            Some(text) => Register {
+                kind: SelectionKind::Line,
+                text: text.to_string(),
+            },
+            None if text.is_empty() => return Ok(None),
+            None => Register {
+                kind: SelectionKind::Char,
+                text,
+            },
+        }))
+    }
+}
+
+#[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(char::from(BASE64[(group >> (18 - 6 * index) & 63) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,511 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::state::register::Register;
+use crate::state::swap::state_dir;
+use crate::state::tab::Tab;
+use crate::state::visual::SelectionKind;
+use crate::state::window::{Layout, Window};
+use crate::state::{Editor, Position, StatusMessage};
+use crate::{Document, Line};
+
+const STATE_HEADER: &str = "sodium state 1";
+const SESSION_HEADER: &str = "sodium session 1";
+// How many files, and lines of each history, the state file keeps
+const FILE_LIMIT: usize = 100;
+const HISTORY_LIMIT: usize = 100;
+// The mark that holds where the cursor was when a file was left
+pub const LAST_CURSOR: char = '"';
+
+/// Lines typed at a prompt, oldest first.
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+}
+
+impl History {
+    // A line typed again moves to the end
+    pub fn add(&mut self, line: &str) {
+        self.entries.retain(|entry| entry != line);
+        self.entries.push(line.to_string());
+        if self.entries.len() > HISTORY_LIMIT {
+            self.entries.remove(0);
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    // The entry before or after `index`; going back from none starts at the newest, and going
+    // forward past it gives none again
+    pub fn step(&self, index: Option<usize>, back: bool) -> Option<usize> {
+        match (index, back) {
+            (None, true) => self.entries.len().checked_sub(1),
+            (Some(index), true) => Some(index.saturating_sub(1)),
+            (None, false) => None,
+            (Some(index), false) => Some(index.saturating_add(1)).filter(|next| *next < self.entries.len()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum HistoryKind {
+    Command,
+    Search,
+}
+
+/// The marks of a file kept in the state file, the cursor among them as the `"` mark.
+pub struct FileState {
+    path: PathBuf,
+    marks: Vec<(char, Position)>,
+}
+
+// A tab of a session file being read
+struct SessionTab {
+    name: Option<String>,
+    focus: usize,
+    tree: String,
+    windows: Vec<Window>,
+}
+
+// Backslashes and line breaks escaped, so that any text fits on one line
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some(escaped) => result.push(escaped),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+// Files are remembered by their full path, wherever the editor is started from
+pub(super) fn absolute(filename: &str) -> PathBuf {
+    fs::canonicalize(filename).unwrap_or_else(|_| {
+        env::current_dir().map_or_else(|_| PathBuf::from(filename), |dir| dir.join(filename))
+    })
+}
+
+// How to name `path` from the current directory
+pub(super) fn relative(path: &Path) -> String {
+    let dir = env::current_dir().ok();
+    dir.as_deref()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// `<name> <line> <column>` and whatever follows
+fn parse_mark(text: &str) -> Option<(char, Position, &str)> {
+    let mut fields = text.splitn(4, ' ');
+    let name = fields.next()?.chars().next()?;
+    let y = fields.next()?.parse().ok()?;
+    let x = fields.next()?.parse().ok()?;
+    Some((name, Position { x, y }, fields.next().unwrap_or("")))
+}
+
+fn kind_name(kind: SelectionKind) -> char {
+    match kind {
+        SelectionKind::Char => 'c',
+        SelectionKind::Line => 'l',
+        SelectionKind::Block => 'b',
+    }
+}
+
+fn parse_register(text: &str) -> Option<(char, Register)> {
+    let mut fields = text.splitn(3, ' ');
+    let name = fields.next()?.chars().next()?;
+    let kind = match fields.next()? {
+        "c" => SelectionKind::Char,
+        "l" => SelectionKind::Line,
+        "b" => SelectionKind::Block,
+        _ => return None,
+    };
+    let text = unescape(fields.next().unwrap_or(""));
+    Some((name, Register { kind, text }))
+}
+
+fn state_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("state"))
+}
+
+// Sessions are the files of one directory, so a name must not lead out of it
+fn session_path(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || name.contains(&['/', '\\'][..]) || name.contains("..") || name.ends_with(".tmp") {
+        return Err(format!("Invalid session name: {}", name));
+    }
+    state_dir()
+        .map(|dir| dir.join("sessions").join(name))
+        .ok_or_else(|| "No state directory to keep sessions in".to_string())
+}
+
+// Names of the saved sessions, sorted
+pub fn session_names() -> Vec<String> {
+    let mut names: Vec<String> = state_dir()
+        .and_then(|dir| fs::read_dir(dir.join("sessions")).ok())
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| !name.ends_with(".tmp"))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+// Replace `path` in one go, so that a crash never leaves half of it behind
+fn write_file(path: &Path, contents: &str) -> Result<(), io::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+impl SessionTab {
+    fn into_tab(self) -> Option<Tab> {
+        Some(Tab {
+            name: self.name,
+            layout: Layout::from_tree(&self.tree, self.windows, self.focus)?,
+        })
+    }
+}
+
+impl Editor {
+    pub(super) fn history(&self, kind: HistoryKind) -> &History {
+        match kind {
+            HistoryKind::Command => &self.commands,
+            HistoryKind::Search => &self.searches,
+        }
+    }
+
+    pub(super) fn history_mut(&mut self, kind: HistoryKind) -> &mut History {
+        match kind {
+            HistoryKind::Command => &mut self.commands,
+            HistoryKind::Search => &mut self.searches,
+        }
+    }
+
+    // Read the state file left by earlier runs, and go back to where we were in the files
+    // opened at startup
+    pub(super) fn load_state(&mut self) {
+        let contents = match state_path().map(fs::read_to_string) {
+            Some(Ok(contents)) => contents,
+            Some(Err(error)) if error.kind() != io::ErrorKind::NotFound => {
+                self.report("Could not read the state file", &error);
+                return;
+            }
+            _ => return,
+        };
+        let mut lines = contents.lines();
+        if lines.next() != Some(STATE_HEADER) {
+            return;
+        }
+        for line in lines {
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match kind {
+                "file" => self.remembered.push(FileState {
+                    path: PathBuf::from(unescape(rest)),
+                    marks: Vec::new(),
+                }),
+                "mark" => {
+                    if let (Some(state), Some((name, position, _))) = (self.remembered.last_mut(), parse_mark(rest)) {
+                        state.marks.push((name, position));
+                    }
+                }
+                "global" => {
+                    if let Some((name, position, path)) = parse_mark(rest) {
+                        let filename = relative(Path::new(&unescape(path)));
+                        self.file_marks.insert(name, (filename, position));
+                    }
+                }
+                "command" => self.commands.add(&unescape(rest)),
+                "search" => self.searches.add(&unescape(rest)),
+                "register" => {
+                    if let Some((name, register)) = parse_register(rest) {
+                        self.registers.set(name, register);
+                    }
+                }
+                _ => (),
+            }
+        }
+        for index in 0..self.documents.len() {
+            self.restore_file(index);
+        }
+        self.restore_cursor();
+    }
+
+    // Give a document just opened the marks its file had, global ones included
+    pub(super) fn restore_file(&mut self, index: usize) {
+        let path = match self.documents.get(index).and_then(|document| document.filename.as_deref()) {
+            Some(filename) => absolute(filename),
+            None => return,
+        };
+        let marks = self
+            .remembered
+            .iter()
+            .find(|state| state.path == path)
+            .map(|state| state.marks.clone())
+            .unwrap_or_default();
+        let globals: Vec<char> = self
+            .file_marks
+            .iter()
+            .filter(|(_, (filename, _))| absolute(filename) == path)
+            .map(|(name, _)| *name)
+            .collect();
+        if let Some(document) = self.documents.get_mut(index) {
+            for (name, position) in marks {
+                document.marks.set(name, position);
+            }
+            for name in globals {
+                if let Some((_, position)) = self.file_marks.remove(&name) {
+                    document.marks.set(name, position);
+                }
+            }
+        }
+    }
+
+    // Put the focused window's cursor back where its file was last left
+    pub(super) fn restore_cursor(&mut self) {
+        if let Some(position) = self.document().marks.get(LAST_CURSOR).cloned() {
+            let y = position.y.min(self.document().len().saturating_sub(1));
+            let x = position.x.min(self.document().line(y).map_or(0, Line::len));
+            self.window_mut().cur_pos = Position { x, y };
+        }
+    }
+
+    // Every window leaves its cursor in the `"` mark of its document, the focused one last
+    fn note_cursors(&mut self) {
+        let focused = (self.tab, self.layout().focus);
+        let mut cursors: Vec<(usize, Position)> = Vec::new();
+        for (tab, layout) in self.tabs.iter().map(|tab| &tab.layout).enumerate() {
+            for (index, window) in layout.windows.iter().enumerate() {
+                if (tab, index) != focused {
+                    cursors.push((window.document, window.cur_pos.clone()));
+                }
+            }
+        }
+        cursors.push((self.window().document, self.window().cur_pos.clone()));
+        for (document, position) in cursors {
+            if let Some(document) = self.documents.get_mut(document) {
+                document.marks.set(LAST_CURSOR, position);
+            }
+        }
+    }
+
+    // Keep the marks of buffer `index` for the state file, as the most recent one
+    fn remember_file(&mut self, index: usize) {
+        let document = match self.documents.get(index) {
+            Some(document) => document,
+            None => return,
+        };
+        let path = match &document.filename {
+            Some(filename) => absolute(filename),
+            None => return,
+        };
+        let marks = document
+            .marks
+            .iter()
+            .filter(|(name, _)| name.is_ascii_lowercase() || *name == LAST_CURSOR)
+            .map(|(name, position)| (name, position.clone()))
+            .collect();
+        self.remembered.retain(|state| state.path != path);
+        self.remembered.insert(0, FileState { path, marks });
+        self.remembered.truncate(FILE_LIMIT);
+    }
+
+    // A buffer is about to be deleted
+    pub(super) fn remember_buffer(&mut self, index: usize) {
+        self.note_cursors();
+        self.remember_file(index);
+    }
+
+    // Write what is worth keeping to the state file, on the way out
+    pub(super) fn save_state(&mut self) -> Result<(), io::Error> {
+        let path = match state_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        self.note_cursors();
+        for index in (0..self.documents.len()).rev() {
+            self.remember_file(index);
+        }
+
+        let mut contents = format!("{}\n", STATE_HEADER);
+        for state in &self.remembered {
+            contents.push_str(&format!("file {}\n", escape(&state.path.to_string_lossy())));
+            for (name, position) in &state.marks {
+                contents.push_str(&format!("mark {} {} {}\n", name, position.y, position.x));
+            }
+        }
+        let mut globals: Vec<(char, PathBuf, Position)> = self
+            .file_marks
+            .iter()
+            .map(|(name, (filename, position))| (*name, absolute(filename), position.clone()))
+            .collect();
+        for document in &self.documents {
+            if let Some(filename) = &document.filename {
+                for (name, position) in document.marks.iter().filter(|(name, _)| name.is_ascii_uppercase()) {
+                    globals.push((name, absolute(filename), position.clone()));
+                }
+            }
+        }
+        globals.sort_by_key(|(name, _, _)| *name);
+        for (name, path, position) in globals {
+            let path = escape(&path.to_string_lossy());
+            contents.push_str(&format!("global {} {} {} {}\n", name, position.y, position.x, path));
+        }
+        for (kind, history) in &[("command", &self.commands), ("search", &self.searches)] {
+            for entry in &history.entries {
+                contents.push_str(&format!("{} {}\n", kind, escape(entry)));
+            }
+        }
+        let mut registers: Vec<(char, &Register)> = self.registers.iter().filter(|(name, _)| *name != '+').collect();
+        registers.sort_by_key(|(name, _)| *name);
+        for (name, register) in registers {
+            contents.push_str(&format!("register {} {} {}\n", name, kind_name(register.kind), escape(&register.text)));
+        }
+        write_file(&path, &contents)
+    }
+
+    // `:mksession[!] <name>`: remember the tabs and windows and the files in them
+    pub(super) fn make_session(&mut self, name: Option<&str>, force: bool) {
+        let name = match name {
+            Some(name) => name,
+            None => return self.error("Usage: mksession <name>"),
+        };
+        let path = match session_path(name) {
+            Ok(path) => path,
+            Err(error) => return self.error(&error),
+        };
+        if path.exists() && !force {
+            self.error(&format!("Session {} exists (add ! to overwrite)", name));
+            return;
+        }
+
+        let mut contents = format!("{}\n", SESSION_HEADER);
+        for tab in &self.tabs {
+            contents.push_str(&format!("tab {}\n", escape(tab.name.as_deref().unwrap_or(""))));
+            contents.push_str(&format!("layout {} {}\n", tab.layout.focus, tab.layout.tree()));
+            for window in &tab.layout.windows {
+                let filename = self
+                    .documents
+                    .get(window.document)
+                    .and_then(|document| document.filename.as_deref())
+                    .map_or_else(String::new, |filename| absolute(filename).to_string_lossy().into_owned());
+                contents.push_str(&format!("window {} {} {}\n", window.cur_pos.y, window.cur_pos.x, escape(&filename)));
+            }
+        }
+        contents.push_str(&format!("current {}\n", self.tab));
+        match write_file(&path, &contents) {
+            Ok(()) => self.status_message = StatusMessage::from(format!("Session {} saved", name)),
+            Err(error) => self.report(&format!("Could not write session {}", name), &error),
+        }
+    }
+
+    // `:session <name>`: open the files of a saved session in its tabs and windows; without a
+    // name, list the sessions
+    pub(super) fn load_session(&mut self, name: Option<&str>) {
+        let name = match name {
+            Some(name) => name,
+            None => {
+                self.list_sessions();
+                return;
+            }
+        };
+        let path = match session_path(name) {
+            Ok(path) => path,
+            Err(error) => return self.error(&error),
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                self.report(&format!("Could not read session {}", name), &error);
+                return;
+            }
+        };
+        let mut lines = contents.lines();
+        if lines.next() != Some(SESSION_HEADER) {
+            self.error(&format!("Not a session file: {}", name));
+            return;
+        }
+
+        let mut tabs: Vec<SessionTab> = Vec::new();
+        let mut current = 0;
+        for line in lines {
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match kind {
+                "tab" => tabs.push(SessionTab {
+                    name: Some(unescape(rest)).filter(|name| !name.is_empty()),
+                    focus: 0,
+                    tree: "0".to_string(),
+                    windows: Vec::new(),
+                }),
+                "layout" => {
+                    if let (Some(tab), Some((focus, tree))) = (tabs.last_mut(), rest.split_once(' ')) {
+                        tab.focus = focus.parse().unwrap_or(0);
+                        tab.tree = tree.to_string();
+                    }
+                }
+                "window" => {
+                    if let Some((_, position, path)) = parse_mark(&format!("w {}", rest)) {
+                        let path = unescape(path);
+                        let document = if path.is_empty() {
+                            self.documents.push(Document::default());
+                            self.documents.len().saturating_sub(1)
+                        } else {
+                            self.buffer_for(&relative(Path::new(&path)))
+                        };
+                        if let Some(tab) = tabs.last_mut() {
+                            tab.windows.push(Window {
+                                cur_pos: position,
+                                ..Window::new(document)
+                            });
+                        }
+                    }
+                }
+                "current" => current = rest.parse().unwrap_or(0),
+                _ => (),
+            }
+        }
+
+        let tabs: Option<Vec<Tab>> = tabs.into_iter().map(SessionTab::into_tab).collect();
+        match tabs {
+            Some(tabs) if !tabs.is_empty() => {
+                self.tab = current.min(tabs.len().saturating_sub(1));
+                self.tabs = tabs;
+                self.clamp_cursors();
+                self.status_message = StatusMessage::from(format!("Session {} restored", name));
+            }
+            _ => self.error(&format!("Session {} is damaged", name)),
+        }
+    }
+
+    fn list_sessions(&mut self) {
+        let names = session_names();
+        self.status_message = StatusMessage::from(if names.is_empty() {
+            "No sessions saved".to_string()
+        } else {
+            format!("Sessions: {}", names.join(" "))
+        });
+    }
+}
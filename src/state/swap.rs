@@ -0,0 +1,180 @@
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+use crate::Line;
+
+const HEADER: &str = "sodium swap 1";
+
+// Largest middle section (in line pairs) we run the full LCS diff on
+const DIFF_LIMIT: usize = 4_000_000;
+
+// A swap file left behind by a session that did not exit cleanly
+pub struct Recovery {
+    pub path: PathBuf,
+    pub pid: u32,
+    pub modified: Option<SystemTime>,
+    pub lines: Vec<String>,
+}
+
+impl Recovery {
+    // Is the process that wrote this swap file still around?
+    pub fn is_running(&self) -> bool {
+        self.pid != process::id() && Path::new(&format!("/proc/{}", self.pid)).exists()
+    }
+}
+
+// Per-user state directory, used for untitled buffers
+pub fn state_dir() -> Option<PathBuf> {
+    let base = if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+        PathBuf::from(dir)
+    } else {
+        PathBuf::from(env::var_os("HOME")?).join(".local").join("state")
+    };
+    Some(base.join("sodium"))
+}
+
+// Swap files live next to the document as `.name.swp`, untitled buffers go to the state dir.
+// When `.name.swp` is taken (by another session, or a stale one the user kept) the next
+// choices are `.name.swo`, `.name.swn` and so on.
+pub fn paths_for(filename: Option<&str>) -> Vec<PathBuf> {
+    if let Some(filename) = filename {
+        let path = Path::new(filename);
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return Vec::new(),
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        ('a'..='p').rev().map(|last| dir.join(format!(".{}.sw{}", name, last))).collect()
+    } else {
+        state_dir()
+            .map(|dir| dir.join("swap").join(format!("untitled-{}.swp", process::id())))
+            .into_iter()
+            .collect()
+    }
+}
+
+pub fn write(path: &Path, filename: Option<&str>, lines: &[Line]) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    // Write to a temporary file first so a crash mid-write never clobbers the old journal
+    let tmp = path.with_extension("swp~");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "pid {}", process::id())?;
+        writeln!(file, "file {}", filename.unwrap_or(""))?;
+        writeln!(file)?;
+        for line in lines {
+            file.write_all(line.as_bytes())?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+pub fn read(path: &Path) -> Result<Recovery, Error> {
+    let data = fs::read_to_string(path)?;
+    let mut lines = data.lines();
+
+    if lines.next() != Some(HEADER) {
+        return Err(Error::new(ErrorKind::InvalidData, "not a sodium swap file"));
+    }
+
+    let mut pid = 0;
+    for header in &mut lines {
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("pid ") {
+            pid = value.parse().unwrap_or(0);
+        }
+    }
+
+    Ok(Recovery {
+        path: path.to_path_buf(),
+        pid,
+        modified: fs::metadata(path).and_then(|meta| meta.modified()).ok(),
+        lines: lines.map(String::from).collect(),
+    })
+}
+
+// Look for a stale swap file belonging to `filename`
+pub fn find(filename: &str) -> Option<Recovery> {
+    paths_for(Some(filename)).iter().filter(|path| path.exists()).find_map(|path| read(path).ok())
+}
+
+pub fn remove(path: &Path) {
+    if path.exists() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+// Line based diff of `old` against `new`, prefixed with "  ", "- " and "+ "
+pub fn diff(old: &[String], new: &[String]) -> Vec<String> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let old_mid = &old[prefix..old.len().saturating_sub(suffix)];
+    let new_mid = &new[prefix..new.len().saturating_sub(suffix)];
+
+    let mut result: Vec<String> = old[..prefix].iter().map(|line| format!("  {}", line)).collect();
+
+    if old_mid.len().saturating_mul(new_mid.len()) > DIFF_LIMIT {
+        result.extend(old_mid.iter().map(|line| format!("- {}", line)));
+        result.extend(new_mid.iter().map(|line| format!("+ {}", line)));
+    } else {
+        result.extend(lcs_diff(old_mid, new_mid));
+    }
+
+    result.extend(old[old.len().saturating_sub(suffix)..].iter().map(|line| format!("  {}", line)));
+    result
+}
+
+#[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+fn lcs_diff(old: &[String], new: &[String]) -> Vec<String> {
+    let (n, m) = (old.len(), new.len());
+    let mut table = vec![0_usize; (n + 1) * (m + 1)];
+
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[i * (m + 1) + j] = if old[i] == new[j] {
+                table[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                table[(i + 1) * (m + 1) + j].max(table[i * (m + 1) + j + 1])
+            };
+        }
+    }
+
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            result.push(format!("  {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if table[(i + 1) * (m + 1) + j] >= table[i * (m + 1) + j + 1] {
+            result.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            result.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    result.extend(old[i..].iter().map(|line| format!("- {}", line)));
+    result.extend(new[j..].iter().map(|line| format!("+ {}", line)));
+    result
+}
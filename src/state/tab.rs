@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use crate::state::window::{Layout, Window};
+use crate::state::{Editor, StatusMessage};
+use crate::Document;
+
+/// A page of windows, laid out independently of the other tabs.
+pub struct Tab {
+    pub name: Option<String>, // Set with `tabname`, otherwise named after the focused document
+    pub layout: Layout,
+}
+
+impl Tab {
+    pub fn new(document: usize) -> Self {
+        Self {
+            name: None,
+            layout: Layout::new(document),
+        }
+    }
+
+    pub fn from_window(window: Window) -> Self {
+        let mut tab = Self::new(window.document);
+        *tab.layout.focused_mut() = window;
+        tab
+    }
+
+    // Label for the tab line: the tab's name and a `+` if any of its documents is modified
+    pub fn label(&self, documents: &[Document]) -> String {
+        let name = self.name.clone().unwrap_or_else(|| {
+            documents
+                .get(self.layout.focused().document)
+                .and_then(|document| document.filename.as_deref())
+                .and_then(|filename| Path::new(filename).file_name())
+                .map_or_else(|| "[untitled]".to_string(), |name| name.to_string_lossy().into_owned())
+        });
+        let modified = self.layout.windows.iter().any(|window| {
+            documents
+                .get(window.document)
+                .map_or(false, Document::is_changed)
+        });
+        format!("{}{}", name, if modified { " +" } else { "" })
+    }
+}
+
+impl Editor {
+    // Open a new tab after the current one, on `filename` or an empty buffer
+    pub(super) fn tab_new(&mut self, filename: Option<&str>) {
+        let index = if let Some(filename) = filename {
+            self.buffer_for(filename)
+        } else {
+            self.documents.push(Document::default());
+            self.documents.len().saturating_sub(1)
+        };
+        self.tab = self.tab.saturating_add(1);
+        self.tabs.insert(self.tab, Tab::new(index));
+    }
+
+    // Move the focused window into a tab of its own
+    pub(super) fn window_to_tab(&mut self) {
+        if self.layout().windows.len() <= 1 {
+            self.status_message = StatusMessage::error("Already the only window in this tab".to_string());
+            return;
+        }
+        let window = self.window().clone();
+        self.layout_mut().close();
+        self.tab = self.tab.saturating_add(1);
+        self.tabs.insert(self.tab, Tab::from_window(window));
+    }
+
+    pub(super) fn tab_close(&mut self) {
+        if self.tabs.len() <= 1 {
+            self.status_message = StatusMessage::error("Cannot close the last tab".to_string());
+            return;
+        }
+        self.tabs.remove(self.tab);
+        self.tab = self.tab.min(self.tabs.len().saturating_sub(1));
+    }
+
+    pub(super) fn tab_only(&mut self) {
+        let tab = self.tabs.remove(self.tab);
+        self.tabs = vec![tab];
+        self.tab = 0;
+    }
+
+    // `tabn` goes to the next tab, `tabn N` to tab N (counting from 1)
+    pub(super) fn tab_switch(&mut self, number: Option<&str>, forward: bool) {
+        let count = self.tabs.len();
+        match number.map(str::parse::<usize>) {
+            None => {
+                self.tab = if forward {
+                    self.tab.saturating_add(1) % count
+                } else {
+                    self.tab.checked_sub(1).unwrap_or(count.saturating_sub(1))
+                };
+            }
+            Some(Ok(number)) if number >= 1 && number <= count => self.tab = number.saturating_sub(1),
+            Some(_) => self.status_message = StatusMessage::error("No such tab".to_string()),
+        }
+    }
+
+    // `tabmove N` puts the tab at position N (0 is first), `tabmove +N`/`-N` moves it relative
+    pub(super) fn tab_move(&mut self, argument: Option<&str>) {
+        let last = self.tabs.len().saturating_sub(1);
+        let argument = argument.unwrap_or("");
+        let target = if let Some(delta) = argument.strip_prefix('+') {
+            delta.parse::<usize>().ok().map(|delta| self.tab.saturating_add(delta))
+        } else if let Some(delta) = argument.strip_prefix('-') {
+            delta.parse::<usize>().ok().map(|delta| self.tab.saturating_sub(delta))
+        } else if argument.is_empty() {
+            Some(last)
+        } else {
+            argument.parse::<usize>().ok()
+        };
+
+        match target {
+            Some(target) => {
+                let tab = self.tabs.remove(self.tab);
+                self.tab = target.min(last);
+                self.tabs.insert(self.tab, tab);
+            }
+            None => self.status_message = StatusMessage::error("Usage: tabmove [+-]<numeral>".to_string()),
+        }
+    }
+
+    pub(super) fn tab_name(&mut self, name: Option<&str>) {
+        let tab = self.tab;
+        if let Some(tab) = self.tabs.get_mut(tab) {
+            tab.name = name.map(String::from);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::convert::TryFrom;
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+
+// The color schemes bundled with the editor, a zlib compressed bincode dump of a syntect `ThemeSet`
+const BUNDLED: &[u8] = include_bytes!("../../../assets/default.themedump");
+
+// Positions in the editor settings of the dump; the version of syntect that wrote it has 32 of them
+pub const FOREGROUND: usize = 0;
+pub const BACKGROUND: usize = 1;
+pub const LINE_HIGHLIGHT: usize = 3;
+pub const BRACKETS_FOREGROUND: usize = 11;
+pub const BRACKETS_BACKGROUND: usize = 12;
+pub const FIND_HIGHLIGHT: usize = 17;
+pub const FIND_HIGHLIGHT_FOREGROUND: usize = 18;
+pub const GUTTER: usize = 19;
+pub const GUTTER_FOREGROUND: usize = 20;
+pub const SELECTION: usize = 21;
+pub const SELECTION_FOREGROUND: usize = 22;
+const SETTINGS: usize = 32;
+const CSS_SETTINGS: [usize; 2] = [7, 8]; // Strings rather than colors
+const UNDERLINE_SETTINGS: [usize; 3] = [10, 13, 15]; // An enum tag rather than a color
+
+pub type Rgba = (u8, u8, u8, u8);
+
+/// A color scheme as TextMate/Sublime themes describe them.
+pub struct Scheme {
+    pub name: String,
+    pub settings: Vec<Option<Rgba>>, // Indexed by the constants above
+    pub items: Vec<Item>,
+}
+
+/// Colors for the text matching any of `scopes`.
+pub struct Item {
+    pub scopes: Vec<String>, // Only plain selectors, the innermost scope of each
+    pub foreground: Option<Rgba>,
+    pub background: Option<Rgba>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.at.checked_add(count).filter(|end| *end <= self.data.len());
+        let bytes = end
+            .and_then(|end| self.data.get(self.at..end))
+            .ok_or_else(|| "theme dump ends early".to_string())?;
+        self.at = self.at.saturating_add(count);
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        self.bytes(1)?.first().copied().ok_or_else(|| "theme dump ends early".to_string())
+    }
+
+    fn length(&mut self) -> Result<usize, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        usize::try_from(u64::from_le_bytes(bytes)).map_err(|_| "theme dump is corrupt".to_string())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.length()?;
+        String::from_utf8(self.bytes(length)?.to_vec()).map_err(|_| "theme dump is corrupt".to_string())
+    }
+
+    fn present(&mut self) -> Result<bool, String> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err("theme dump is corrupt".to_string()),
+        }
+    }
+
+    fn color(&mut self) -> Result<Option<Rgba>, String> {
+        if !self.present()? {
+            return Ok(None);
+        }
+        match self.bytes(4)? {
+            [r, g, b, a] => Ok(Some((*r, *g, *b, *a))),
+            _ => Err("theme dump is corrupt".to_string()),
+        }
+    }
+
+    fn scope_stack(&mut self) -> Result<Vec<String>, String> {
+        // Scopes cleared by the selector; not something we need
+        for _ in 0..self.length()? {
+            for _ in 0..self.length()? {
+                self.string()?;
+            }
+        }
+        (0..self.length()?).map(|_| self.string()).collect()
+    }
+
+    fn scheme(&mut self) -> Result<Scheme, String> {
+        let key = self.string()?;
+        for _ in 0..2 {
+            // Display name and author
+            if self.present()? {
+                self.string()?;
+            }
+        }
+
+        let mut settings = Vec::with_capacity(SETTINGS);
+        for index in 0..SETTINGS {
+            if CSS_SETTINGS.contains(&index) {
+                if self.present()? {
+                    self.string()?;
+                }
+                settings.push(None);
+            } else if UNDERLINE_SETTINGS.contains(&index) {
+                if self.present()? {
+                    self.bytes(4)?;
+                }
+                settings.push(None);
+            } else {
+                settings.push(self.color()?);
+            }
+        }
+
+        let mut items = Vec::new();
+        for _ in 0..self.length()? {
+            let mut scopes = Vec::new();
+            for _ in 0..self.length()? {
+                let path = self.scope_stack()?;
+                let mut excluded = false;
+                for _ in 0..self.length()? {
+                    self.scope_stack()?;
+                    excluded = true;
+                }
+                if let (Some(scope), 1, false) = (path.last(), path.len(), excluded) {
+                    scopes.push(scope.clone());
+                }
+            }
+            let foreground = self.color()?;
+            let background = self.color()?;
+            if self.present()? {
+                self.byte()?; // Font style
+            }
+            items.push(Item {
+                scopes,
+                foreground,
+                background,
+            });
+        }
+        Ok(Scheme {
+            name: key,
+            settings,
+            items,
+        })
+    }
+}
+
+// Every bundled color scheme, in name order
+pub fn load() -> Result<Vec<Scheme>, String> {
+    let mut data = Vec::new();
+    ZlibDecoder::new(BUNDLED)
+        .read_to_end(&mut data)
+        .map_err(|error| format!("could not unpack the bundled themes: {}", error))?;
+
+    let mut reader = Reader { data: &data, at: 0 };
+    (0..reader.length()?).map(|_| reader.scheme()).collect()
+}
@@ -0,0 +1,359 @@
+use std::fs;
+use std::path::PathBuf;
+
+use termion::color::Rgb;
+
+use crate::state::config;
+use crate::terminal::Style;
+
+mod dump;
+
+use dump::{Rgba, Scheme};
+
+// Faces for syntax highlighting and the scope each one is colored after in bundled themes
+const SYNTAX_FACES: &[(&str, &str)] = &[
+    ("comment", "comment"),
+    ("string", "string"),
+    ("keyword", "keyword"),
+    ("number", "constant.numeric"),
+    ("constant", "constant"),
+    ("function", "entity.name.function"),
+    ("type", "storage.type"),
+];
+const BUILTIN: &[&str] = &["default", "dark"];
+const ERROR_RED: Rgb = Rgb(220, 50, 47);
+const WARNING_ORANGE: Rgb = Rgb(203, 75, 22);
+
+/// The styles the editor draws its interface and text with.
+#[derive(Clone)]
+pub struct Theme {
+    pub name: String,
+    pub text: Style,
+    pub status: Style, // Status line of the focused window, and the current tab
+    pub status_inactive: Style, // Other status lines and tabs, window separators
+    pub message: Style,
+    pub error: Style,
+    pub warning: Style, // Diagnostics from language servers that are not errors
+    pub selection: Style,
+    pub cursor_line: Style,
+    pub gutter: Style, // Line numbers
+    pub search: Style, // Matches of the search being typed
+    pub bracket: Style, // The bracket matching the one at the cursor
+    pub menu: Style, // Words offered by insert mode completion
+    pub menu_selected: Style,
+    pub syntax: Vec<(&'static str, Style)>, // Named after `SYNTAX_FACES`
+}
+
+impl Theme {
+    // A built in theme, one of the bundled color schemes, or a theme file of the user
+    pub fn named(name: &str) -> Result<Self, String> {
+        Self::load(name, &mut Vec::new())
+    }
+
+    // `loading` has the theme files being read, each based on the next
+    fn load(name: &str, loading: &mut Vec<String>) -> Result<Self, String> {
+        if let Some(theme) = Self::builtin(name) {
+            return Ok(theme);
+        }
+        if let Some(scheme) = dump::load()?.into_iter().find(|scheme| scheme.name == name) {
+            return Ok(Self::from_scheme(&scheme));
+        }
+        match fs::read_to_string(user_path(name)) {
+            Ok(contents) => {
+                loading.push(name.to_string());
+                let theme = Self::from_file(name, &contents, loading);
+                loading.pop();
+                theme
+            }
+            Err(_) => Err(format!("Unknown theme: {}", name)),
+        }
+    }
+
+    // Names of every theme `named` knows about
+    pub fn list() -> Vec<String> {
+        let mut names: Vec<String> = BUILTIN.iter().map(|name| (*name).to_string()).collect();
+        names.extend(dump::load().unwrap_or_default().into_iter().map(|scheme| scheme.name));
+        if let Ok(entries) = fs::read_dir(user_path("")) {
+            let mut user: Vec<String> = entries
+                .filter_map(Result::ok)
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    name.strip_suffix(".theme").map(String::from)
+                })
+                .collect();
+            user.sort();
+            names.extend(user);
+        }
+        names
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        let (status, status_inactive, error, warning, selection, cursor_line, gutter, search, bracket, menu, menu_selected) = match name {
+            "default" => (
+                (Rgb(63, 63, 63), Rgb(239, 239, 239)),
+                (Rgb(63, 63, 63), Rgb(150, 150, 150)),
+                ERROR_RED,
+                WARNING_ORANGE,
+                Rgb(180, 200, 230),
+                Rgb(238, 238, 238),
+                Rgb(150, 150, 150),
+                Rgb(250, 220, 80),
+                Rgb(170, 220, 220),
+                Rgb(225, 225, 225),
+                Rgb(150, 180, 220),
+            ),
+            "dark" => (
+                (Rgb(220, 220, 220), Rgb(68, 68, 68)),
+                (Rgb(150, 150, 150), Rgb(40, 40, 40)),
+                Rgb(255, 95, 95),
+                Rgb(255, 175, 95),
+                Rgb(60, 80, 120),
+                Rgb(48, 48, 48),
+                Rgb(110, 110, 110),
+                Rgb(140, 110, 20),
+                Rgb(30, 110, 110),
+                Rgb(58, 58, 58),
+                Rgb(60, 80, 120),
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            name: name.to_string(),
+            status: Style::new(Some(status.0), Some(status.1)),
+            status_inactive: Style::new(Some(status_inactive.0), Some(status_inactive.1)),
+            error: Style::new(Some(error), None),
+            warning: Style::new(Some(warning), None),
+            selection: Style::new(None, Some(selection)),
+            cursor_line: Style::new(None, Some(cursor_line)),
+            gutter: Style::new(Some(gutter), None),
+            search: Style::new(None, Some(search)),
+            bracket: Style::new(None, Some(bracket)),
+            menu: Style::new(None, Some(menu)),
+            menu_selected: Style::new(None, Some(menu_selected)),
+            ..Self::plain(name)
+        })
+    }
+
+    // Everything in the terminal's own colors
+    fn plain(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            text: Style::default(),
+            status: Style::default(),
+            status_inactive: Style::default(),
+            message: Style::default(),
+            error: Style::default(),
+            warning: Style::default(),
+            selection: Style::default(),
+            cursor_line: Style::default(),
+            gutter: Style::default(),
+            search: Style::default(),
+            bracket: Style::default(),
+            menu: Style::default(),
+            menu_selected: Style::default(),
+            syntax: SYNTAX_FACES.iter().map(|(face, _)| (*face, Style::default())).collect(),
+        }
+    }
+
+    fn from_scheme(scheme: &Scheme) -> Self {
+        let background = scheme.settings.get(dump::BACKGROUND).copied().flatten();
+        let setting = |index: usize| {
+            scheme
+                .settings
+                .get(index)
+                .copied()
+                .flatten()
+                .map(|color| blend(color, background))
+        };
+        let foreground = setting(dump::FOREGROUND);
+        let background = setting(dump::BACKGROUND);
+        let selection = setting(dump::SELECTION);
+        let line_highlight = setting(dump::LINE_HIGHLIGHT);
+
+        let text = Style::new(foreground, background);
+        let scope = |wanted: &str| {
+            // The most specific item covering `wanted`, `constant` for `constant.numeric` say
+            scheme
+                .items
+                .iter()
+                .flat_map(|item| item.scopes.iter().map(move |scope| (scope, item)))
+                .filter(|(scope, _)| {
+                    wanted == scope.as_str() || wanted.starts_with(&format!("{}.", scope))
+                })
+                .max_by_key(|(scope, _)| scope.len())
+                .map(|(_, item)| {
+                    Style::new(
+                        item.foreground.map(|color| blend(color, background.map(rgba))),
+                        item.background.map(|color| blend(color, background.map(rgba))),
+                    )
+                })
+        };
+
+        Self {
+            name: scheme.name.clone(),
+            text,
+            status: Style::new(foreground, selection.or(line_highlight)),
+            status_inactive: Style::new(
+                setting(dump::GUTTER_FOREGROUND).or(foreground),
+                line_highlight.or(selection),
+            ),
+            message: text,
+            error: Style::new(scope("invalid").and_then(|style| style.fg).or(Some(ERROR_RED)), background),
+            warning: Style::new(
+                scope("invalid.deprecated").and_then(|style| style.fg).or(Some(WARNING_ORANGE)),
+                background,
+            ),
+            selection: Style::new(setting(dump::SELECTION_FOREGROUND), selection),
+            cursor_line: Style::new(None, line_highlight),
+            gutter: Style::new(setting(dump::GUTTER_FOREGROUND), setting(dump::GUTTER)),
+            search: Style::new(setting(dump::FIND_HIGHLIGHT_FOREGROUND), setting(dump::FIND_HIGHLIGHT)),
+            bracket: Style::new(
+                setting(dump::BRACKETS_FOREGROUND),
+                setting(dump::BRACKETS_BACKGROUND).or(selection),
+            ),
+            menu: Style::new(foreground, line_highlight.or(selection)),
+            menu_selected: Style::new(setting(dump::SELECTION_FOREGROUND), selection),
+            syntax: SYNTAX_FACES
+                .iter()
+                .map(|(face, wanted)| (*face, scope(wanted).unwrap_or_default()))
+                .collect(),
+        }
+    }
+
+    // `base <theme>` (first, optional) then `<face> fg=<color> bg=<color>` lines
+    fn from_file(name: &str, contents: &str, loading: &mut Vec<String>) -> Result<Self, String> {
+        let mut theme = Self::builtin("default").unwrap_or_else(|| Self::plain(name));
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('"') {
+                continue;
+            }
+            let at = |error: String| format!("{}:{}: {}", user_path(name).display(), number.saturating_add(1), error);
+            let (face, attributes) = line.split_at(line.find(' ').unwrap_or(line.len()));
+            let base = attributes.trim();
+            if face == "base" && loading.iter().any(|loaded| loaded == base) {
+                let chain: Vec<&str> = loading.iter().map(String::as_str).chain(Some(base)).collect();
+                return Err(at(format!("Themes based on each other: {}", chain.join(" -> "))));
+            } else if face == "base" {
+                theme = Self::load(base, loading).map_err(at)?;
+            } else {
+                theme.highlight(face, attributes).map_err(at)?;
+            }
+        }
+        theme.name = name.to_string();
+        Ok(theme)
+    }
+
+    fn face_mut(&mut self, face: &str) -> Option<&mut Style> {
+        match face {
+            "text" => Some(&mut self.text),
+            "status" => Some(&mut self.status),
+            "status_inactive" => Some(&mut self.status_inactive),
+            "message" => Some(&mut self.message),
+            "error" => Some(&mut self.error),
+            "warning" => Some(&mut self.warning),
+            "selection" => Some(&mut self.selection),
+            "cursor_line" => Some(&mut self.cursor_line),
+            "gutter" => Some(&mut self.gutter),
+            "search" => Some(&mut self.search),
+            "bracket" => Some(&mut self.bracket),
+            "menu" => Some(&mut self.menu),
+            "menu_selected" => Some(&mut self.menu_selected),
+            _ => self
+                .syntax
+                .iter_mut()
+                .find(|(name, _)| *name == face)
+                .map(|(_, style)| style),
+        }
+    }
+
+    // `highlight <face> fg=#rrggbb bg=none`; colors not mentioned are left alone
+    pub fn highlight(&mut self, face: &str, attributes: &str) -> Result<(), String> {
+        let style = self.face_mut(face).ok_or_else(|| format!("Unknown face: {}", face))?;
+        let mut changed = *style;
+        for attribute in attributes.split_whitespace() {
+            match attribute.find('=').map(|index| attribute.split_at(index)) {
+                Some(("fg", color)) => changed.fg = parse_color(&color[1..])?,
+                Some(("bg", color)) => changed.bg = parse_color(&color[1..])?,
+                _ => return Err(format!("Expected fg=<color> or bg=<color>, got `{}`", attribute)),
+            }
+        }
+        *style = changed;
+        Ok(())
+    }
+
+    // Every face as a `highlight` command would set it
+    pub fn faces(&self) -> Vec<String> {
+        let interface = [
+            ("text", self.text),
+            ("status", self.status),
+            ("status_inactive", self.status_inactive),
+            ("message", self.message),
+            ("error", self.error),
+            ("warning", self.warning),
+            ("selection", self.selection),
+            ("cursor_line", self.cursor_line),
+            ("gutter", self.gutter),
+            ("search", self.search),
+            ("bracket", self.bracket),
+            ("menu", self.menu),
+            ("menu_selected", self.menu_selected),
+        ];
+        interface
+            .iter()
+            .chain(self.syntax.iter())
+            .map(|(face, style)| format!("{:<16}fg={} bg={}", face, color_name(style.fg), color_name(style.bg)))
+            .collect()
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::builtin("default").unwrap_or_else(|| Self::plain("default"))
+    }
+}
+
+// Theme files live in `themes/` next to the config file
+fn user_path(name: &str) -> PathBuf {
+    let directory = config::path()
+        .and_then(|path| path.parent().map(|directory| directory.join("themes")))
+        .unwrap_or_default();
+    if name.is_empty() {
+        directory
+    } else {
+        directory.join(format!("{}.theme", name))
+    }
+}
+
+fn rgba(color: Rgb) -> Rgba {
+    (color.0, color.1, color.2, 255)
+}
+
+// Colors with some transparency are mixed into the background, as editors draw them
+#[allow(clippy::cast_possible_truncation, clippy::integer_arithmetic)]
+fn blend(color: Rgba, background: Option<Rgba>) -> Rgb {
+    let (r, g, b, alpha) = color;
+    let (br, bg, bb, _) = background.unwrap_or((0, 0, 0, 255));
+    let mix = |front: u8, back: u8| {
+        ((u32::from(front) * u32::from(alpha) + u32::from(back) * (255 - u32::from(alpha))) / 255) as u8
+    };
+    Rgb(mix(r, br), mix(g, bg), mix(b, bb))
+}
+
+// `#rrggbb`, or `none` for the terminal's own color
+fn parse_color(text: &str) -> Result<Option<Rgb>, String> {
+    if text == "none" {
+        return Ok(None);
+    }
+    let hex = text
+        .strip_prefix('#')
+        .filter(|hex| hex.len() == 6)
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| format!("Expected a color like #rrggbb or none, got `{}`", text))?;
+    #[allow(clippy::cast_possible_truncation)]
+    Ok(Some(Rgb((hex >> 16) as u8, (hex >> 8) as u8, hex as u8)))
+}
+
+fn color_name(color: Option<Rgb>) -> String {
+    color.map_or_else(|| "none".to_string(), |Rgb(r, g, b)| format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
@@ -0,0 +1,331 @@
+use std::mem;
+
+use termion::event::Key;
+
+use crate::error::Error;
+use crate::state::keymap::Action;
+use crate::state::register::Register;
+use crate::state::{Editor, Position, State, StatusMessage};
+use crate::{Document, Line};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SelectionKind {
+    Char,
+    Line,
+    Block,
+}
+
+/// Text picked out in visual mode; the cursor is the other end.
+#[derive(Clone)]
+pub struct Selection {
+    pub kind: SelectionKind,
+    pub anchor: Position,
+}
+
+// Where a block change was started, so that what gets typed can be copied to the other lines
+pub struct BlockInsert {
+    top: usize,
+    bottom: usize,
+    column: usize, // On screen
+}
+
+impl Selection {
+    // The first and last selected positions, in document order; for blocks the top left and
+    // bottom right corners
+    pub fn bounds(&self, cursor: &Position) -> (Position, Position) {
+        let (anchor, cursor) = (&self.anchor, cursor);
+        if self.kind == SelectionKind::Block {
+            return (
+                Position {
+                    x: anchor.x.min(cursor.x),
+                    y: anchor.y.min(cursor.y),
+                },
+                Position {
+                    x: anchor.x.max(cursor.x),
+                    y: anchor.y.max(cursor.y),
+                },
+            );
+        }
+        if (anchor.y, anchor.x) <= (cursor.y, cursor.x) {
+            (anchor.clone(), cursor.clone())
+        } else {
+            (cursor.clone(), anchor.clone())
+        }
+    }
+
+    // Screen columns a block covers, end exclusive: from the left edge of its leftmost corner
+    // to the right edge of its rightmost one, so that tabs and wide characters count as drawn
+    pub fn block_span(&self, cursor: &Position, document: &Document, tab_width: usize) -> (usize, usize) {
+        let span = |position: &Position| {
+            let line = document.line(position.y);
+            let left = line.map_or(0, |line| line.width_to(position.x, tab_width));
+            let right = line.map_or(0, |line| line.width_to(position.x.saturating_add(1), tab_width));
+            // Past the end of the line the cursor is on one blank column
+            (left, right.max(left.saturating_add(1)))
+        };
+        let (anchor, cursor) = (span(&self.anchor), span(cursor));
+        (anchor.0.min(cursor.0), anchor.1.max(cursor.1))
+    }
+
+    // Graphemes selected on line `y`, end exclusive; one past the end of the line stands for
+    // its line break
+    pub fn columns(
+        &self,
+        cursor: &Position,
+        y: usize,
+        document: &Document,
+        tab_width: usize,
+    ) -> Option<(usize, usize)> {
+        let (start, end) = self.bounds(cursor);
+        if y < start.y || y > end.y {
+            return None;
+        }
+        let len = document.line(y).map_or(0, Line::len);
+        let (from, to) = match self.kind {
+            SelectionKind::Char => (
+                if y == start.y { start.x } else { 0 },
+                if y == end.y { end.x.saturating_add(1) } else { usize::MAX },
+            ),
+            SelectionKind::Line => (0, usize::MAX),
+            SelectionKind::Block => {
+                let (left, right) = self.block_span(cursor, document, tab_width);
+                document.line(y).map_or((0, 0), |line| line.graphemes_between(left, right, tab_width))
+            }
+        };
+        let to = to.min(len.saturating_add(1));
+        if from < to {
+            Some((from, to))
+        } else {
+            None
+        }
+    }
+}
+
+impl Editor {
+    // Start selecting, switch to another kind of selection, or stop if it is already this kind
+    pub(super) fn visual(&mut self, kind: SelectionKind) {
+        let anchor = self.window().cur_pos.clone();
+        let window = self.window_mut();
+        match &mut window.selection {
+            Some(selection) if selection.kind == kind => {
+                window.selection = None;
+                self.mode = State::Normal;
+            }
+            Some(selection) => selection.kind = kind,
+            None => {
+                window.selection = Some(Selection { kind, anchor });
+                self.mode = State::Visual;
+            }
+        }
+    }
+
+    pub(super) fn swap_anchor(&mut self) {
+        let window = self.window_mut();
+        if let Some(selection) = &mut window.selection {
+            mem::swap(&mut selection.anchor, &mut window.cur_pos);
+        }
+    }
+
+    // Apply an operator to the selection; without one `~` works on the character under the
+    // cursor and the others on the current line
+    pub(super) fn operate(&mut self, action: Action) -> Result<(), Error> {
+        let cursor = self.window().cur_pos.clone();
+        let selected = self.window().selection.is_some();
+        let selection = self.window_mut().selection.take().unwrap_or_else(|| Selection {
+            kind: if action == Action::ToggleCase {
+                SelectionKind::Char
+            } else {
+                SelectionKind::Line
+            },
+            anchor: cursor.clone(),
+        });
+        let visual = self.mode == State::Visual;
+        if visual {
+            self.mode = State::Normal;
+        }
+        let (start, end) = selection.bounds(&cursor);
+        let rows = start.y..=end.y.min(self.document().len().saturating_sub(1));
+
+        match action {
+            Action::Yank => {
+                self.yank(&selection, &cursor, false)?;
+                let count = rows.count();
+                if count > 2 {
+                    self.status_message = StatusMessage::from(format!("{} lines yanked", count));
+                }
+                self.window_mut().cur_pos = start;
+            }
+            Action::Delete | Action::Change => {
+                self.yank(&selection, &cursor, true)?;
+                self.delete_selection(&selection, &cursor);
+                if action == Action::Change {
+                    if selection.kind == SelectionKind::Line {
+                        self.document_mut().insert_line(start.y, "");
+                        self.window_mut().cur_pos = Position { x: 0, y: start.y };
+                    } else if selection.kind == SelectionKind::Block && end.y > start.y {
+                        let (left, _) = selection.block_span(&cursor, self.document(), self.options.tab_width);
+                        self.block_insert = Some(BlockInsert {
+                            top: start.y,
+                            bottom: end.y,
+                            column: left,
+                        });
+                    }
+                    self.mode = State::Insert;
+                }
+            }
+            Action::Indent | Action::Outdent | Action::Reindent => {
+                if action == Action::Reindent {
+                    self.reindent(start.y, *rows.end());
+                } else {
+                    self.shift_lines(start.y, *rows.end(), action == Action::Indent);
+                }
+                self.window_mut().cur_pos = Position {
+                    x: self.first_non_blank(start.y),
+                    y: start.y,
+                };
+            }
+            Action::Reflow => self.reflow(start.y, *rows.end()),
+            Action::ToggleCase | Action::Lowercase | Action::Uppercase => {
+                let tab_width = self.options.tab_width;
+                for y in rows {
+                    if let Some((from, to)) = selection.columns(&cursor, y, self.document(), tab_width) {
+                        self.document_mut().edit_line(y, from, to, |text| change_case(text, action));
+                    }
+                }
+                if selected {
+                    self.window_mut().cur_pos = start;
+                } else {
+                    self.move_cursor(Key::Right);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Keep the selected text in a register, to be put back later
+    fn yank(&mut self, selection: &Selection, cursor: &Position, delete: bool) -> Result<(), Error> {
+        let (start, end) = selection.bounds(cursor);
+        let document = self.document();
+        let tab_width = self.options.tab_width;
+        let text = match selection.kind {
+            SelectionKind::Char => document.text(
+                &start,
+                &Position {
+                    x: end.x.saturating_add(1),
+                    y: end.y,
+                },
+            ),
+            SelectionKind::Line | SelectionKind::Block => (start.y..=end.y)
+                .filter_map(|y| {
+                    let line = document.line(y)?;
+                    Some(if selection.kind == SelectionKind::Line {
+                        line.as_str()
+                    } else {
+                        let (from, to) = selection.columns(cursor, y, document, tab_width).unwrap_or((0, 0));
+                        line.slice(from, to)
+                    })
+                })
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        };
+        self.store_register(
+            Register {
+                kind: selection.kind,
+                text,
+            },
+            delete,
+        )
+    }
+
+    pub(super) fn delete_selection(&mut self, selection: &Selection, cursor: &Position) {
+        let (start, end) = selection.bounds(cursor);
+        match selection.kind {
+            SelectionKind::Char => {
+                let after = Position {
+                    x: end.x.saturating_add(1),
+                    y: end.y,
+                };
+                self.document_mut().delete_text(&start, &after);
+                self.window_mut().cur_pos = start;
+            }
+            SelectionKind::Line => {
+                self.document_mut().delete_lines(start.y, end.y);
+                let y = start.y.min(self.document().len().saturating_sub(1));
+                self.window_mut().cur_pos = Position {
+                    x: self.first_non_blank(y),
+                    y,
+                };
+            }
+            SelectionKind::Block => {
+                let tab_width = self.options.tab_width;
+                let mut top = start.x;
+                for y in start.y..=end.y {
+                    if let Some((from, to)) = selection.columns(cursor, y, self.document(), tab_width) {
+                        if y == start.y {
+                            top = from;
+                        }
+                        self.document_mut().edit_line(y, from, to, |_| String::new());
+                    }
+                }
+                self.window_mut().cur_pos = Position { x: top, y: start.y };
+            }
+        }
+    }
+
+    // After a block change, type the same text on the other lines of the block
+    pub(super) fn finish_block_insert(&mut self) {
+        let insert = match self.block_insert.take() {
+            Some(insert) => insert,
+            None => return,
+        };
+        let tab_width = self.options.tab_width;
+        let cursor = self.window().cur_pos.clone();
+        let start = self.document().line(insert.top).map_or(0, |line| line.index_at(insert.column, tab_width));
+        if cursor.y != insert.top || cursor.x <= start {
+            return;
+        }
+        let text = self
+            .document()
+            .line(insert.top)
+            .map_or("", |line| line.slice(start, cursor.x))
+            .to_string();
+        // On the other lines it goes in at the same screen column, if they reach that far
+        for y in insert.top.saturating_add(1)..=insert.bottom {
+            let at = match self.document().line(y) {
+                Some(line) if line.width_to(line.len(), tab_width) >= insert.column => {
+                    line.index_at(insert.column, tab_width)
+                }
+                _ => continue,
+            };
+            self.document_mut().edit_line(y, at, at, |_| text.clone());
+        }
+    }
+
+    pub(super) fn first_non_blank(&self, y: usize) -> usize {
+        self.document().line(y).map_or(0, |line| {
+            line.as_str()
+                .chars()
+                .take_while(|c| c.is_whitespace())
+                .count()
+        })
+    }
+}
+
+fn change_case(text: &str, action: Action) -> String {
+    match action {
+        Action::Lowercase => text.to_lowercase(),
+        Action::Uppercase => text.to_uppercase(),
+        _ => text
+            .chars()
+            .flat_map(|c| {
+                let swapped: Vec<char> = if c.is_lowercase() {
+                    c.to_uppercase().collect()
+                } else {
+                    c.to_lowercase().collect()
+                };
+                swapped
+            })
+            .collect(),
+    }
+}
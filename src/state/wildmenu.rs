@@ -0,0 +1,191 @@
+use std::fs;
+use std::path::Path;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::state::command::COMMANDS;
+use crate::state::keymap::mapping_commands;
+use crate::state::session::session_names;
+use crate::state::theme::Theme;
+use crate::state::Editor;
+use crate::terminal::Screen;
+
+/// What Tab completes in a prompt.
+#[derive(Clone, Copy)]
+pub enum Completer {
+    Command, // Command names, then their arguments
+    File,
+}
+
+/// Candidates for the end of the prompt text, gone through with Tab and listed over the
+/// message bar.
+pub struct Wildmenu {
+    start: usize, // Byte where the completed part of the text starts
+    typed: String, // What it was before going through the candidates
+    candidates: Vec<String>,
+    selected: Option<usize>,
+}
+
+fn starting_with<I: IntoIterator<Item = String>>(names: I, prefix: &str) -> Vec<String> {
+    names.into_iter().filter(|name| name.starts_with(prefix)).collect()
+}
+
+// Entries of the directory `typed` is in whose names start with its last part, directories
+// with a `/` after them; dot files only when that part starts with a dot
+fn file_candidates(typed: &str) -> (usize, Vec<String>) {
+    let start = typed.rfind('/').map_or(0, |index| index.saturating_add(1));
+    let (dir, prefix) = typed.split_at(start);
+    let entries = match fs::read_dir(if dir.is_empty() { Path::new(".") } else { Path::new(dir) }) {
+        Ok(entries) => entries,
+        Err(_) => return (start, Vec::new()),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            Some(if entry.path().is_dir() { format!("{}/", name) } else { name })
+        })
+        .collect();
+    names.sort();
+    (start, names)
+}
+
+impl Editor {
+    // Where the part of `text` to complete starts, and what it could be
+    fn prompt_candidates(&self, completer: Completer, text: &str) -> (usize, Vec<String>) {
+        match completer {
+            Completer::File => file_candidates(text),
+            Completer::Command => self.command_candidates(text),
+        }
+    }
+
+    fn command_candidates(&self, text: &str) -> (usize, Vec<String>) {
+        // A range in front of the command is left alone
+        let command = text.trim_start_matches(|c: char| c.is_ascii_digit() || ".,$%+- ".contains(c));
+        let index = match command.find(' ') {
+            Some(index) => index,
+            None => {
+                let names = COMMANDS.iter().map(|name| (*name).to_string()).chain(mapping_commands());
+                let mut names = starting_with(names, command);
+                names.sort();
+                return (text.len().saturating_sub(command.len()), names);
+            }
+        };
+        let name = command.get(..index).unwrap_or("").trim_end_matches('!');
+        let argument = command.get(index..).unwrap_or("").trim_start();
+        let start = text.len().saturating_sub(argument.len());
+        let names = |names: Vec<&str>| starting_with(names.into_iter().map(String::from), argument);
+        let candidates = match name {
+            "set" if argument.contains('=') => Vec::new(),
+            "set" | "get" => names(self.options.names(false)),
+            "unset" | "toggle" => names(self.options.names(true)),
+            "theme" => starting_with(Theme::list(), argument),
+            "session" | "mksession" | "mks" => starting_with(session_names(), argument),
+            // Buffers go by any part of their name
+            "b" => {
+                let mut buffers: Vec<String> = Vec::new();
+                for document in &self.documents {
+                    let shown = document.display_name();
+                    if shown.contains(argument) && !buffers.contains(&shown) {
+                        buffers.push(shown);
+                    }
+                }
+                buffers
+            }
+            "o" | "e" | "w" | "sp" | "split" | "vs" | "vsplit" | "tabnew" | "tabe" | "source" | "cfile" | "cf" => {
+                let (offset, files) = file_candidates(argument);
+                return (start.saturating_add(offset), files);
+            }
+            _ => Vec::new(),
+        };
+        (start, candidates)
+    }
+
+    // Tab and Shift-Tab in a prompt: complete the end of `text`, going through the candidates
+    // when there is more than one, and past either end back to what was typed
+    pub(super) fn complete_prompt(&mut self, completer: Completer, text: &mut String, forward: bool) {
+        if self.wildmenu.is_none() {
+            let (start, mut candidates) = self.prompt_candidates(completer, text);
+            if candidates.len() == 1 {
+                text.truncate(start);
+                text.push_str(&candidates.remove(0));
+                return;
+            }
+            if candidates.is_empty() {
+                return;
+            }
+            self.wildmenu = Some(Wildmenu {
+                start,
+                typed: text.get(start..).unwrap_or("").to_string(),
+                candidates,
+                selected: None,
+            });
+        }
+        if let Some(menu) = self.wildmenu.as_mut() {
+            let last = menu.candidates.len().saturating_sub(1);
+            menu.selected = match (menu.selected, forward) {
+                (None, true) => Some(0),
+                (None, false) => Some(last),
+                (Some(index), true) if index >= last => None,
+                (Some(index), true) => Some(index.saturating_add(1)),
+                (Some(0), false) => None,
+                (Some(index), false) => Some(index.saturating_sub(1)),
+            };
+            text.truncate(menu.start);
+            match menu.selected.and_then(|index| menu.candidates.get(index)) {
+                Some(candidate) => text.push_str(candidate),
+                None => text.push_str(&menu.typed),
+            }
+        }
+    }
+
+    // The candidates on the line above the message bar, scrolled sideways to show the
+    // selected one, with `<` and `>` where more are cut off
+    pub(super) fn draw_wildmenu(&self, screen: &mut Screen) {
+        let menu = match &self.wildmenu {
+            Some(menu) => menu,
+            None => return,
+        };
+        let row = match screen.height().checked_sub(2) {
+            Some(row) => row,
+            None => return,
+        };
+        let width = screen.width();
+        let widths: Vec<usize> = menu
+            .candidates
+            .iter()
+            .map(|candidate| candidate.graphemes(true).count().saturating_add(2))
+            .collect();
+        // Room between the `<` and `>` columns
+        let room = width.saturating_sub(2);
+        let mut first = 0;
+        if let Some(selected) = menu.selected {
+            while first < selected && widths.iter().take(selected.saturating_add(1)).skip(first).sum::<usize>() > room {
+                first = first.saturating_add(1);
+            }
+        }
+
+        screen.fill(0, row, width, self.theme.menu);
+        if first > 0 {
+            screen.put_str(0, row, "<", self.theme.menu);
+        }
+        let mut x: usize = 1;
+        for (index, candidate) in menu.candidates.iter().enumerate().skip(first) {
+            let needed = widths.get(index).copied().unwrap_or(0);
+            if x.saturating_add(needed) > width.saturating_sub(1) {
+                screen.put_str(width.saturating_sub(1), row, ">", self.theme.menu);
+                break;
+            }
+            let style = if menu.selected == Some(index) {
+                self.theme.menu_selected
+            } else {
+                self.theme.menu
+            };
+            screen.put_str(x.saturating_add(1), row, candidate, style);
+            x = x.saturating_add(needed);
+        }
+    }
+}
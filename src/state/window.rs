@@ -0,0 +1,472 @@
+use std::iter::Peekable;
+use std::mem;
+use std::str::Chars;
+
+use crate::state::mark::JumpList;
+use crate::state::visual::Selection;
+use crate::state::Position;
+
+// Smallest window we will shrink to: one text row plus the status line, one column
+const MIN_HEIGHT: usize = 2;
+const MIN_WIDTH: usize = 1;
+// Splits remember how much of their space goes to the first child, in thousandths
+const SHARE_TOTAL: usize = 1000;
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// A view on a document, with its own cursor and scroll offset.
+#[derive(Clone)]
+pub struct Window {
+    pub document: usize, // Index into the editor's documents
+    pub cur_pos: Position,
+    pub offset: Position,
+    pub rect: Rect, // Screen area including the status line, set by `Layout::arrange`
+    pub gutter: usize, // Columns taken by line numbers
+    pub selection: Option<Selection>, // Only while in visual mode
+    pub jumps: JumpList,
+}
+
+impl Window {
+    pub fn new(document: usize) -> Self {
+        Self {
+            document,
+            cur_pos: Position::default(),
+            offset: Position::default(),
+            rect: Rect::default(),
+            gutter: 0,
+            selection: None,
+            jumps: JumpList::default(),
+        }
+    }
+
+    pub fn text_height(&self) -> usize {
+        self.rect.height.saturating_sub(1)
+    }
+
+    pub fn text_width(&self) -> usize {
+        self.rect.width.saturating_sub(self.gutter)
+    }
+}
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum SplitDirection {
+    Horizontal, // One window above the other
+    Vertical, // Side by side
+}
+
+enum Node {
+    Leaf(usize),
+    Split {
+        direction: SplitDirection,
+        share: usize,
+        extent: usize, // Rows or columns available to both children at the last arrange
+        first: Box<Node>,
+        second: Box<Node>,
+    },
+}
+
+enum Resized {
+    NotFound,
+    Found,
+    Done,
+}
+
+/// The windows on screen and how they are split.
+pub struct Layout {
+    pub windows: Vec<Window>,
+    pub focus: usize,
+    pub separators: Vec<Rect>, // Columns between side by side windows, set by `arrange`
+    root: Node,
+}
+
+impl Layout {
+    pub fn new(document: usize) -> Self {
+        Self {
+            windows: vec![Window::new(document)],
+            focus: 0,
+            separators: Vec::new(),
+            root: Node::Leaf(0),
+        }
+    }
+
+    #[allow(clippy::indexing_slicing)]
+    pub fn focused(&self) -> &Window {
+        &self.windows[self.focus]
+    }
+
+    #[allow(clippy::indexing_slicing)]
+    pub fn focused_mut(&mut self) -> &mut Window {
+        &mut self.windows[self.focus]
+    }
+
+    // Split the focused window in two; the new half goes above (or left) and takes the focus
+    pub fn split(&mut self, direction: SplitDirection) {
+        let window = self.focused().clone();
+        let new = self.windows.len();
+        self.windows.push(window);
+
+        let focus = self.focus;
+        Self::replace_leaf(&mut self.root, focus, &mut |leaf| Node::Split {
+            direction,
+            share: SHARE_TOTAL / 2,
+            extent: 0,
+            first: Box::new(Node::Leaf(new)),
+            second: Box::new(leaf),
+        });
+        self.focus = new;
+    }
+
+    // Close the focused window; the last one cannot be closed
+    pub fn close(&mut self) -> bool {
+        if self.windows.len() <= 1 {
+            return false;
+        }
+        let closed = self.focus;
+        let root = mem::replace(&mut self.root, Node::Leaf(0));
+        self.root = Self::remove_leaf(root, closed).unwrap_or(Node::Leaf(0));
+        self.windows.remove(closed);
+        self.focus = closed.saturating_sub(1).min(self.windows.len().saturating_sub(1));
+        true
+    }
+
+    // Close every window but the focused one
+    pub fn only(&mut self) {
+        let window = self.focused().clone();
+        self.windows = vec![window];
+        self.focus = 0;
+        self.root = Node::Leaf(0);
+    }
+
+    pub fn cycle(&mut self, forward: bool) {
+        let count = self.windows.len();
+        self.focus = if forward {
+            self.focus.saturating_add(1) % count
+        } else {
+            self.focus.checked_sub(1).unwrap_or(count.saturating_sub(1))
+        };
+    }
+
+    // Focus the neighbouring window in `direction`, `forward` meaning below or right
+    pub fn focus_towards(&mut self, direction: SplitDirection, forward: bool) {
+        let current = self.focused().rect;
+        let cursor = self.focused().cur_pos.clone();
+        let offset = self.focused().offset.clone();
+        let (cursor_x, cursor_y) = (
+            current.x.saturating_add(cursor.x.saturating_sub(offset.x)),
+            current.y.saturating_add(cursor.y.saturating_sub(offset.y)),
+        );
+
+        let mut best: Option<(usize, usize, bool)> = None; // (window, distance, lines up with cursor)
+        for (index, window) in self.windows.iter().enumerate() {
+            let rect = window.rect;
+            let (distance, overlaps, aligned) = match direction {
+                SplitDirection::Horizontal => {
+                    let distance = if forward {
+                        rect.y.checked_sub(current.y.saturating_add(current.height))
+                    } else {
+                        current.y.checked_sub(rect.y.saturating_add(rect.height))
+                    };
+                    (
+                        distance,
+                        rect.x < current.x.saturating_add(current.width) && current.x < rect.x.saturating_add(rect.width),
+                        rect.x <= cursor_x && cursor_x < rect.x.saturating_add(rect.width),
+                    )
+                }
+                SplitDirection::Vertical => {
+                    let distance = if forward {
+                        rect.x.checked_sub(current.x.saturating_add(current.width))
+                    } else {
+                        current.x.checked_sub(rect.x.saturating_add(rect.width))
+                    };
+                    (
+                        distance,
+                        rect.y < current.y.saturating_add(current.height) && current.y < rect.y.saturating_add(rect.height),
+                        rect.y <= cursor_y && cursor_y < rect.y.saturating_add(rect.height),
+                    )
+                }
+            };
+            if let Some(distance) = distance.filter(|_| overlaps) {
+                let better = best.map_or(true, |(_, best_distance, best_aligned)| {
+                    distance < best_distance || (distance == best_distance && aligned && !best_aligned)
+                });
+                if better {
+                    best = Some((index, distance, aligned));
+                }
+            }
+        }
+        if let Some((index, _, _)) = best {
+            self.focus = index;
+        }
+    }
+
+    // Grow (or shrink, for a negative `delta`) the focused window by `delta` rows or columns
+    pub fn resize(&mut self, direction: SplitDirection, delta: isize) -> bool {
+        let focus = self.focus;
+        matches!(Self::resize_node(&mut self.root, focus, direction, delta), Resized::Done)
+    }
+
+    // Give both sides of every split the same share
+    pub fn equalize(&mut self) {
+        Self::equalize_node(&mut self.root);
+    }
+
+    // Windows showing the document at `removed` switch to `replacement`, later indices shift down
+    pub fn remove_document(&mut self, removed: usize, replacement: usize) {
+        for window in &mut self.windows {
+            window.jumps.remove_document(removed);
+            if window.document == removed {
+                window.document = replacement;
+                window.cur_pos = Position::default();
+                window.offset = Position::default();
+            }
+            if window.document > removed {
+                window.document = window.document.saturating_sub(1);
+            }
+        }
+    }
+
+    // The splits written out for a session: a window number, or `h<share>(<first>,<second>)`
+    // for one part above the other and `v...` for side by side
+    pub fn tree(&self) -> String {
+        Self::node_tree(&self.root)
+    }
+
+    // Windows laid out the way `tree` says, as long as every one of them is in it once
+    pub fn from_tree(tree: &str, windows: Vec<Window>, focus: usize) -> Option<Self> {
+        let mut chars = tree.chars().peekable();
+        let root = Self::parse_node(&mut chars)?;
+        let mut leaves = Vec::new();
+        Self::leaves(&root, &mut leaves);
+        leaves.sort_unstable();
+        if chars.next().is_some() || !leaves.iter().copied().eq(0..windows.len()) {
+            return None;
+        }
+        Some(Self {
+            focus: focus.min(windows.len().saturating_sub(1)),
+            windows,
+            separators: Vec::new(),
+            root,
+        })
+    }
+
+    // Work out where every window goes inside `area`
+    pub fn arrange(&mut self, area: Rect) {
+        self.separators.clear();
+        Self::arrange_node(&mut self.root, area, &mut self.windows, &mut self.separators);
+    }
+
+    fn replace_leaf(node: &mut Node, window: usize, with: &mut dyn FnMut(Node) -> Node) -> bool {
+        match node {
+            Node::Leaf(index) if *index == window => {
+                let leaf = mem::replace(node, Node::Leaf(window));
+                *node = with(leaf);
+                true
+            }
+            Node::Leaf(_) => false,
+            Node::Split { first, second, .. } => {
+                Self::replace_leaf(first, window, with) || Self::replace_leaf(second, window, with)
+            }
+        }
+    }
+
+    fn remove_leaf(node: Node, window: usize) -> Option<Node> {
+        match node {
+            Node::Leaf(index) if index == window => None,
+            Node::Leaf(index) => Some(Node::Leaf(if index > window { index.saturating_sub(1) } else { index })),
+            Node::Split {
+                direction,
+                share,
+                extent,
+                first,
+                second,
+            } => match (Self::remove_leaf(*first, window), Self::remove_leaf(*second, window)) {
+                (Some(first), Some(second)) => Some(Node::Split {
+                    direction,
+                    share,
+                    extent,
+                    first: Box::new(first),
+                    second: Box::new(second),
+                }),
+                (Some(node), None) | (None, Some(node)) => Some(node),
+                (None, None) => None,
+            },
+        }
+    }
+
+    fn resize_node(node: &mut Node, window: usize, direction: SplitDirection, delta: isize) -> Resized {
+        match node {
+            Node::Leaf(index) => {
+                if *index == window {
+                    Resized::Found
+                } else {
+                    Resized::NotFound
+                }
+            }
+            Node::Split {
+                direction: split,
+                share,
+                extent,
+                first,
+                second,
+            } => {
+                let grow_first = match Self::resize_node(first, window, direction, delta) {
+                    Resized::Done => return Resized::Done,
+                    Resized::Found => delta,
+                    Resized::NotFound => match Self::resize_node(second, window, direction, delta) {
+                        Resized::Done => return Resized::Done,
+                        Resized::Found => delta.saturating_neg(),
+                        Resized::NotFound => return Resized::NotFound,
+                    },
+                };
+                if *split != direction || *extent == 0 {
+                    return Resized::Found;
+                }
+                let size = Self::first_size(*split, *share, *extent) as isize;
+                let size = size.saturating_add(grow_first).max(0) as usize;
+                *share = size
+                    .saturating_mul(SHARE_TOTAL)
+                    .saturating_add(extent.saturating_sub(1))
+                    .checked_div(*extent)
+                    .unwrap_or(0)
+                    .min(SHARE_TOTAL);
+                Resized::Done
+            }
+        }
+    }
+
+    fn equalize_node(node: &mut Node) {
+        if let Node::Split { share, first, second, .. } = node {
+            *share = SHARE_TOTAL / 2;
+            Self::equalize_node(first);
+            Self::equalize_node(second);
+        }
+    }
+
+    // Size of the first child of a split over `extent` cells, leaving room for both children
+    fn first_size(direction: SplitDirection, share: usize, extent: usize) -> usize {
+        let minimum = match direction {
+            SplitDirection::Horizontal => MIN_HEIGHT,
+            SplitDirection::Vertical => MIN_WIDTH,
+        };
+        let size = extent.saturating_mul(share) / SHARE_TOTAL;
+        size.min(extent.saturating_sub(minimum)).max(minimum).min(extent)
+    }
+
+    fn node_tree(node: &Node) -> String {
+        match node {
+            Node::Leaf(index) => index.to_string(),
+            Node::Split {
+                direction,
+                share,
+                first,
+                second,
+                ..
+            } => format!(
+                "{}{}({},{})",
+                if *direction == SplitDirection::Horizontal { 'h' } else { 'v' },
+                share,
+                Self::node_tree(first),
+                Self::node_tree(second)
+            ),
+        }
+    }
+
+    fn parse_node(chars: &mut Peekable<Chars>) -> Option<Node> {
+        let direction = match chars.peek() {
+            Some('h') => SplitDirection::Horizontal,
+            Some('v') => SplitDirection::Vertical,
+            _ => return Some(Node::Leaf(Self::parse_number(chars)?)),
+        };
+        chars.next();
+        let share = Self::parse_number(chars)?.min(SHARE_TOTAL);
+        let expect = |chars: &mut Peekable<Chars>, c: char| chars.next().filter(|next| *next == c);
+        expect(chars, '(')?;
+        let first = Self::parse_node(chars)?;
+        expect(chars, ',')?;
+        let second = Self::parse_node(chars)?;
+        expect(chars, ')')?;
+        Some(Node::Split {
+            direction,
+            share,
+            extent: 0,
+            first: Box::new(first),
+            second: Box::new(second),
+        })
+    }
+
+    fn parse_number(chars: &mut Peekable<Chars>) -> Option<usize> {
+        let mut digits = String::new();
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            digits.push(digit);
+        }
+        digits.parse().ok()
+    }
+
+    fn leaves(node: &Node, found: &mut Vec<usize>) {
+        match node {
+            Node::Leaf(index) => found.push(*index),
+            Node::Split { first, second, .. } => {
+                Self::leaves(first, found);
+                Self::leaves(second, found);
+            }
+        }
+    }
+
+    fn arrange_node(node: &mut Node, area: Rect, windows: &mut [Window], separators: &mut Vec<Rect>) {
+        match node {
+            Node::Leaf(index) => {
+                if let Some(window) = windows.get_mut(*index) {
+                    window.rect = area;
+                }
+            }
+            Node::Split {
+                direction,
+                share,
+                extent,
+                first,
+                second,
+            } => {
+                let (first_area, second_area) = match direction {
+                    SplitDirection::Horizontal => {
+                        *extent = area.height;
+                        let height = Self::first_size(*direction, *share, *extent);
+                        (
+                            Rect { height, ..area },
+                            Rect {
+                                y: area.y.saturating_add(height),
+                                height: area.height.saturating_sub(height),
+                                ..area
+                            },
+                        )
+                    }
+                    SplitDirection::Vertical => {
+                        // One column goes to the separator
+                        *extent = area.width.saturating_sub(1);
+                        let width = Self::first_size(*direction, *share, *extent);
+                        separators.push(Rect {
+                            x: area.x.saturating_add(width),
+                            width: 1,
+                            ..area
+                        });
+                        (
+                            Rect { width, ..area },
+                            Rect {
+                                x: area.x.saturating_add(width).saturating_add(1),
+                                width: extent.saturating_sub(width),
+                                ..area
+                            },
+                        )
+                    }
+                };
+                Self::arrange_node(first, first_area, windows, separators);
+                Self::arrange_node(second, second_area, windows, separators);
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::env;
+use std::fmt::Write;
+
+use termion::color::Rgb;
+
+// xterm's defaults for the 16 basic colors
+const ANSI_16: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+// Levels of each channel in the 6x6x6 cube of the 256 color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// How many colors the terminal can show.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Depth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+impl Depth {
+    // Guess from the environment, the same way most terminal programs do
+    pub fn detect() -> Self {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            Self::TrueColor
+        } else if term.contains("256") {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "truecolor" | "24bit" => Some(Self::TrueColor),
+            "256" => Some(Self::Ansi256),
+            "16" => Some(Self::Ansi16),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::TrueColor => "truecolor",
+            Self::Ansi256 => "256",
+            Self::Ansi16 => "16",
+        }
+    }
+
+    // Escape sequence setting the foreground (or background) to the closest color we can show
+    pub fn write(self, out: &mut String, color: Rgb, background: bool) {
+        let Rgb(r, g, b) = color;
+        let _ = match self {
+            Self::TrueColor => write!(out, "\x1b[{};2;{};{};{}m", if background { 48 } else { 38 }, r, g, b),
+            Self::Ansi256 => write!(out, "\x1b[{};5;{}m", if background { 48 } else { 38 }, nearest_256(color)),
+            Self::Ansi16 => {
+                let index = nearest(color, ANSI_16.iter().copied()) as u8;
+                let code = match (index < 8, background) {
+                    (true, false) => 30_u8.saturating_add(index),
+                    (true, true) => 40_u8.saturating_add(index),
+                    (false, false) => 82_u8.saturating_add(index),
+                    (false, true) => 92_u8.saturating_add(index),
+                };
+                write!(out, "\x1b[{}m", code)
+            }
+        };
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let channel = |x: u8, y: u8| {
+        let difference = u32::from(x.max(y).saturating_sub(x.min(y)));
+        difference.saturating_mul(difference)
+    };
+    channel(a.0, b.0)
+        .saturating_add(channel(a.1, b.1))
+        .saturating_add(channel(a.2, b.2))
+}
+
+// Index of the closest of `palette` to `color`
+fn nearest(color: Rgb, palette: impl Iterator<Item = (u8, u8, u8)>) -> usize {
+    let Rgb(r, g, b) = color;
+    palette
+        .enumerate()
+        .min_by_key(|(_, candidate)| distance((r, g, b), *candidate))
+        .map_or(0, |(index, _)| index)
+}
+
+// Either a color of the 6x6x6 cube or one of the 24 grays, whichever is closer
+#[allow(clippy::cast_possible_truncation, clippy::integer_arithmetic)]
+fn nearest_256(color: Rgb) -> u8 {
+    let Rgb(r, g, b) = color;
+    let level = |value: u8| nearest(Rgb(value, value, value), CUBE_LEVELS.iter().map(|level| (*level, *level, *level)));
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]);
+
+    let average = (u32::from(r) + u32::from(g) + u32::from(b)) / 3;
+    let gray_index = (average.saturating_sub(3) / 10).min(23);
+    let gray_level = (8 + gray_index * 10) as u8;
+
+    if distance((r, g, b), (gray_level, gray_level, gray_level)) < distance((r, g, b), cube) {
+        232 + gray_index as u8
+    } else {
+        (16 + 36 * ri + 6 * gi + bi) as u8
+    }
+}
@@ -1,12 +1,36 @@
+use std::collections::VecDeque;
 use std::io::{self, stdout, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
-use termion::input::TermRead;
+use termion::input::TermReadEventsAndRaw;
 use termion::raw::{IntoRawMode, RawTerminal};
-use termion::event::Key;
-use termion::color;
+use termion::event::{self as termion_event, Key};
 use termion::screen::AlternateScreen;
 
-use crate::state::Position;
+use crate::error::Error;
+
+pub mod color;
+mod restore;
+pub mod screen;
+
+pub use color::Depth;
+pub use screen::{Screen, Style};
+
+// How often a blocked read looks for a termination signal
+const SIGNAL_POLL: Duration = Duration::from_millis(100);
+// Bracketed paste: the terminal wraps pasted text in these instead of passing it off as typing
+const PASTE_ON: &str = "\x1b[?2004h";
+pub const PASTE_OFF: &str = "\x1b[?2004l";
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Something that came from the keyboard.
+pub enum Event {
+    Key(Key),
+    Paste(String), // With `\n` line breaks, whatever the terminal sent
+}
 
 pub struct  Size {
     pub height: u16,
@@ -14,87 +38,172 @@ pub struct  Size {
 }
 
 impl Size {
+    // Falls back to the size at startup if the terminal cannot be queried
     pub fn height(&self) -> u16 {
-        let size = termion::terminal_size().unwrap();
-        size.1.saturating_sub(2)
+        termion::terminal_size().map_or(self.height, |size| size.1.saturating_sub(2))
     }
 
     pub fn width(&self) -> u16 {
-        let size = termion::terminal_size().unwrap();
-        size.0
+        termion::terminal_size().map_or(self.width, |size| size.0)
     }
 }
 
 pub struct Terminal {
     pub size: Size,
-    pub _stdout: RawTerminal<AlternateScreen<std::io::Stdout>>,
+    stdout: RawTerminal<AlternateScreen<std::io::Stdout>>,
+    events: Receiver<Result<Event, io::Error>>,
+    pasted: VecDeque<Key>, // Rest of a paste that arrived where only keys make sense
+    previous: Option<Screen>, // Last frame flushed to the terminal
+    colors: Depth,
 }
 
 impl Terminal {
-    pub fn new() -> Result<Self, std::io::Error> {
-        let size = termion::terminal_size()?;
+    pub fn new() -> Result<Self, Error> {
+        let size = termion::terminal_size().map_err(Error::Terminal)?;
+        restore::install();
 
-        Ok(Self {
+        let mut terminal = Self {
             size: Size {
                 height: size.1.saturating_sub(2),
                 width: size.0,
             },
-            _stdout: AlternateScreen::from(stdout()).into_raw_mode()?,
-        })
+            stdout: AlternateScreen::from(stdout()).into_raw_mode().map_err(Error::Terminal)?,
+            events: Self::spawn_reader(),
+            pasted: VecDeque::new(),
+            previous: None,
+            colors: Depth::detect(),
+        };
+        terminal.write_raw(PASTE_ON)?;
+        Ok(terminal)
     }
 
-    pub fn set_fg_color(color: color::Rgb) {
-        print!("{}", color::Fg(color));
+    // Keys are read on their own thread so the editor can wake up while the user is idle
+    fn spawn_reader() -> Receiver<Result<Event, io::Error>> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut paste: Option<Vec<u8>> = None;
+            for event in io::stdin().events_and_raw() {
+                let event = match (event, &mut paste) {
+                    (Ok((_, raw)), None) if raw == PASTE_START => {
+                        paste = Some(Vec::new());
+                        continue;
+                    }
+                    (Ok((_, raw)), Some(_)) if raw == PASTE_END => {
+                        let text = String::from_utf8_lossy(&paste.take().unwrap_or_default())
+                            .replace("\r\n", "\n")
+                            .replace('\r', "\n");
+                        Ok(Event::Paste(text))
+                    }
+                    (Ok((_, raw)), Some(paste)) => {
+                        paste.extend_from_slice(&raw);
+                        continue;
+                    }
+                    (Ok((termion_event::Event::Key(key), _)), None) => Ok(Event::Key(key)),
+                    (Ok(_), None) => continue,
+                    (Err(error), _) => Err(error),
+                };
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
     }
 
-    pub fn reset_fg_color() {
-         print!("{}", color::Fg(color::Reset));
-     }
+    // A blank frame covering the whole terminal, status and message bars included
+    pub fn frame(&self) -> Screen {
+        Screen::new(
+            self.size.width() as usize,
+            (self.size.height() as usize).saturating_add(2),
+        )
+    }
 
-    pub fn set_bg_color(color: color::Rgb) {
-        print!("{}", color::Bg(color));
+    // Flush the cells that changed since the last frame in a single write
+    pub fn render(&mut self, screen: Screen) -> Result<(), Error> {
+        let out = screen.diff(self.previous.as_ref(), self.colors);
+        self.previous = Some(screen);
+        self.stdout
+            .write_all(out.as_bytes())
+            .and_then(|()| self.stdout.flush())
+            .map_err(Error::Terminal)
     }
 
-    pub fn reset_bg_color() {
-        print!("{}", color::Bg(color::Reset));
+    // Colors are picked for `depth` from the next frame on, which is then drawn in full
+    pub fn set_colors(&mut self, depth: Depth) {
+        if depth != self.colors {
+            self.colors = depth;
+            self.previous = None;
+        }
     }
 
-    pub fn size(&self) -> &Size {
-        &self.size
+    // Escape sequences that are not part of a frame, such as clipboard requests
+    pub fn write_raw(&mut self, sequence: &str) -> Result<(), Error> {
+        self.stdout
+            .write_all(sequence.as_bytes())
+            .and_then(|()| self.stdout.flush())
+            .map_err(Error::Terminal)
     }
 
     pub fn clear_screen() {
         print!("{}", termion::clear::All);
     }
 
-    pub fn cursor_pos(position: &Position) {
-        let Position{x, y} = position;
-        let x = x.saturating_add(1);
-        let y = y.saturating_add(1);
-        print!("{}", termion::cursor::Goto(x as u16, y as u16));
+    pub fn flush() -> Result<(), Error> {
+        io::stdout().flush().map_err(Error::Terminal)
     }
 
-    pub fn flush() -> Result<(), std::io::Error> {
-        io::stdout().flush()
+    pub fn read_key(&mut self) -> Result<Key, Error> {
+        loop {
+            if let Some(key) = self.read_key_timeout(SIGNAL_POLL)? {
+                return Ok(key);
+            }
+        }
     }
 
-    pub fn read_key() -> Result<Key, std::io::Error> {
-        loop {
-            if let Some(key) = io::stdin().lock().keys().next() {
-                return key;
+    // Like `read_key`, but gives up with `None` once `timeout` passes without input; a paste
+    // comes out as the keys that would type it
+    pub fn read_key_timeout(&mut self, timeout: Duration) -> Result<Option<Key>, Error> {
+        if let Some(key) = self.pasted.pop_front() {
+            return Ok(Some(key));
+        }
+        match self.read_event_timeout(timeout)? {
+            Some(Event::Key(key)) => Ok(Some(key)),
+            Some(Event::Paste(text)) => {
+                self.pasted.extend(text.chars().map(Key::Char));
+                Ok(self.pasted.pop_front())
             }
+            None => Ok(None),
         }
     }
 
-    pub fn cursor_hide() {
-        print!("{}", termion::cursor::Hide);
+    pub fn read_event_timeout(&mut self, timeout: Duration) -> Result<Option<Event>, Error> {
+        if let Some(key) = self.pasted.pop_front() {
+            return Ok(Some(Event::Key(key)));
+        }
+        let mut waited = Duration::from_secs(0);
+        loop {
+            if let Some(signal) = restore::pending_signal() {
+                return Err(Error::Signal(restore::signal_name(signal)));
+            }
+            if waited >= timeout {
+                return Ok(None);
+            }
+            let wait = SIGNAL_POLL.min(timeout - waited);
+            match self.events.recv_timeout(wait) {
+                Ok(event) => return event.map(Some).map_err(Error::Terminal),
+                Err(RecvTimeoutError::Timeout) => waited += wait,
+                Err(RecvTimeoutError::Disconnected) => return Err(Self::closed()),
+            }
+        }
     }
 
-    pub fn cursor_show() {
-        print!("{}", termion::cursor::Show);
+    fn closed() -> Error {
+        Error::Terminal(io::Error::new(io::ErrorKind::UnexpectedEof, "input closed"))
     }
+}
 
-    pub fn clear_current_line() {
-        print!("{}", termion::clear::CurrentLine);
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.write_raw(PASTE_OFF);
     }
-}
\ No newline at end of file
+}
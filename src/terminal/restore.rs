@@ -0,0 +1,77 @@
+use std::io::{self, Write};
+use std::panic;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// Last termination signal we received, 0 if none
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+// Remember the cooked terminal settings and make sure they come back if we panic or get killed
+pub fn install() {
+    let original = termios();
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        leave(original.as_ref());
+        default_hook(info);
+    }));
+
+    // The handler only records the signal; the event loop notices it and shuts down cleanly
+    #[allow(unsafe_code)]
+    unsafe {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGHUP, handler);
+    }
+}
+
+pub fn pending_signal() -> Option<i32> {
+    match SIGNAL.swap(0, Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+pub fn signal_name(signal: i32) -> &'static str {
+    match signal {
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGHUP => "SIGHUP",
+        _ => "signal",
+    }
+}
+
+#[allow(unsafe_code)]
+fn termios() -> Option<libc::termios> {
+    unsafe {
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDOUT_FILENO, &mut termios) == 0 {
+            Some(termios)
+        } else {
+            None
+        }
+    }
+}
+
+// Back to the main screen in cooked mode with a visible cursor, so whatever is printed next is readable
+fn leave(original: Option<&libc::termios>) {
+    let mut stdout = io::stdout();
+    let _ = write!(
+        stdout,
+        "{}{}{}{}",
+        super::PASTE_OFF,
+        termion::style::Reset,
+        termion::cursor::Show,
+        termion::screen::ToMainScreen
+    );
+    let _ = stdout.flush();
+
+    if let Some(original) = original {
+        #[allow(unsafe_code)]
+        unsafe {
+            libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, original);
+        }
+    }
+}
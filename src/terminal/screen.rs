@@ -0,0 +1,217 @@
+use std::fmt::Write;
+
+use termion::color;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::state::Position;
+use crate::terminal::color::Depth;
+
+// Unchanged cells between two changes that are rewritten instead of jumped over
+const SHORT_GAP: usize = 4;
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub fg: Option<color::Rgb>,
+    pub bg: Option<color::Rgb>,
+}
+
+impl Style {
+    pub fn new(fg: Option<color::Rgb>, bg: Option<color::Rgb>) -> Self {
+        Self { fg, bg }
+    }
+
+    // Colors this style leaves unset are taken from `base`
+    pub fn or(self, base: Self) -> Self {
+        Self {
+            fg: self.fg.or(base.fg),
+            bg: self.bg.or(base.bg),
+        }
+    }
+
+    // Escape sequence switching the terminal from any style to this one
+    fn escape(self, out: &mut String, depth: Depth) {
+        let _ = write!(out, "{}", termion::style::Reset);
+        if let Some(fg) = self.fg {
+            depth.write(out, fg, false);
+        }
+        if let Some(bg) = self.bg {
+            depth.write(out, bg, true);
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Cell {
+    symbol: String, // A single grapheme, empty in the second column of a wide one
+    style: Style,
+}
+
+impl Cell {
+    fn is_continuation(&self) -> bool {
+        self.symbol.is_empty()
+    }
+
+    fn write(&self, out: &mut String, current: &mut Option<Style>, depth: Depth) {
+        if *current != Some(self.style) {
+            self.style.escape(out, depth);
+            *current = Some(self.style);
+        }
+        out.push_str(&self.symbol);
+    }
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            symbol: " ".to_string(),
+            style: Style::default(),
+        }
+    }
+}
+
+/// One frame worth of styled cells, drawn off-screen and then diffed against the last frame.
+#[derive(Clone)]
+pub struct Screen {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+    pub cursor: Option<Position>,
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::default(); width.saturating_mul(height)],
+            cursor: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Write `text` starting at column `x` of row `y`, clipped to the screen; returns the columns used
+    #[allow(clippy::integer_arithmetic)]
+    pub fn put_str(&mut self, x: usize, y: usize, text: &str, style: Style) -> usize {
+        if y >= self.height {
+            return 0;
+        }
+        let mut column = x;
+        for grapheme in text.graphemes(true) {
+            if column >= self.width {
+                break;
+            }
+            let width = grapheme.width();
+            let (symbol, width) = if grapheme.chars().any(char::is_control) || width == 0 {
+                (" ", 1)
+            } else if column + width > self.width {
+                // Half a wide character does not fit at the right edge
+                (" ", 1)
+            } else {
+                (grapheme, width)
+            };
+            self.set(column, y, symbol, style);
+            for extra in 1..width {
+                self.set(column + extra, y, "", style);
+            }
+            column += width;
+        }
+        column.saturating_sub(x)
+    }
+
+    // Put `symbol` in one cell, blanking what is left of a wide character it overwrites half of
+    #[allow(clippy::integer_arithmetic)]
+    fn set(&mut self, x: usize, y: usize, symbol: &str, style: Style) {
+        let index = y * self.width + x;
+        let old = match self.cells.get(index) {
+            Some(old) => old.clone(),
+            None => return,
+        };
+        if old.is_continuation() && !symbol.is_empty() && x > 0 {
+            if let Some(left) = self.cells.get_mut(index - 1) {
+                left.symbol = " ".to_string();
+            }
+        }
+        if x + 1 < self.width {
+            if let Some(right) = self.cells.get_mut(index + 1).filter(|right| right.is_continuation()) {
+                right.symbol = " ".to_string();
+            }
+        }
+        if let Some(cell) = self.cells.get_mut(index) {
+            *cell = Cell {
+                symbol: symbol.to_string(),
+                style,
+            };
+        }
+    }
+
+    // Paint `width` blank cells of `style`, starting at column `x` of row `y`
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, style: Style) {
+        let end = x.saturating_add(width).min(self.width);
+        for column in x..end {
+            self.put_str(column, y, " ", style);
+        }
+    }
+
+    // Escape sequences turning `previous` into this frame; everything is redrawn if there is none
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    pub fn diff(&self, previous: Option<&Self>, depth: Depth) -> String {
+        let previous = previous.filter(|previous| previous.width == self.width && previous.height == self.height);
+
+        let mut out = String::new();
+        let _ = write!(out, "{}", termion::cursor::Hide);
+        if previous.is_none() {
+            let _ = write!(out, "{}{}", termion::style::Reset, termion::clear::All);
+        }
+
+        // After a full clear every cell on the terminal is blank
+        let blank = Cell::default();
+        let mut style: Option<Style> = None;
+        for y in 0..self.height {
+            // Column the terminal cursor will be at after the last cell we wrote on this row
+            let mut at: Option<usize> = None;
+            for x in 0..self.width {
+                let index = y * self.width + x;
+                let cell = &self.cells[index];
+                // Drawn along with the wide character on its left
+                if cell.is_continuation() {
+                    continue;
+                }
+                if previous.map_or(&blank, |previous| &previous.cells[index]) == cell {
+                    continue;
+                }
+                match at {
+                    // Rewriting a few unchanged cells is cheaper than moving the cursor over them
+                    Some(column) if x - column <= SHORT_GAP => {
+                        for skipped in &self.cells[y * self.width + column..index] {
+                            skipped.write(&mut out, &mut style, depth);
+                        }
+                    }
+                    _ => {
+                        let _ = write!(out, "{}", termion::cursor::Goto(x as u16 + 1, y as u16 + 1));
+                    }
+                }
+                cell.write(&mut out, &mut style, depth);
+                at = Some(x + 1);
+            }
+        }
+
+        let _ = write!(out, "{}", termion::style::Reset);
+        if let Some(Position { x, y }) = &self.cursor {
+            let _ = write!(
+                out,
+                "{}{}",
+                termion::cursor::Goto(x.saturating_add(1) as u16, y.saturating_add(1) as u16),
+                termion::cursor::Show
+            );
+        }
+        out
+    }
+}